@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use crate::archive_info::open_archive;
+use crate::binary_naming::BINARY_SIDECAR_NAME;
+use crate::brotli_entries::BROTLI_MANIFEST_NAME;
+use crate::chunking::CHUNK_MANIFEST_NAME;
+use crate::creation_time::CREATION_TIMES_NAME;
+use crate::collector::{archive_entry_name, collect_files, entry_prefix};
+use crate::dedup::DEDUP_MANIFEST_NAME;
+use crate::entry_writer::CompressionSettings;
+use crate::sparse::SPARSE_MANIFEST_NAME;
+use crate::utils::file_crc32;
+use crate::ownership::OWNERSHIP_NAME;
+use crate::xattrs::XATTRS_NAME;
+
+/// Files of the folder compared with the entries of the archive, by entry name
+#[derive(Debug, Default)]
+pub struct ArchiveDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+/// True for the hidden entries written by this tool to describe the run, which aren't files of the folder
+pub fn is_metadata_entry(name: &str) -> bool {
+    [BINARY_SIDECAR_NAME, BROTLI_MANIFEST_NAME, CHUNK_MANIFEST_NAME, CREATION_TIMES_NAME, DEDUP_MANIFEST_NAME, OWNERSHIP_NAME, SPARSE_MANIFEST_NAME, XATTRS_NAME].contains(&name)
+}
+
+/// Compares the archive with the files the folder would be archived as. Files are modified when their size
+/// differs, or their CRC32 with the same size. Modification times aren't compared as the entries don't keep them.
+pub fn diff_archive(zip_path: &Path, folder_path: &Path, settings: &CompressionSettings) -> io::Result<ArchiveDiff> {
+    let mut entries: BTreeMap<String, (u64, u32)> = open_archive(zip_path)?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && !is_metadata_entry(&entry.name))
+        .map(|entry| (entry.name, (entry.uncompressed_size, entry.crc32)))
+        .collect();
+
+    let root = entry_prefix(folder_path, settings)?;
+    let mut diff = ArchiveDiff::default();
+    for path in collect_files(folder_path, settings)? {
+        let name = archive_entry_name(folder_path, &path, &root, settings)?;
+        match entries.remove(&name) {
+            None => diff.added.push(name),
+            Some((size, crc32)) => {
+                if std::fs::metadata(&path)?.len() != size || file_crc32(&path)? != crc32 {
+                    diff.modified.push(name);
+                }
+            }
+        }
+    }
+    diff.removed = entries.into_keys().collect();
+    diff.added.sort();
+    diff.modified.sort();
+    Ok(diff)
+}
+
+pub fn print_diff(diff: &ArchiveDiff) {
+    for (title, names) in [("Added", &diff.added), ("Removed", &diff.removed), ("Modified", &diff.modified)] {
+        if names.is_empty() {
+            continue;
+        }
+        println!("{}:", title);
+        for name in names {
+            println!("  {}", name);
+        }
+    }
+    println!("{} added, {} removed, {} modified", diff.added.len(), diff.removed.len(), diff.modified.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compress, entry_names, settings, write_files, TempDir};
+
+    #[test]
+    fn added_removed_and_modified_files_are_told_apart() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("same.txt", b"same"), ("size.txt", b"short"), ("content.txt", b"abcd"), ("gone.txt", b"gone")]);
+        let settings = settings(&dir.join("out.zip"));
+        compress(&input, &settings);
+
+        // Same size, different bytes, so only the CRC32 tells it changed
+        write_files(&input, &[("size.txt", b"much longer"), ("content.txt", b"dcba"), ("nested/new.txt", b"new")]);
+        std::fs::remove_file(input.join("gone.txt")).unwrap();
+        let diff = diff_archive(&dir.join("out.zip"), &input, &settings).unwrap();
+        assert_eq!(diff.added, ["nested/new.txt"]);
+        assert_eq!(diff.removed, ["gone.txt"]);
+        assert_eq!(diff.modified, ["content.txt", "size.txt"]);
+    }
+
+    #[test]
+    fn entries_are_named_with_the_prefix_settings() {
+        let dir = TempDir::new();
+        let input = dir.join("project");
+        write_files(&input, &[("same.txt", b"same"), ("src/main.rs", b"fn main() {}")]);
+        let settings = CompressionSettings { prefix: Some("release".to_owned()), include_root: true, ..settings(&dir.join("out.zip")) };
+        compress(&input, &settings);
+
+        write_files(&input, &[("src/main.rs", b"fn main() { }"), ("new.txt", b"new")]);
+        let diff = diff_archive(&dir.join("out.zip"), &input, &settings).unwrap();
+        assert_eq!(diff.added, ["release/project/new.txt"]);
+        assert!(diff.removed.is_empty(), "{:?}", diff.removed);
+        assert_eq!(diff.modified, ["release/project/src/main.rs"]);
+
+        // The names of an archive written with --strip-prefix lose it in the diff too
+        let settings = CompressionSettings { strip_prefix: Some("src".to_owned()), strip_prefix_lenient: true, ..settings };
+        compress(&input, &settings);
+        let diff = diff_archive(&dir.join("out.zip"), &input, &settings).unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty(), "{:?}", diff);
+        assert!(entry_names(&dir.join("out.zip")).contains(&"release/project/main.rs".to_owned()));
+    }
+}
@@ -0,0 +1,396 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::collector::normalize_separators;
+use crate::utils::get_zip_compression_method;
+
+pub fn add_file_to_zip(
+    zip_path: &Path,
+    file_path: &Path,
+    compression_algorithm: &str,
+    compression_level: i64,
+    entry_name: Option<&str>,
+) -> io::Result<()> {
+    if !file_path.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("File {:?} does not exist or is not a file", file_path)));
+    }
+
+    let entry_name = match entry_name {
+        Some(name) => normalize_separators(name),
+        None => match file_path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} has no UTF-8 file name to use as the entry name, pass --entry-name", file_path))),
+        },
+    };
+
+    let (compression_method, valid_level) = get_zip_compression_method(compression_algorithm, compression_level)?;
+    let options: FileOptions<()> = FileOptions::default()
+        .compression_method(compression_method).compression_level(valid_level);
+
+    let archive = OpenOptions::new().read(true).write(true).open(zip_path)?;
+    let mut zip = ZipWriter::new_append(archive)?;
+
+    zip.start_file(entry_name.as_str(), options)?;
+    let mut file = File::open(file_path)?;
+    io::copy(&mut file, &mut zip)?;
+    zip.finish()?;
+
+    println!("Added {:?} to {:?} as {}", file_path, zip_path, entry_name);
+    Ok(())
+}
+
+pub fn remove_entry_from_zip(zip_path: &Path, entry_name: &str) -> io::Result<()> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let removed_index = match archive.index_for_name(entry_name) {
+        Some(index) => index,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Entry {} not found in {:?}", entry_name, zip_path))),
+    };
+
+    // The zip crate cannot delete entries in place, so every other entry is copied into a new archive,
+    // which is removed again when the copy fails
+    let temp_path = zip_path.with_extension("zip.tmp");
+    let copied = copy_entries_except(&mut archive, removed_index, &temp_path);
+    drop(archive);
+    if let Err(e) = copied.and_then(|()| std::fs::rename(&temp_path, zip_path)) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    println!("Removed {} from {:?}", entry_name, zip_path);
+    Ok(())
+}
+
+fn copy_entries_except(archive: &mut ZipArchive<File>, removed_index: usize, output_zip: &Path) -> io::Result<()> {
+    let mut zip = ZipWriter::new(File::create(output_zip)?);
+    for i in 0..archive.len() {
+        if i == removed_index {
+            continue;
+        }
+        let file = archive.by_index_raw(i)?;
+        zip.raw_copy_file(file)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Rewrites the entries of `input_zip` into `output_zip` with the given method. The entries are written one at a time
+/// in the order of the input's central directory, so the output lists them in the same order. Without a level the
+/// recompressed entries get the algorithm's default level, and the entries already using the method are kept as they are.
+/// The output is written next to itself as `<output>.tmp` and renamed once complete, so a failed run leaves nothing behind.
+pub fn recompress_zip(
+    input_zip: &Path,
+    output_zip: &Path,
+    compression_algorithm: &str,
+    compression_level: Option<i64>,
+    only: &[String],
+) -> io::Result<()> {
+    // A level outside of every range picks the default level
+    let (compression_method, valid_level) = get_zip_compression_method(compression_algorithm, compression_level.unwrap_or(i64::MAX))?;
+    let only = entry_patterns(only)?;
+    // Writing over the input, under its own path or through a link to it, would destroy it before it is read
+    if output_zip.exists() && output_zip.canonicalize()? == input_zip.canonicalize()? {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is the input archive, recompress into another file", output_zip)));
+    }
+    let mut archive = ZipArchive::new(File::open(input_zip)?)?;
+
+    let mut temp_name = output_zip.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = output_zip.with_file_name(temp_name);
+    let recompressed = recompress_entries(&mut archive, &temp_path, compression_method, valid_level, compression_level, only.as_ref());
+    let raw_copied = match recompressed.and_then(|raw_copied| std::fs::rename(&temp_path, output_zip).map(|()| raw_copied)) {
+        Ok(raw_copied) => raw_copied,
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+
+    println!("Recompressed {:?} into {:?}, {} of {} entries copied without recompressing",
+        input_zip, output_zip, raw_copied, archive.len());
+    Ok(())
+}
+
+// Returns how many entries were copied without recompressing them
+fn recompress_entries(
+    archive: &mut ZipArchive<File>,
+    output_zip: &Path,
+    compression_method: CompressionMethod,
+    valid_level: Option<i64>,
+    compression_level: Option<i64>,
+    only: Option<&Gitignore>,
+) -> io::Result<usize> {
+    let mut zip = ZipWriter::new(File::create(output_zip)?);
+    let mut raw_copied = 0;
+
+    for i in 0..archive.len() {
+        // Entries not matching `--only`, and without a level the ones already using the target method, are copied
+        // compressed, without decompressing them. The zip format doesn't record the level an entry was written at.
+        let raw_file = archive.by_index_raw(i)?;
+        let selected = only.is_none_or(|only| only.matched_path_or_any_parents(raw_file.name(), false).is_ignore());
+        let same_method = raw_file.compression() == compression_method && compression_level.is_none();
+        if raw_file.is_dir() || same_method || !selected {
+            zip.raw_copy_file(raw_file)?;
+            raw_copied += 1;
+            continue;
+        }
+        drop(raw_file);
+
+        let mut file = archive.by_index(i)?;
+        let mut options: FileOptions<()> = FileOptions::default()
+            .compression_method(compression_method)
+            .compression_level(valid_level)
+            .large_file(file.size() >= u32::MAX as u64);
+        if let Some(last_modified) = file.last_modified() {
+            options = options.last_modified_time(last_modified);
+        }
+        if let Some(mode) = file.unix_mode() {
+            options = options.unix_permissions(mode);
+        }
+        zip.start_file(file.name().to_owned(), options)?;
+        io::copy(&mut file, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(raw_copied)
+}
+
+// Patterns are matched against the entry names like .gitignore lines, so `*.txt` matches in every directory
+fn entry_patterns(patterns: &[String]) -> io::Result<Option<Gitignore>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let invalid = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid --only pattern: {}", e));
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(invalid)?;
+    }
+    builder.build().map(Some).map_err(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use zip::CompressionMethod;
+    use super::*;
+    use crate::test_support::{entry_names, random_bytes, write_zip, TempDir};
+
+    const MIXED: [(&str, CompressionMethod); 3] = [("a.txt", CompressionMethod::Deflated), ("b.txt", CompressionMethod::Zstd), ("c.dat", CompressionMethod::Stored)];
+
+    // An archive with the entries of MIXED, each holding its name repeated
+    fn write_mixed_zip(zip_path: &Path) {
+        let mut zip = ZipWriter::new(File::create(zip_path).unwrap());
+        for (name, method) in MIXED {
+            zip.start_file(name, FileOptions::<()>::default().compression_method(method)).unwrap();
+            zip.write_all(name.repeat(50).as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    // The method and the compressed bytes of every entry, by name
+    fn raw_entries(zip_path: &Path) -> Vec<(String, CompressionMethod, Vec<u8>)> {
+        let mut archive = ZipArchive::new(File::open(zip_path).unwrap()).unwrap();
+        (0..archive.len()).map(|i| {
+            let mut file = archive.by_index_raw(i).unwrap();
+            let mut raw = Vec::new();
+            file.read_to_end(&mut raw).unwrap();
+            (file.name().to_owned(), file.compression(), raw)
+        }).collect()
+    }
+
+    fn contents(zip_path: &Path, name: &str) -> String {
+        let mut archive = ZipArchive::new(File::open(zip_path).unwrap()).unwrap();
+        let contents = io::read_to_string(archive.by_name(name).unwrap()).unwrap();
+        contents
+    }
+
+    #[test]
+    fn added_file_becomes_the_third_entry() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a"), ("b.txt", b"b")]);
+        std::fs::write(dir.join("c.txt"), b"c").unwrap();
+        add_file_to_zip(&zip_path, &dir.join("c.txt"), "Deflated", 6, Some("docs\\c.txt")).unwrap();
+
+        assert_eq!(entry_names(&zip_path), ["a.txt", "b.txt", "docs/c.txt"]);
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("docs/c.txt").unwrap()).unwrap(), "c");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_without_a_utf8_name_needs_an_entry_name() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a")]);
+        let file_path = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&file_path, b"cafe").unwrap();
+
+        let e = add_file_to_zip(&zip_path, &file_path, "Deflated", 6, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("--entry-name"), "{}", e);
+        assert_eq!(entry_names(&zip_path), ["a.txt"]);
+
+        add_file_to_zip(&zip_path, &file_path, "Deflated", 6, Some("cafe.txt")).unwrap();
+        assert_eq!(entry_names(&zip_path), ["a.txt", "cafe.txt"]);
+    }
+
+    #[test]
+    fn removed_entry_leaves_the_other_two() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        remove_entry_from_zip(&zip_path, "b.txt").unwrap();
+
+        assert_eq!(entry_names(&zip_path), ["a.txt", "c.txt"]);
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("c.txt").unwrap()).unwrap(), "c");
+        assert!(!zip_path.with_extension("zip.tmp").exists());
+    }
+
+    #[test]
+    fn failed_removal_leaves_no_temp_file() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        // Breaking the local header of the last entry makes its copy fail once the temporary archive was started
+        let mut bytes = std::fs::read(&zip_path).unwrap();
+        let header = bytes.windows(4).enumerate().filter(|(_, window)| *window == b"PK\x03\x04").nth(2).unwrap().0;
+        bytes[header + 3] = 0;
+        std::fs::write(&zip_path, &bytes).unwrap();
+
+        assert!(remove_entry_from_zip(&zip_path, "a.txt").is_err());
+        assert!(!zip_path.with_extension("zip.tmp").exists());
+        assert_eq!(std::fs::read(&zip_path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn removing_a_missing_entry_fails() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a")]);
+        assert_eq!(remove_entry_from_zip(&zip_path, "b.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(entry_names(&zip_path), ["a.txt"]);
+    }
+
+    #[test]
+    fn missing_file_is_not_added() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a")]);
+        let e = add_file_to_zip(&zip_path, &dir.join("missing.txt"), "Deflated", 6, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(entry_names(&zip_path), ["a.txt"]);
+    }
+
+    #[test]
+    fn entries_already_using_the_method_are_copied_raw() {
+        let dir = TempDir::new();
+        let (input, output) = (dir.join("input.zip"), dir.join("output.zip"));
+        write_mixed_zip(&input);
+        recompress_zip(&input, &output, "Zstd", None, &[]).unwrap();
+
+        let (before, after) = (raw_entries(&input), raw_entries(&output));
+        assert!(after.iter().all(|(_, method, _)| *method == CompressionMethod::Zstd));
+        // b.txt already was Zstd, so its compressed bytes are the same, the others were recompressed
+        assert_eq!(after[1], before[1]);
+        assert_ne!(after[0].2, before[0].2);
+        for (name, _) in MIXED {
+            assert_eq!(contents(&output, name), name.repeat(50));
+        }
+    }
+
+    #[test]
+    fn given_level_recompresses_entries_already_using_the_method() {
+        let dir = TempDir::new();
+        let (input, level_1) = (dir.join("input.zip"), dir.join("level-1.zip"));
+        let words = ["alpha ", "beta ", "gamma ", "delta ", "epsilon ", "zeta "];
+        let text: String = random_bytes(100_000, 9).iter().map(|byte| words[*byte as usize % words.len()]).collect();
+        write_zip(&input, &[("a.txt", text.as_bytes())]);
+        recompress_zip(&input, &level_1, "Zstd", Some(1), &[]).unwrap();
+
+        let level_19 = dir.join("level-19.zip");
+        recompress_zip(&level_1, &level_19, "Zstd", Some(19), &[]).unwrap();
+        let (before, after) = (raw_entries(&level_1), raw_entries(&level_19));
+        assert_eq!(after[0].1, CompressionMethod::Zstd);
+        assert!(after[0].2.len() < before[0].2.len(), "{} bytes at level 19, {} at level 1", after[0].2.len(), before[0].2.len());
+        assert_eq!(contents(&level_19, "a.txt"), text);
+
+        // Without a level the entry keeps the bytes it was written with
+        let kept = dir.join("kept.zip");
+        recompress_zip(&level_1, &kept, "Zstd", None, &[]).unwrap();
+        assert_eq!(raw_entries(&kept), before);
+    }
+
+    #[test]
+    fn recompressing_into_the_input_is_refused() {
+        let dir = TempDir::new();
+        let input = dir.join("input.zip");
+        write_mixed_zip(&input);
+        let before = std::fs::read(&input).unwrap();
+        let e = recompress_zip(&input, &input, "Zstd", None, &[]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&input, dir.join("link.zip")).unwrap();
+            let e = recompress_zip(&input, &dir.join("link.zip"), "Zstd", None, &[]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(std::fs::read(&input).unwrap(), before);
+    }
+
+    #[test]
+    fn failed_recompression_leaves_no_output() {
+        let dir = TempDir::new();
+        let (input, output) = (dir.join("input.zip"), dir.join("output.zip"));
+        write_mixed_zip(&input);
+        // Breaking the local header of the last entry makes the run fail once the output was started
+        let mut bytes = std::fs::read(&input).unwrap();
+        let header = bytes.windows(4).enumerate().filter(|(_, window)| *window == b"PK\x03\x04").nth(2).unwrap().0;
+        bytes[header + 3] = 0;
+        std::fs::write(&input, &bytes).unwrap();
+
+        assert!(recompress_zip(&input, &output, "Zstd", None, &[]).is_err());
+        assert!(!output.exists());
+        assert!(!dir.join("output.zip.tmp").exists());
+    }
+
+    #[test]
+    fn only_matching_entries_are_recompressed() {
+        let dir = TempDir::new();
+        let (input, output) = (dir.join("input.zip"), dir.join("output.zip"));
+        let text = "text ".repeat(100);
+        write_zip(&input, &[("a.txt", text.as_bytes()), ("b.dat", text.as_bytes()), ("docs/c.txt", text.as_bytes()), ("d.dat", b"d")]);
+        recompress_zip(&input, &output, "Zstd", Some(3), &["*.txt".to_owned()]).unwrap();
+
+        let (before, after) = (raw_entries(&input), raw_entries(&output));
+        assert_eq!(after.len(), 4);
+        for (before, after) in before.iter().zip(&after) {
+            if after.0.ends_with(".txt") {
+                assert_eq!(after.1, CompressionMethod::Zstd);
+                assert_eq!(contents(&output, &after.0), text);
+            } else {
+                // The other entries are copied compressed, byte for byte
+                assert_eq!(after, before);
+                assert_eq!(after.1, CompressionMethod::Deflated);
+            }
+        }
+    }
+
+    #[test]
+    fn recompressed_entries_keep_their_order() {
+        let dir = TempDir::new();
+        let input = dir.join("input.zip");
+        // Neither sorted nor in the order of any walk, with directories and entries copied raw in between
+        let names = ["zebra.txt", "b/", "b/nested.txt", "apple.txt", "c.dat", "m.txt", "a/", "0.txt"];
+        let entries: Vec<(&str, &[u8])> = names.iter().map(|name| (*name, name.as_bytes())).collect();
+        write_zip(&input, &entries);
+        for (algorithm, only) in [("Zstd", &[][..]), ("Bzip2", &[][..]), ("Zstd", &["*.txt".to_owned()][..])] {
+            let output = dir.join(format!("{}-{}.zip", algorithm, only.len()));
+            recompress_zip(&input, &output, algorithm, Some(3), only).unwrap();
+            assert_eq!(entry_names(&output), names, "{} {:?}", algorithm, only);
+        }
+    }
+}
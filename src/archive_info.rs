@@ -0,0 +1,305 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use serde_json::Value;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::brotli_entries::BROTLI_MANIFEST_NAME;
+use crate::chunking::{part_name, CHUNK_MANIFEST_NAME};
+use crate::decompression_wo_conversion::entry_by_index;
+use crate::dedup::DEDUP_MANIFEST_NAME;
+use crate::sparse::SPARSE_MANIFEST_NAME;
+
+/// The metadata of one archive entry, as read from the central directory
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub name: String,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub method: CompressionMethod,
+    pub crc32: u32,
+    pub is_dir: bool,
+    pub unix_mode: Option<u32>,
+    pub comment: String,
+}
+
+/// One entry yielded by `entries`, whose data is only decompressed when `read_to` is called.
+/// A `ZipFile` borrows its archive mutably, so an iterator can't yield entries borrowing it. Instead every entry
+/// holds the archive through a shared `Rc<RefCell<_>>` and its index: entries can be kept after the iteration,
+/// but they can't be sent to other threads and only one of them can be read at a time.
+pub struct ArchiveEntry {
+    pub info: EntryInfo,
+    index: usize,
+    data: EntryData,
+    archive: Rc<SharedArchive>,
+}
+
+struct SharedArchive {
+    archive: RefCell<ZipArchive<File>>,
+    password: Option<String>,
+}
+
+// How the bytes of an entry relate to the file it was written from, as recorded in the archive's manifests
+#[derive(Clone, Copy)]
+enum EntryData {
+    File,
+    Brotli,
+    /// Only restored by extracting the whole archive, with the reason
+    NeedsExtraction(&'static str),
+}
+
+impl ArchiveEntry {
+    /// Decompresses the entry into the writer, checking its CRC32, and returns the number of bytes written.
+    /// These are the bytes of the archived file, also for Brotli entries with the `brotli` feature.
+    /// Entries that only become the file once the whole archive is extracted fail with `Unsupported`: the parts
+    /// of files split by `--chunk-large-files`, the markers of `--dedup` duplicates, files stored by `--sparse`
+    /// without their holes, and Brotli entries without the `brotli` feature.
+    pub fn read_to(&self, writer: &mut impl Write) -> io::Result<u64> {
+        match self.data {
+            EntryData::NeedsExtraction(reason) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} {}, extract the whole archive to get the file", self.info.name, reason)));
+            }
+            EntryData::Brotli if cfg!(not(feature = "brotli")) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} is compressed with Brotli, build with `--features brotli` to read it", self.info.name)));
+            }
+            EntryData::File | EntryData::Brotli => {}
+        }
+        let mut archive = self.archive.archive.borrow_mut();
+        let mut file = entry_by_index(&mut archive, self.index, self.archive.password.as_deref())?;
+        #[cfg(feature = "brotli")]
+        if matches!(self.data, EntryData::Brotli) {
+            return io::copy(&mut brotli::Decompressor::new(file, 64 * 1024), writer);
+        }
+        io::copy(&mut file, writer)
+    }
+}
+
+/// Iterates over the entries of the archive in archive order. Only the central directory and the manifests are
+/// read up front, each entry's metadata is read as it is yielded and its data when it is read. The password
+/// decrypts the entries of encrypted archives when they are read.
+pub fn entries(path: &Path, password: Option<&str>) -> io::Result<impl Iterator<Item = io::Result<ArchiveEntry>>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let data = entry_data(&mut archive, password)?;
+    let len = archive.len();
+    let archive = Rc::new(SharedArchive { archive: RefCell::new(archive), password: password.map(str::to_owned) });
+    Ok((0..len).map(move |index| {
+        let info = entry_info(&mut archive.archive.borrow_mut(), index)?;
+        let data = data.get(&info.name).copied().unwrap_or(EntryData::File);
+        Ok(ArchiveEntry { info, index, data, archive: Rc::clone(&archive) })
+    }))
+}
+
+// The entries the manifests say don't hold their file's bytes as they are
+fn entry_data(archive: &mut ZipArchive<File>, password: Option<&str>) -> io::Result<HashMap<String, EntryData>> {
+    let mut data = HashMap::new();
+    if let Some(manifest) = read_manifest(archive, CHUNK_MANIFEST_NAME, password)? {
+        for file in manifest["chunked_files"].as_array().into_iter().flatten() {
+            let (Some(name), Some(parts)) = (file["name"].as_str(), file["parts"].as_u64()) else { continue };
+            for part in 0..parts {
+                data.insert(part_name(name, part), EntryData::NeedsExtraction("is one part of a chunked file"));
+            }
+        }
+    }
+    if let Some(manifest) = read_manifest(archive, DEDUP_MANIFEST_NAME, password)? {
+        for duplicate in manifest["duplicates"].as_object().into_iter().flat_map(|duplicates| duplicates.keys()) {
+            data.insert(duplicate.clone(), EntryData::NeedsExtraction("is the marker of a deduplicated file"));
+        }
+    }
+    if let Some(manifest) = read_manifest(archive, SPARSE_MANIFEST_NAME, password)? {
+        for file in manifest["sparse_files"].as_array().into_iter().flatten() {
+            if let Some(name) = file["name"].as_str() {
+                data.insert(name.to_owned(), EntryData::NeedsExtraction("is stored without the holes of a sparse file"));
+            }
+        }
+    }
+    if let Some(manifest) = read_manifest(archive, BROTLI_MANIFEST_NAME, password)? {
+        for name in manifest["brotli_files"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+            data.insert(name.to_owned(), EntryData::Brotli);
+        }
+    }
+    Ok(data)
+}
+
+fn read_manifest(archive: &mut ZipArchive<File>, name: &str, password: Option<&str>) -> io::Result<Option<Value>> {
+    let Some(index) = archive.index_for_name(name) else {
+        return Ok(None);
+    };
+    let mut bytes = Vec::new();
+    entry_by_index(archive, index, password)?.read_to_end(&mut bytes)?;
+    serde_json::from_slice(&bytes).map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid {}: {}", name, e)))
+}
+
+fn entry_info(archive: &mut ZipArchive<File>, index: usize) -> io::Result<EntryInfo> {
+    let entry = archive.by_index_raw(index)?;
+    Ok(EntryInfo {
+        name: entry.name().to_owned(),
+        compressed_size: entry.compressed_size(),
+        uncompressed_size: entry.size(),
+        method: entry.compression(),
+        crc32: entry.crc32(),
+        is_dir: entry.is_dir(),
+        unix_mode: entry.unix_mode(),
+        comment: entry.comment().to_owned(),
+    })
+}
+
+/// Reads the metadata of every entry in the archive, in archive order, without decompressing anything
+pub fn open_archive(path: &Path) -> io::Result<Vec<EntryInfo>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    (0..archive.len()).map(|index| entry_info(&mut archive, index)).collect()
+}
+
+pub fn list_entries(zip_path: &Path) -> io::Result<()> {
+    let entries = open_archive(zip_path)?;
+    println!("{:>12} {:>12} {:<10} {:<8} {:<6} Name", "Size", "Compressed", "Method", "CRC32", "Mode");
+    for entry in &entries {
+        let mode = entry.unix_mode.map(|mode| format!("{:o}", mode & 0o7777)).unwrap_or_else(|| "-".to_owned());
+        let name = if entry.is_dir && !entry.name.ends_with('/') { format!("{}/", entry.name) } else { entry.name.clone() };
+        println!("{:>12} {:>12} {:<10} {:08x} {:<6} {}",
+            entry.uncompressed_size, entry.compressed_size, entry.method.to_string(), entry.crc32, mode, name);
+        if !entry.comment.is_empty() {
+            println!("{:>12} {}", "Comment:", entry.comment);
+        }
+    }
+    println!("{} entries", entries.len());
+    Ok(())
+}
+
+/// Checks whether the file opens as a zip and prints its entry count and compression methods.
+/// Fails with a message telling a file that isn't a zip at all from a damaged one, e.g. a truncated download.
+pub fn probe(path: &Path) -> io::Result<()> {
+    match open_archive(path) {
+        Ok(entries) => {
+            let methods: BTreeSet<String> = entries.iter().filter(|entry| !entry.is_dir).map(|entry| entry.method.to_string()).collect();
+            println!("{:?}: valid zip archive, {} entries", path, entries.len());
+            if !methods.is_empty() {
+                println!("Compression methods: {}", methods.into_iter().collect::<Vec<_>>().join(", "));
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::PermissionDenied => Err(e),
+        Err(e) => {
+            // Zip files start with a local file header, or the end of central directory record when empty
+            let mut signature = [0u8; 4];
+            let starts_like_zip = File::open(path)?.read_exact(&mut signature).is_ok()
+                && (signature == *b"PK\x03\x04" || signature == *b"PK\x05\x06");
+            if starts_like_zip {
+                println!("{:?}: corrupt zip archive, {}", path, e);
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Corrupt zip archive"))
+            } else {
+                println!("{:?}: not a zip archive", path);
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Not a zip archive"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, fixture, settings, write_files, write_zip, TempDir};
+
+    fn read_entry(zip_path: &Path, name: &str, password: Option<&str>) -> io::Result<Vec<u8>> {
+        let entry = entries(zip_path, password)?.find(|entry| entry.as_ref().is_ok_and(|entry| entry.info.name == name)).unwrap()?;
+        let mut bytes = Vec::new();
+        entry.read_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn single_entry_is_read_through_the_iterator() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"first"), ("nested/b.txt", &b"second ".repeat(100))]);
+        let settings = settings(&dir.join("out.zip"));
+        compress(&dir.join("input"), &settings);
+        assert_eq!(read_entry(&settings.output_zip, "nested/b.txt", None).unwrap(), b"second ".repeat(100));
+    }
+
+    #[test]
+    fn encrypted_entries_are_read_with_the_password() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("secret.txt", b"secret")]);
+        let settings = CompressionSettings { password: Some("hunter2".to_owned()), ..settings(&dir.join("out.zip")) };
+        compress(&dir.join("input"), &settings);
+        assert_eq!(read_entry(&settings.output_zip, "secret.txt", Some("hunter2")).unwrap(), b"secret");
+        assert!(read_entry(&settings.output_zip, "secret.txt", None).is_err());
+    }
+
+    #[test]
+    fn entries_needing_a_whole_extraction_are_unsupported() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"same"), ("b.txt", b"same"), ("large.bin", &[7; 3000])]);
+        let settings = CompressionSettings { dedup: true, chunk_large_files: Some(1024), ..settings(&dir.join("out.zip")) };
+        compress(&dir.join("input"), &settings);
+        let marker = open_archive(&settings.output_zip).unwrap().into_iter().find(|entry| (entry.name == "a.txt" || entry.name == "b.txt") && entry.uncompressed_size == 0).unwrap();
+        assert_eq!(read_entry(&settings.output_zip, &marker.name, None).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert_eq!(read_entry(&settings.output_zip, &part_name("large.bin", 0), None).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli_entries_are_decoded() {
+        let dir = TempDir::new();
+        let text = b"brotli compresses text well ".repeat(50);
+        write_files(&dir.join("input"), &[("text.txt", &text)]);
+        let settings = CompressionSettings { compression_algorithm: "Brotli".to_owned(), ..settings(&dir.join("out.zip")) };
+        compress(&dir.join("input"), &settings);
+        assert_eq!(read_entry(&settings.output_zip, "text.txt", None).unwrap(), text);
+    }
+
+    #[test]
+    fn open_archive_reads_the_fixture_metadata() {
+        let entries = open_archive(&fixture("sample.zip")).unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["hello.txt", "docs/", "docs/notes.txt"]);
+
+        let hello = &entries[0];
+        assert_eq!((hello.uncompressed_size, hello.compressed_size), (120, 17));
+        assert_eq!(hello.method, CompressionMethod::Deflated);
+        assert_eq!(hello.crc32, 0xbf17cb04);
+        assert_eq!(hello.unix_mode.map(|mode| mode & 0o7777), Some(0o644));
+        assert!(!hello.is_dir);
+
+        assert!(entries[1].is_dir);
+
+        let notes = &entries[2];
+        assert_eq!((notes.uncompressed_size, notes.compressed_size), (12, 12));
+        assert_eq!(notes.method, CompressionMethod::Stored);
+        assert_eq!(notes.crc32, 0xbf24923a);
+        assert_eq!(notes.comment, "kept as is");
+    }
+
+    #[test]
+    fn malformed_manifest_does_not_stop_the_listing() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("manifest.zip");
+        write_zip(&zip_path, &[("a.txt", b"a"), (CHUNK_MANIFEST_NAME, b"{ not json")]);
+
+        let names: Vec<String> = open_archive(&zip_path).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["a.txt", CHUNK_MANIFEST_NAME]);
+        probe(&zip_path).unwrap();
+        assert!(entries(&zip_path, None).is_err());
+    }
+
+    #[test]
+    fn probe_tells_corrupt_archives_from_other_files() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("valid.zip");
+        let text = "text to compress\n".repeat(100);
+        write_zip(&zip_path, &[("a.txt", text.as_bytes()), ("b.txt", b"b")]);
+        probe(&zip_path).unwrap();
+
+        let bytes = std::fs::read(&zip_path).unwrap();
+        std::fs::write(dir.join("truncated.zip"), &bytes[..bytes.len() / 2]).unwrap();
+        assert_eq!(probe(&dir.join("truncated.zip")).unwrap_err().to_string(), "Corrupt zip archive");
+
+        std::fs::write(dir.join("notes.zip"), b"just some text, not an archive").unwrap();
+        assert_eq!(probe(&dir.join("notes.zip")).unwrap_err().to_string(), "Not a zip archive");
+        assert_eq!(probe(&dir.join("missing.zip")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use zip::ZipWriter;
+
+use crate::central_directory::{self, HostOs};
+use crate::checksum;
+use crate::collector::{canonical_output_path, subfolders};
+use crate::compression::add_files_to_zip;
+use crate::compression_wo_conversion::{add_files_directly_to_zip, add_stdin_to_zip};
+use crate::entry_writer::{CompressionSettings, RunSummary};
+use crate::report;
+use crate::streaming;
+
+/// How the compression command writes the archive and gets it to the output
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputOptions {
+    pub convert_to_binary: bool,
+    pub host_os: Option<HostOs>,
+    /// Build the archive in the temp directory and copy it to the output at the end, which may be a FIFO
+    pub streaming: bool,
+    /// Write the output in place instead of to a temporary file renamed once the archive is complete
+    pub no_atomic: bool,
+}
+
+/// Makes sure the directory the output is written to exists, creating it with `mkdir`. Without it a missing
+/// directory is a `NotFound` error naming it, rather than the bare error of creating a file in it.
+pub fn ensure_output_dir(output_dir: &Path, mkdir: bool) -> io::Result<()> {
+    if output_dir.as_os_str().is_empty() || output_dir.is_dir() {
+        return Ok(());
+    }
+    if mkdir {
+        return std::fs::create_dir_all(output_dir);
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("Output directory {:?} does not exist, pass --mkdir to create it.", output_dir)))
+}
+
+/// Writes the whole archive to `archive_path`, including the central directory patches and the checksum file,
+/// which is named after the output. With a stdin entry name, standard input is archived instead of the folder.
+pub fn write_archive(archive_path: &Path, output_zip_path: &Path, folder_path: &Path, stdin_entry: Option<&str>, settings: &CompressionSettings, options: OutputOptions) -> io::Result<RunSummary> {
+    let file = File::create(archive_path)?;
+    let zip = ZipWriter::new(file);
+    let zip_mutex = Mutex::new(zip);
+
+    println!("Creating zip file at {:?}", output_zip_path);
+
+    let summary = if let Some(entry_name) = stdin_entry {
+        add_stdin_to_zip(&zip_mutex, entry_name, settings)?
+    } else if options.convert_to_binary || settings.convert_types.is_some() {
+        println!("Converting files to binary and adding to zip...");
+        add_files_to_zip(&zip_mutex, folder_path, settings)?
+    } else {
+        println!("Adding files directly to zip...");
+        // Standard compression without binary conversion
+        add_files_directly_to_zip(&zip_mutex, folder_path, settings)?
+    };
+
+    let zip = zip_mutex.into_inner().unwrap();
+    zip.finish()?;
+    if !summary.source_paths.is_empty() {
+        let comments = summary.source_paths.iter()
+            .map(|(entry_name, source_path)| (entry_name.clone(), source_path.to_string_lossy().into_owned()))
+            .collect();
+        central_directory::set_entry_comments(archive_path, &comments)?;
+    }
+    if let Some(host_os) = options.host_os {
+        central_directory::set_host_os(archive_path, host_os)?;
+    }
+    if let Some(report) = &settings.report {
+        report::write_report(report, archive_path, &summary.report_rows)?;
+    }
+    if let Some(checksum_algorithm) = settings.checksum_algorithm {
+        checksum::write_checksum_file(archive_path, output_zip_path, checksum_algorithm)?;
+    }
+    Ok(summary)
+}
+
+/// Writes the archive of a folder, or of stdin, to the output. The archive is only moved to the output once it
+/// is complete, so a failed run leaves no half-written zip behind.
+pub fn compress_to_output(folder_path: &Path, output_zip_path: &Path, stdin_entry: Option<&str>, settings: &CompressionSettings, options: OutputOptions) -> io::Result<RunSummary> {
+    let archive_path = if options.streaming {
+        streaming::temp_archive_path(&settings.temp_dir)
+    } else if options.no_atomic {
+        output_zip_path.to_path_buf()
+    } else {
+        streaming::atomic_temp_path(output_zip_path)
+    };
+    let summary = match write_archive(&archive_path, output_zip_path, folder_path, stdin_entry, settings, options) {
+        Err(e) => {
+            // A cancelled archive or one that ran out of disk space is incomplete, so it isn't left behind either way
+            let incomplete = matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::StorageFull);
+            if archive_path != output_zip_path || incomplete {
+                let _ = std::fs::remove_file(&archive_path);
+            }
+            if e.kind() == io::ErrorKind::StorageFull {
+                return Err(streaming::out_of_space_error(&archive_path));
+            }
+            return Err(e);
+        }
+        Ok(summary) => summary,
+    };
+    if options.streaming {
+        streaming::stream_to_output(&archive_path, output_zip_path)?;
+    } else if archive_path != output_zip_path {
+        std::fs::rename(&archive_path, output_zip_path)?;
+    }
+    Ok(summary)
+}
+
+/// Writes one archive per immediate subfolder of the folder, named `<subfolder>.zip`, into the output directory,
+/// for `--per-subfolder`. Returns the number of files that failed in all of them.
+pub fn compress_per_subfolder(folder_path: &Path, output_dir: &Path, settings: &mut CompressionSettings, options: OutputOptions) -> io::Result<usize> {
+    let subfolders = subfolders(folder_path, output_dir, settings)?;
+    let mut failed_files = 0;
+    for subfolder in &subfolders {
+        let mut archive_name = subfolder.file_name().unwrap_or_default().to_os_string();
+        archive_name.push(".zip");
+        let subfolder_zip = output_dir.join(archive_name);
+        println!("Compressing {:?} into {:?}", subfolder, subfolder_zip);
+        settings.output_zip = canonical_output_path(&subfolder_zip);
+        failed_files += compress_to_output(subfolder, &subfolder_zip, None, settings, options)?.failures.len();
+    }
+    println!("Wrote {} archives to {:?}", subfolders.len(), output_dir);
+    Ok(failed_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use super::*;
+    use crate::collector::canonical_output_path;
+    use crate::test_support::{entry_names, random_bytes, settings, write_files, TempDir};
+
+    #[test]
+    fn complete_archive_is_renamed_to_the_output() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("b.txt", b"b")]);
+        let output = dir.join("out.zip");
+        compress_to_output(&dir.join("input"), &output, None, &settings(&output), OutputOptions::default()).unwrap();
+        assert!(output.is_file());
+        assert!(!streaming::atomic_temp_path(&output).exists());
+    }
+
+    #[test]
+    fn failed_archive_leaves_the_output_untouched() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("b.txt", b"b")]);
+        let output = dir.join("out.zip");
+        // Cancelling before the first entry makes the run fail once the temporary archive was created
+        let settings = CompressionSettings { cancel: Some(Arc::new(AtomicBool::new(true))), ..settings(&output) };
+        let Err(e) = compress_to_output(&dir.join("input"), &output, None, &settings, OutputOptions::default()) else {
+            panic!("a cancelled run succeeded");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert!(!output.exists());
+        assert!(!streaming::atomic_temp_path(&output).exists());
+
+        std::fs::write(&output, b"previous archive").unwrap();
+        assert!(compress_to_output(&dir.join("input"), &output, None, &settings, OutputOptions::default()).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"previous archive");
+    }
+
+    #[test]
+    fn archive_inside_its_input_folder_leaves_itself_out() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a")]);
+        let output = input.join("out.zip");
+        let settings = CompressionSettings { output_zip: canonical_output_path(&output), ..settings(&output) };
+        // The second run finds the first archive in the folder
+        for _ in 0..2 {
+            compress_to_output(&input, &output, None, &settings, OutputOptions::default()).unwrap();
+            assert_eq!(entry_names(&output), ["a.txt"]);
+        }
+    }
+
+    #[test]
+    fn missing_output_directory_is_only_created_with_mkdir() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a")]);
+        let output = dir.join("nonexistent/out.zip");
+
+        let e = ensure_output_dir(output.parent().unwrap(), false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("nonexistent"));
+        assert!(!dir.join("nonexistent").exists());
+
+        ensure_output_dir(output.parent().unwrap(), true).unwrap();
+        compress_to_output(&dir.join("input"), &output, None, &settings(&output), OutputOptions::default()).unwrap();
+        assert_eq!(entry_names(&output), ["a.txt"]);
+        assert!(ensure_output_dir(Path::new(""), false).is_ok());
+    }
+
+    #[test]
+    fn each_subfolder_gets_its_own_archive() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("one/a.txt", b"a"), ("one/deep/b.txt", b"b"), ("two/c.txt", b"c"), ("top.txt", b"left out")]);
+        let output_dir = dir.join("archives");
+        std::fs::create_dir(&output_dir).unwrap();
+
+        let mut settings = settings(&output_dir.join("unused.zip"));
+        assert_eq!(compress_per_subfolder(&input, &output_dir, &mut settings, OutputOptions::default()).unwrap(), 0);
+        let mut archives: Vec<String> = std::fs::read_dir(&output_dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        archives.sort();
+        assert_eq!(archives, ["one.zip", "two.zip"]);
+
+        // The entries are named relative to their subfolder
+        let mut one = entry_names(&output_dir.join("one.zip"));
+        one.sort();
+        assert_eq!(one, ["a.txt", "deep/b.txt"]);
+        assert_eq!(entry_names(&output_dir.join("two.zip")), ["c.txt"]);
+        let mut archive = zip::ZipArchive::new(File::open(output_dir.join("one.zip")).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("deep/b.txt").unwrap()).unwrap(), "b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streamed_archive_is_read_back_from_a_fifo() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("nested/b.txt", b"b")]);
+        let output = dir.join("out.zip");
+        crate::test_support::make_fifo(&output);
+        let fifo = output.clone();
+        let reader = std::thread::spawn(move || std::fs::read(fifo).unwrap());
+
+        let options = OutputOptions { streaming: true, ..OutputOptions::default() };
+        compress_to_output(&dir.join("input"), &output, None, &settings(&output), options).unwrap();
+        let bytes = reader.join().unwrap();
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes)).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("nested/b.txt").unwrap()).unwrap(), "b");
+        assert_eq!(archive.len(), 2);
+        assert!(!streaming::temp_archive_path(dir.path()).exists());
+    }
+
+    // Unmounts the filesystem when the test ends, even when it fails
+    #[cfg(target_os = "linux")]
+    struct Mount<'a>(&'a Path);
+
+    #[cfg(target_os = "linux")]
+    impl Drop for Mount<'_> {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("umount").arg(self.0).status();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_disk_leaves_no_partial_archive() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("noise.bin", &random_bytes(2 * 1024 * 1024, 5))]);
+        let full = dir.join("full");
+        std::fs::create_dir(&full).unwrap();
+        // Mounting a filesystem small enough to fill needs root, elsewhere the test has nothing to run on
+        let mounted = std::process::Command::new("mount").args(["-t", "tmpfs", "-o", "size=1m", "tmpfs"]).arg(&full)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !mounted {
+            println!("Skipping, a tmpfs can't be mounted at {:?}", full);
+            return;
+        }
+        let _mount = Mount(&full);
+
+        let output = full.join("out.zip");
+        let modes = [
+            OutputOptions::default(),
+            OutputOptions { no_atomic: true, ..OutputOptions::default() },
+            OutputOptions { streaming: true, ..OutputOptions::default() },
+        ];
+        for options in modes {
+            // The streamed archive is built outside of the full filesystem and only fails once it is copied there
+            let settings = CompressionSettings { temp_dir: dir.path().to_path_buf(), ..settings(&output) };
+            let Err(e) = compress_to_output(&input, &output, None, &settings, options) else {
+                panic!("{:?} fit on a full disk", options);
+            };
+            assert_eq!(e.kind(), io::ErrorKind::StorageFull, "{:?}", options);
+            assert!(e.to_string().starts_with("Not enough disk space"), "{}", e);
+            assert!(e.to_string().contains(&format!("{:?}", full.canonicalize().unwrap())), "{}", e);
+            assert_eq!(std::fs::read_dir(&full).unwrap().count(), 0, "{:?}", options);
+            assert!(!streaming::temp_archive_path(dir.path()).exists());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::utils::copy_via_mmap;
+
+static FILE_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// One kind of file the conversion to binary supports. Adding a convertible type means
+/// implementing this and dispatching to it from `convert_to_target_format`.
+pub trait BinaryConverter {
+    /// Name of the file kind in the log lines, e.g. `Image`
+    fn kind(&self) -> &'static str;
+
+    /// Writes the binary form of `source_path` into `output_folder` and returns its path.
+    /// By default the bytes are copied into `<file name>.bin`.
+    fn to_binary(&self, source_path: &Path, output_folder: &Path) -> io::Result<PathBuf> {
+        copy_to_binary(self.kind(), source_path, output_folder)
+    }
+}
+
+/// Copies the bytes of `source_path` into `<file name>.bin` in `output_folder`, for converters that
+/// only change the copy afterwards
+pub fn copy_to_binary(kind: &str, source_path: &Path, output_folder: &Path) -> io::Result<PathBuf> {
+    let mut binary_file_name = source_path.file_name().unwrap_or_default().to_os_string();
+    binary_file_name.push(".bin");
+    let binary_file_path = output_folder.join(binary_file_name);
+
+    copy_via_mmap(source_path, &binary_file_path)?;
+
+    let count = FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+    println!("{}: {} file: {:?} converted to Binary file: {:?}", count, kind, source_path.file_name().unwrap(), binary_file_path.file_name().unwrap());
+
+    Ok(binary_file_path)
+}
+
+/// Video, audio and other files aren't converted, they are copied under their own name
+pub struct MediaConverter;
+
+impl BinaryConverter for MediaConverter {
+    fn kind(&self) -> &'static str {
+        "Media"
+    }
+
+    fn to_binary(&self, source_path: &Path, output_folder: &Path) -> io::Result<PathBuf> {
+        let output_path = output_folder.join(source_path.file_name().unwrap());
+        std::fs::copy(source_path, &output_path)?;
+        Ok(output_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::convert_to_target_format;
+    use crate::image_processing::ImageConverter;
+    use crate::text_to_binary::{LineEnding, TextConverter};
+    use crate::test_support::{write_files, TempDir};
+
+    // A converter only naming its kind gets the default copy into `<file name>.bin`
+    struct ArchiveConverter;
+
+    impl BinaryConverter for ArchiveConverter {
+        fn kind(&self) -> &'static str {
+            "Archive"
+        }
+    }
+
+    #[test]
+    fn converters_write_their_binary_form() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("photo.png", b"\x89PNG"), ("notes.txt", b"one\ntwo\n"), ("backup.tar", b"tar")]);
+        let output = dir.join("output");
+        std::fs::create_dir_all(&output).unwrap();
+
+        let image = ImageConverter.to_binary(&dir.join("input/photo.png"), &output).unwrap();
+        assert_eq!(image, output.join("photo.png.bin"));
+        assert_eq!(std::fs::read(image).unwrap(), b"\x89PNG");
+        let text = TextConverter { line_ending: Some(LineEnding::Crlf) }.to_binary(&dir.join("input/notes.txt"), &output).unwrap();
+        assert_eq!(std::fs::read(text).unwrap(), b"one\r\ntwo\r\n");
+        let archive = ArchiveConverter.to_binary(&dir.join("input/backup.tar"), &output).unwrap();
+        assert_eq!(archive, output.join("backup.tar.bin"));
+    }
+
+    #[test]
+    fn files_are_dispatched_to_the_converter_of_their_type() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("notes.txt", b"a\r\nb"), ("clip.mp4", b"mp4")]);
+        let output = dir.join("output");
+        std::fs::create_dir_all(&output).unwrap();
+
+        let text = convert_to_target_format(&dir.join("input/notes.txt"), &output, Some(LineEnding::Lf)).unwrap();
+        assert_eq!(std::fs::read(text).unwrap(), b"a\nb");
+        // Media files are copied under their own name
+        assert_eq!(convert_to_target_format(&dir.join("input/clip.mp4"), &output, None).unwrap(), output.join("clip.mp4"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Entry listing the binary-converted entries of archives written with `--binary-naming sidecar`
+pub const BINARY_SIDECAR_NAME: &str = ".rust-zip-binary.json";
+
+/// How converted files are named in the archive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BinaryNaming {
+    /// Converted files get a `.bin` suffix, e.g. `photo.png.bin`
+    #[default]
+    Suffix,
+    /// Converted files keep their original name and are listed in a metadata entry
+    Sidecar,
+}
+
+pub fn sidecar_json(binary_entries: &[String]) -> String {
+    json!({ "binary_entries": binary_entries }).to_string()
+}
+
+/// The names of the binary-converted entries, or None for archives without a sidecar entry
+pub fn read_sidecar(archive: &mut ZipArchive<File>) -> io::Result<Option<HashSet<String>>> {
+    let mut sidecar = match archive.by_name(BINARY_SIDECAR_NAME) {
+        Ok(sidecar) => sidecar,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::new();
+    sidecar.read_to_end(&mut data)?;
+
+    let sidecar: Value = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid binary sidecar: {}", e)))?;
+    let entries = sidecar["binary_entries"].as_array().cloned().unwrap_or_default();
+    Ok(Some(entries.iter().filter_map(Value::as_str).map(str::to_owned).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompression::decompress_and_convert_to_files;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress_converted, entry_names, extract_options, settings, write_files, TempDir};
+
+    #[tokio::test]
+    async fn sidecar_naming_round_trips() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("notes.txt", b"notes"), ("docs/more.txt", b"more"), ("data.dat", b"data")]);
+        let zip_path = dir.join("sidecar.zip");
+        compress_converted(&dir.join("input"), &CompressionSettings { binary_naming: BinaryNaming::Sidecar, ..settings(&zip_path) });
+
+        let mut names = entry_names(&zip_path);
+        names.sort();
+        assert_eq!(names, [BINARY_SIDECAR_NAME, "data.dat", "docs/more.txt", "notes.txt"]);
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let binary_entries = read_sidecar(&mut archive).unwrap().unwrap();
+        assert_eq!(binary_entries, HashSet::from(["notes.txt".to_owned(), "docs/more.txt".to_owned()]));
+
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &extract_options()).await.unwrap();
+        for (name, contents) in [("notes.txt", "notes"), ("docs/more.txt", "more"), ("data.dat", "data")] {
+            assert_eq!(std::fs::read_to_string(dir.join("output").join(name)).unwrap(), contents);
+        }
+        assert!(!dir.join("output").join(BINARY_SIDECAR_NAME).exists());
+        assert!(!dir.join("output/notes.txt.bin").exists());
+    }
+}
@@ -0,0 +1,118 @@
+use std::io;
+use std::path::{Component, Path};
+use serde_json::{json, Value};
+
+/// Entry listing the entries compressed with Brotli before being stored, as zip has no Brotli method
+pub const BROTLI_MANIFEST_NAME: &str = ".rust-zip-brotli.json";
+
+/// Compresses everything read from `reader` into `writer`, returns the number of bytes read
+#[cfg(feature = "brotli")]
+pub fn compress_into(reader: &mut impl io::Read, writer: &mut impl io::Write, quality: u32) -> io::Result<u64> {
+    use std::io::Write;
+    let mut compressor = brotli::CompressorWriter::new(writer, 64 * 1024, quality, 22);
+    let read = io::copy(reader, &mut compressor)?;
+    compressor.flush()?;
+    // Writes the end of the stream
+    compressor.into_inner();
+    Ok(read)
+}
+
+pub fn brotli_manifest_json(names: &[String]) -> String {
+    json!({ "brotli_files": names }).to_string()
+}
+
+/// Decompresses the extracted files listed in the Brotli manifest in place, then removes the manifest.
+/// Does nothing for archives without Brotli entries.
+pub fn restore_brotli_files(output_folder: &Path) -> io::Result<()> {
+    let manifest_path = output_folder.join(BROTLI_MANIFEST_NAME);
+    if !manifest_path.is_file() {
+        return Ok(());
+    }
+    if cfg!(not(feature = "brotli")) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "The archive has Brotli entries, build with `--features brotli` to extract them"));
+    }
+
+    let manifest: Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Brotli manifest: {}", e)))?;
+    let names = manifest["brotli_files"].as_array().cloned().unwrap_or_default();
+
+    for name in &names {
+        let Some(name) = name.as_str() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid entry in Brotli manifest"));
+        };
+        if !Path::new(name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in Brotli manifest: {}", name)));
+        }
+
+        let compressed_path = output_folder.join(name);
+        let mut decompressed_name = compressed_path.file_name().unwrap_or_default().to_owned();
+        decompressed_name.push(".brotli");
+        let decompressed_path = compressed_path.with_file_name(decompressed_name);
+        decompress_file(&compressed_path, &decompressed_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not decompress Brotli entry {}: {}", name, e)))?;
+        std::fs::rename(&decompressed_path, &compressed_path)?;
+    }
+    if !names.is_empty() {
+        println!("Decompressed {} Brotli files", names.len());
+    }
+
+    std::fs::remove_file(&manifest_path)?;
+    Ok(())
+}
+
+#[cfg(feature = "brotli")]
+fn decompress_file(compressed_path: &Path, decompressed_path: &Path) -> io::Result<()> {
+    let mut decompressor = brotli::Decompressor::new(std::fs::File::open(compressed_path)?, 64 * 1024);
+    io::copy(&mut decompressor, &mut std::fs::File::create(decompressed_path)?)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "brotli"))]
+fn decompress_file(_compressed_path: &Path, _decompressed_path: &Path) -> io::Result<()> {
+    unreachable!("Brotli manifests are rejected without the brotli feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompression_wo_conversion::decompress_files;
+    use crate::test_support::{extract_options, TempDir};
+
+    #[cfg(feature = "brotli")]
+    #[tokio::test]
+    async fn brotli_entries_round_trip() {
+        use crate::entry_writer::CompressionSettings;
+        use crate::test_support::{compress, settings, write_files};
+
+        let dir = TempDir::new();
+        let text = "Brotli is good at text like this line.\n".repeat(1000);
+        write_files(&dir.join("input"), &[("notes.txt", text.as_bytes()), ("nested/empty.txt", b"")]);
+        let zip_path = dir.join("out.zip");
+        let settings = CompressionSettings { compression_algorithm: "Brotli".to_owned(), compression_level: 9, ..settings(&zip_path) };
+        compress(&dir.join("input"), &settings);
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&zip_path).unwrap()).unwrap();
+        let entry = archive.by_name("notes.txt").unwrap();
+        assert_eq!(entry.compression(), zip::CompressionMethod::Stored);
+        assert!(entry.size() < text.len() as u64 / 10);
+        drop(entry);
+        assert!(archive.by_name(BROTLI_MANIFEST_NAME).is_ok());
+
+        decompress_files(&zip_path, &dir.join("output"), &extract_options()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("output/notes.txt")).unwrap(), text);
+        assert_eq!(std::fs::read(dir.join("output/nested/empty.txt")).unwrap(), b"");
+        assert!(!dir.join("output").join(BROTLI_MANIFEST_NAME).exists());
+    }
+
+    #[cfg(not(feature = "brotli"))]
+    #[tokio::test]
+    async fn brotli_entries_need_the_brotli_feature() {
+        use crate::test_support::write_zip;
+
+        let dir = TempDir::new();
+        let manifest = brotli_manifest_json(&["notes.txt".to_owned()]);
+        write_zip(&dir.join("out.zip"), &[("notes.txt", b"compressed"), (BROTLI_MANIFEST_NAME, manifest.as_bytes())]);
+        let e = decompress_files(&dir.join("out.zip"), &dir.join("output"), &extract_options()).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+}
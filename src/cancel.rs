@@ -0,0 +1,102 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Set from another thread, e.g. a GUI's cancel button or Ctrl-C, to stop a compression or extraction
+/// between two entries
+pub type CancelFlag = Option<Arc<AtomicBool>>;
+
+pub fn is_cancelled(cancel: &CancelFlag) -> bool {
+    cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+pub fn cancelled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Cancelled")
+}
+
+// Returns the `Cancelled` error once the flag is set, for the `?` between entries
+pub fn check_cancelled(cancel: &CancelFlag) -> io::Result<()> {
+    if is_cancelled(cancel) {
+        return Err(cancelled_error());
+    }
+    Ok(())
+}
+
+/// A flag set by the first Ctrl-C, so the command line stops like a GUI caller would
+pub fn cancel_on_ctrl_c() -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Cancelling...");
+            flag.store(true, Ordering::Relaxed);
+        }
+    });
+    cancel
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use super::*;
+    use crate::archive_output::{write_archive, OutputOptions};
+    use crate::decompression_wo_conversion::{decompress_files, ExtractOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::progress::{ProgressCallback, ProgressEvent};
+    use crate::test_support::{extract_options, settings, write_files, write_zip, TempDir};
+
+    const FILES: usize = 200;
+
+    // Sets the flag once the first file is done and counts the files done
+    fn cancel_after_first_file(cancel: &Arc<AtomicBool>, done: &Arc<AtomicUsize>) -> ProgressCallback {
+        let (cancel, done) = (cancel.clone(), done.clone());
+        Box::new(move |event| {
+            if let ProgressEvent::FileDone { .. } = event {
+                done.fetch_add(1, Ordering::SeqCst);
+                cancel.store(true, Ordering::SeqCst);
+            }
+        })
+    }
+
+    fn names() -> Vec<String> {
+        (0..FILES).map(|i| format!("file_{}.txt", i)).collect()
+    }
+
+    #[test]
+    fn compression_stops_partway() {
+        let dir = TempDir::new();
+        let names = names();
+        let files: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), name.as_bytes())).collect();
+        write_files(&dir.join("input"), &files);
+        let (cancel, done) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+        let output = dir.join("out.zip");
+        let settings = CompressionSettings {
+            cancel: Some(cancel.clone()),
+            progress: Some(cancel_after_first_file(&cancel, &done)),
+            ..settings(&output)
+        };
+        let Err(e) = write_archive(&output, &output, &dir.join("input"), None, &settings, OutputOptions::default()) else {
+            panic!("a cancelled compression succeeded");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert!(done.load(Ordering::SeqCst) < FILES);
+    }
+
+    #[tokio::test]
+    async fn extraction_stops_partway() {
+        let dir = TempDir::new();
+        let names = names();
+        let entries: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), name.as_bytes())).collect();
+        write_zip(&dir.join("many.zip"), &entries);
+        let (cancel, done) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+        let options = ExtractOptions {
+            cancel: Some(cancel.clone()),
+            progress: Some(cancel_after_first_file(&cancel, &done)),
+            ..extract_options()
+        };
+        let e = decompress_files(&dir.join("many.zip"), &dir.join("output"), &options).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Interrupted);
+        assert!(std::fs::read_dir(dir.join("output")).unwrap().count() < FILES);
+        assert!(!dir.join("output").join(&names[FILES - 1]).exists());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use clap::ValueEnum;
+use zip::ZipArchive;
+
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EXTRA_FIELD_TAG: u16 = 0x0001;
+
+/// The zip crate only keeps the last central directory record for each entry name, so entries
+/// shadowed by a later entry with the same name are invisible through `ZipArchive`.
+/// This walks the central directory itself and returns the local header offsets of those entries,
+/// in archive order, so they can be read with `read_zipfile_from_stream`.
+pub fn shadowed_entry_offsets(archive: &mut ZipArchive<File>, reader: &mut File) -> io::Result<Vec<u64>> {
+    if archive.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut visible_offsets = HashSet::new();
+    for i in 0..archive.len() {
+        visible_offsets.insert(archive.by_index_raw(i)?.header_start());
+    }
+
+    let directory_start = central_directory_start(reader)? + archive.offset();
+    let mut shadowed = Vec::new();
+    reader.seek(SeekFrom::Start(directory_start))?;
+    let mut reader = io::BufReader::new(reader);
+    loop {
+        let mut record = [0u8; 46];
+        if reader.read_exact(&mut record).is_err() || le_u32(&record[0..4]) != CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+            break;
+        }
+        let uncompressed_size = le_u32(&record[24..28]);
+        let compressed_size = le_u32(&record[20..24]);
+        let name_length = le_u16(&record[28..30]) as usize;
+        let extra_length = le_u16(&record[30..32]) as usize;
+        let comment_length = le_u16(&record[32..34]) as i64;
+        let mut header_start = le_u32(&record[42..46]) as u64;
+
+        let mut name_and_extra = vec![0u8; name_length + extra_length];
+        reader.read_exact(&mut name_and_extra)?;
+        reader.seek_relative(comment_length)?;
+
+        if header_start == u32::MAX as u64 {
+            header_start = zip64_header_start(&name_and_extra[name_length..], uncompressed_size, compressed_size)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing zip64 offset in central directory"))?;
+        }
+        let header_start = header_start + archive.offset();
+
+        if !visible_offsets.contains(&header_start) {
+            shadowed.push(header_start);
+        }
+    }
+    Ok(shadowed)
+}
+
+/// Host system stored in the "version made by" field of the central directory, which tells readers
+/// how to interpret the external attributes holding the permissions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HostOs {
+    /// Unix permissions, what the zip crate writes
+    Unix,
+    /// MS-DOS and FAT attributes, readers ignore the Unix permissions
+    Fat,
+    /// Windows NTFS attributes, readers ignore the Unix permissions
+    Ntfs,
+}
+
+impl HostOs {
+    fn code(self) -> u8 {
+        match self {
+            HostOs::Fat => 0,
+            HostOs::Unix => 3,
+            HostOs::Ntfs => 11,
+        }
+    }
+}
+
+/// The zip crate always records Unix as the host system, so the upper byte of "version made by"
+/// is patched in place in every central directory record of the finished archive
+pub fn set_host_os(zip_path: &Path, host_os: HostOs) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(zip_path)?;
+    let directory_start = central_directory_start(&mut file)?;
+    let mut directory = Vec::new();
+    file.seek(SeekFrom::Start(directory_start))?;
+    file.read_to_end(&mut directory)?;
+
+    let mut position = 0;
+    while directory.len() >= position + 46 && le_u32(&directory[position..]) == CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+        directory[position + 5] = host_os.code();
+        let name_length = le_u16(&directory[position + 28..]) as usize;
+        let extra_length = le_u16(&directory[position + 30..]) as usize;
+        let comment_length = le_u16(&directory[position + 32..]) as usize;
+        position += 46 + name_length + extra_length + comment_length;
+    }
+    file.seek(SeekFrom::Start(directory_start))?;
+    file.write_all(&directory[..position])
+}
+
+/// The zip crate can't write entry comments, so they are added to the central directory of the finished archive.
+/// The records after the central directory are moved by the added length and their sizes and offsets updated.
+pub fn set_entry_comments(zip_path: &Path, comments: &HashMap<String, String>) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(zip_path)?;
+    let directory_start = central_directory_start(&mut file)?;
+    let mut directory = Vec::new();
+    file.seek(SeekFrom::Start(directory_start))?;
+    file.read_to_end(&mut directory)?;
+
+    let mut patched = Vec::with_capacity(directory.len());
+    let mut position = 0;
+    while directory.len() >= position + 46 && le_u32(&directory[position..]) == CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+        let name_length = le_u16(&directory[position + 28..]) as usize;
+        let extra_length = le_u16(&directory[position + 30..]) as usize;
+        let comment_length = le_u16(&directory[position + 32..]) as usize;
+        let record_length = 46 + name_length + extra_length + comment_length;
+        let mut record = directory[position..position + record_length].to_vec();
+        let name = String::from_utf8_lossy(&record[46..46 + name_length]);
+        if let Some(comment) = comments.get(name.as_ref()).filter(|comment| comment.len() <= u16::MAX as usize) {
+            record.truncate(46 + name_length + extra_length);
+            record[32..34].copy_from_slice(&(comment.len() as u16).to_le_bytes());
+            if !comment.is_ascii() {
+                // Bit 11 of the flags, in their high byte, marks the name and comment as UTF-8
+                record[9] |= 0x08;
+            }
+            record.extend_from_slice(comment.as_bytes());
+        }
+        patched.extend_from_slice(&record);
+        position += record_length;
+    }
+    let added = (patched.len() - position) as u64;
+
+    let mut tail = directory[position..].to_vec();
+    if tail.len() >= 56 && le_u32(&tail) == ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+        add_le_u64(&mut tail[40..48], added);
+        if tail.len() >= 76 && le_u32(&tail[56..]) == ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE {
+            add_le_u64(&mut tail[64..72], added);
+        }
+    }
+    let end_record = (0..tail.len().saturating_sub(21)).rev()
+        .find(|&position| le_u32(&tail[position..]) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing end of central directory record"))?;
+    let directory_size = le_u32(&tail[end_record + 12..]);
+    if directory_size != u32::MAX {
+        let directory_size = u32::try_from(directory_size as u64 + added)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Central directory too large for the entry comments"))?;
+        tail[end_record + 12..end_record + 16].copy_from_slice(&directory_size.to_le_bytes());
+    }
+
+    patched.extend_from_slice(&tail);
+    file.seek(SeekFrom::Start(directory_start))?;
+    file.write_all(&patched)?;
+    file.set_len(directory_start + patched.len() as u64)
+}
+
+fn central_directory_start(reader: &mut File) -> io::Result<u64> {
+    // The end of central directory record is 22 bytes plus a comment of up to 64 KiB
+    let file_length = reader.seek(SeekFrom::End(0))?;
+    let search_length = file_length.min(22 + u16::MAX as u64);
+    reader.seek(SeekFrom::Start(file_length - search_length))?;
+    let mut tail = Vec::with_capacity(search_length as usize);
+    reader.take(search_length).read_to_end(&mut tail)?;
+
+    let end_record = (0..tail.len().saturating_sub(21)).rev()
+        .find(|&position| le_u32(&tail[position..]) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing end of central directory record"))?;
+    let directory_start = le_u32(&tail[end_record + 16..]);
+    if directory_start != u32::MAX {
+        return Ok(directory_start as u64);
+    }
+
+    // Zip64 archives keep the real offset in the zip64 end of central directory record,
+    // found through the locator just before the regular record
+    let locator = end_record.checked_sub(20)
+        .filter(|&position| le_u32(&tail[position..]) == ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing zip64 end of central directory locator"))?;
+    let zip64_end_record = u64::from_le_bytes(tail[locator + 8..locator + 16].try_into().unwrap());
+    reader.seek(SeekFrom::Start(zip64_end_record + 48))?;
+    let mut offset = [0u8; 8];
+    reader.read_exact(&mut offset)?;
+    Ok(u64::from_le_bytes(offset))
+}
+
+// The zip64 extra field only holds the values whose regular fields are saturated, in this order
+fn zip64_header_start(mut extra: &[u8], uncompressed_size: u32, compressed_size: u32) -> Option<u64> {
+    while extra.len() >= 4 {
+        let tag = le_u16(&extra[0..2]);
+        let length = le_u16(&extra[2..4]) as usize;
+        let data = extra.get(4..4 + length)?;
+        if tag == ZIP64_EXTRA_FIELD_TAG {
+            let mut position = 0;
+            if uncompressed_size == u32::MAX {
+                position += 8;
+            }
+            if compressed_size == u32::MAX {
+                position += 8;
+            }
+            return data.get(position..position + 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        }
+        extra = &extra[4 + length..];
+    }
+    None
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn add_le_u64(bytes: &mut [u8], added: u64) {
+    let value = u64::from_le_bytes(bytes[..8].try_into().unwrap()) + added;
+    bytes[..8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_output::{write_archive, OutputOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{settings, write_files, TempDir};
+
+    // The fixed-size part of every central directory record
+    fn directory_records(zip_path: &Path) -> Vec<Vec<u8>> {
+        let mut file = File::open(zip_path).unwrap();
+        let directory_start = central_directory_start(&mut file).unwrap();
+        let mut directory = Vec::new();
+        file.seek(SeekFrom::Start(directory_start)).unwrap();
+        file.read_to_end(&mut directory).unwrap();
+
+        let mut records = Vec::new();
+        let mut position = 0;
+        while le_u32(&directory[position..]) == CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+            records.push(directory[position..position + 46].to_vec());
+            position += 46 + (le_u16(&directory[position + 28..]) + le_u16(&directory[position + 30..]) + le_u16(&directory[position + 32..])) as usize;
+        }
+        records
+    }
+
+    fn entry_flags(zip_path: &Path) -> Vec<u16> {
+        directory_records(zip_path).iter().map(|record| le_u16(&record[8..])).collect()
+    }
+
+    #[test]
+    fn non_ascii_source_path_is_stored_as_a_utf8_comment() {
+        let dir = TempDir::new();
+        let input = dir.join("crème");
+        write_files(&input, &[("a.txt", b"a")]);
+        let plain_zip = dir.join("plain.zip");
+        write_archive(&plain_zip, &plain_zip, &input, None, &settings(&plain_zip), OutputOptions::default()).unwrap();
+        let commented_zip = dir.join("commented.zip");
+        let settings = CompressionSettings { record_source_path: true, ..settings(&commented_zip) };
+        write_archive(&commented_zip, &commented_zip, &input, None, &settings, OutputOptions::default()).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&commented_zip).unwrap()).unwrap();
+        let source_path = std::path::absolute(input.join("a.txt")).unwrap();
+        assert_eq!(archive.by_name("a.txt").unwrap().comment(), source_path.to_str().unwrap());
+        let plain_flags = entry_flags(&plain_zip);
+        assert_eq!(entry_flags(&commented_zip), plain_flags.iter().map(|flags| flags | 0x0800).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn host_os_is_set_in_every_record() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a"), ("nested/b.txt", b"b")]);
+        for (host_os, code) in [(HostOs::Fat, 0), (HostOs::Unix, 3), (HostOs::Ntfs, 11)] {
+            let zip_path = dir.join("out.zip");
+            let options = OutputOptions { host_os: Some(host_os), ..OutputOptions::default() };
+            write_archive(&zip_path, &zip_path, &input, None, &settings(&zip_path), options).unwrap();
+            let records = directory_records(&zip_path);
+            assert_eq!(records.len(), 2);
+            assert!(records.iter().all(|record| record[5] == code), "{:?}", host_os);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+
+/// Digest written to the checksum file next to the archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    #[cfg(feature = "sha256")]
+    Sha256,
+    #[cfg(feature = "sha1")]
+    Sha1,
+    Blake3,
+    #[cfg(feature = "md5")]
+    Md5,
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the algorithm as given on the command line, e.g. `sha256`
+    pub fn name(self) -> String {
+        self.to_possible_value().map(|value| value.get_name().to_owned()).unwrap_or_default()
+    }
+
+    // The extension used by the matching `*sum` tool, e.g. `archive.zip.sha256` for `sha256sum`
+    fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "sha256")]
+            ChecksumAlgorithm::Sha256 => "sha256",
+            #[cfg(feature = "sha1")]
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Blake3 => "b3",
+            #[cfg(feature = "md5")]
+            ChecksumAlgorithm::Md5 => "md5",
+        }
+    }
+}
+
+/// Digest of one of the algorithms, fed incrementally
+pub enum ContentHasher {
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+    #[cfg(feature = "sha1")]
+    Sha1(sha1::Sha1),
+    Blake3(Box<blake3::Hasher>),
+    #[cfg(feature = "md5")]
+    Md5(md5::Md5),
+}
+
+impl ContentHasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            #[cfg(feature = "sha256")]
+            ChecksumAlgorithm::Sha256 => ContentHasher::Sha256(sha2::Digest::new()),
+            #[cfg(feature = "sha1")]
+            ChecksumAlgorithm::Sha1 => ContentHasher::Sha1(sha1::Digest::new()),
+            ChecksumAlgorithm::Blake3 => ContentHasher::Blake3(Box::new(blake3::Hasher::new())),
+            #[cfg(feature = "md5")]
+            ChecksumAlgorithm::Md5 => ContentHasher::Md5(md5::Digest::new()),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            #[cfg(feature = "sha256")]
+            ContentHasher::Sha256(hasher) => sha2::Digest::update(hasher, bytes),
+            #[cfg(feature = "sha1")]
+            ContentHasher::Sha1(hasher) => sha1::Digest::update(hasher, bytes),
+            ContentHasher::Blake3(hasher) => { hasher.update(bytes); }
+            #[cfg(feature = "md5")]
+            ContentHasher::Md5(hasher) => md5::Digest::update(hasher, bytes),
+        }
+    }
+
+    /// Returns the lowercase hex digest
+    pub fn finalize(self) -> String {
+        let digest: Vec<u8> = match self {
+            #[cfg(feature = "sha256")]
+            ContentHasher::Sha256(hasher) => sha2::Digest::finalize(hasher).to_vec(),
+            #[cfg(feature = "sha1")]
+            ContentHasher::Sha1(hasher) => sha1::Digest::finalize(hasher).to_vec(),
+            ContentHasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            #[cfg(feature = "md5")]
+            ContentHasher::Md5(hasher) => md5::Digest::finalize(hasher).to_vec(),
+        };
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Hashes the file with the algorithm and returns the lowercase hex digest
+pub fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> io::Result<String> {
+    let mut hasher = ContentHasher::new(algorithm);
+    read_chunks(&mut File::open(path)?, |chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize())
+}
+
+fn read_chunks(file: &mut File, mut update: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        update(&buffer[..read]);
+    }
+}
+
+/// CRC32 and optional digest of the bytes of one entry
+pub struct EntryHashes {
+    pub crc32: u32,
+    pub digest: Option<String>,
+}
+
+/// Feeds the bytes read through it to the hashers, so an entry is hashed by the same read that compresses it
+/// instead of the file being read again afterwards
+pub struct HashingReader<R> {
+    inner: R,
+    crc32: crc32fast::Hasher,
+    digest: Option<ContentHasher>,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algorithm: Option<ChecksumAlgorithm>) -> Self {
+        HashingReader { inner, crc32: crc32fast::Hasher::new(), digest: algorithm.map(ContentHasher::new) }
+    }
+
+    pub fn finish(self) -> EntryHashes {
+        EntryHashes { crc32: self.crc32.finalize(), digest: self.digest.map(ContentHasher::finalize) }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc32.update(&buf[..read]);
+        if let Some(digest) = &mut self.digest {
+            digest.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Hashes an entry that isn't streamed through a `HashingReader`, e.g. a file split into parts
+pub fn hash_entry_file(path: &Path, algorithm: Option<ChecksumAlgorithm>) -> io::Result<EntryHashes> {
+    let mut reader = HashingReader::new(File::open(path)?, algorithm);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+fn sidecar_path(archive_path: &Path, algorithm: ChecksumAlgorithm) -> PathBuf {
+    let mut checksum_path = archive_path.as_os_str().to_owned();
+    checksum_path.push(".");
+    checksum_path.push(algorithm.extension());
+    PathBuf::from(checksum_path)
+}
+
+/// Writes `<output>.<ext>` in the `<digest>  <file name>` format read by `sha256sum -c`, `b3sum -c` and the like.
+/// `archive_path` is the file hashed, which differs from the output with `--streaming`.
+pub fn write_checksum_file(archive_path: &Path, output_path: &Path, algorithm: ChecksumAlgorithm) -> io::Result<PathBuf> {
+    let digest = hash_file(archive_path, algorithm)?;
+    let file_name = output_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let checksum_path = sidecar_path(output_path, algorithm);
+    std::fs::write(&checksum_path, format!("{}  {}\n", digest, file_name))?;
+    println!("Wrote checksum file {:?}", checksum_path);
+    Ok(checksum_path)
+}
+
+/// Checks the archive against its checksum file before it is extracted, `<archive>.<ext>` for the first algorithm
+/// with such a file when none is given. The algorithm is picked by the extension of the checksum file.
+pub fn verify_checksum_file(archive_path: &Path, checksum_file: Option<&Path>) -> io::Result<()> {
+    let checksum_path = match checksum_file {
+        Some(path) => path.to_path_buf(),
+        None => ChecksumAlgorithm::value_variants().iter()
+            .map(|algorithm| sidecar_path(archive_path, *algorithm))
+            .find(|path| path.is_file())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No checksum file found next to {:?}", archive_path)))?,
+    };
+    let extension = checksum_path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    let algorithm = ChecksumAlgorithm::value_variants().iter().copied()
+        .find(|algorithm| algorithm.extension() == extension)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown checksum algorithm for {:?}", checksum_path)))?;
+
+    let contents = std::fs::read_to_string(&checksum_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not read checksum file {:?}: {}", checksum_path, e)))?;
+    let expected = contents.split_whitespace().next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Empty checksum file {:?}", checksum_path)))?;
+    let actual = hash_file(archive_path, algorithm)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Checksum mismatch for {:?}: expected {}, got {}", archive_path, expected, actual)));
+    }
+    println!("Checksum of {:?} matches {:?}", archive_path, checksum_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, random_bytes, settings, write_files, TempDir};
+
+    #[test]
+    fn blake3_sidecar_matches_the_reference_digest() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("out.zip", b"abc")]);
+        let checksum_path = write_checksum_file(&dir.join("out.zip"), &dir.join("out.zip"), ChecksumAlgorithm::Blake3).unwrap();
+        assert_eq!(checksum_path, dir.join("out.zip.b3"));
+        // The digest of "abc" from the BLAKE3 reference implementation, as printed by `b3sum`
+        assert_eq!(std::fs::read_to_string(checksum_path).unwrap(), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85  out.zip\n");
+    }
+
+    #[test]
+    fn streamed_hashes_equal_hashing_the_file_again() {
+        let dir = TempDir::new();
+        let bytes = random_bytes(300_000, 7);
+        write_files(&dir.join("input"), &[("data.bin", &bytes), ("small.txt", b"small")]);
+
+        let mut reader = HashingReader::new(File::open(dir.join("input/data.bin")).unwrap(), Some(ChecksumAlgorithm::Blake3));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let hashes = reader.finish();
+        assert_eq!(hashes.crc32, crc32fast::hash(&bytes));
+        assert_eq!(hashes.digest.unwrap(), hash_file(&dir.join("input/data.bin"), ChecksumAlgorithm::Blake3).unwrap());
+
+        // The manifest gets the digests hashed while the entries were compressed
+        let settings = CompressionSettings {
+            manifest: Some(dir.join("manifest.json")),
+            checksum_algorithm: Some(ChecksumAlgorithm::Blake3),
+            ..settings(&dir.join("out.zip"))
+        };
+        compress(&dir.join("input"), &settings);
+        let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest["entries"].as_array().unwrap().len(), 2);
+        for entry in manifest["entries"].as_array().unwrap() {
+            let source = dir.join("input").join(entry["name"].as_str().unwrap());
+            assert_eq!(entry["blake3"].as_str().unwrap(), hash_file(&source, ChecksumAlgorithm::Blake3).unwrap());
+        }
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn archive_is_checked_against_its_sidecar() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("out.zip", b"archive bytes")]);
+        let zip_path = dir.join("out.zip");
+        let checksum_path = write_checksum_file(&zip_path, &zip_path, ChecksumAlgorithm::Sha256).unwrap();
+        assert_eq!(checksum_path, dir.join("out.zip.sha256"));
+        verify_checksum_file(&zip_path, None).unwrap();
+        verify_checksum_file(&zip_path, Some(&checksum_path)).unwrap();
+
+        // The sidecar may also be given under another name, the extension still picks the algorithm
+        let tampered = dir.join("other.sha256");
+        std::fs::write(&tampered, format!("{}  out.zip\n", "0".repeat(64))).unwrap();
+        let e = verify_checksum_file(&zip_path, Some(&tampered)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().starts_with("Checksum mismatch"));
+
+        std::fs::remove_file(checksum_path).unwrap();
+        assert_eq!(verify_checksum_file(&zip_path, None).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use serde_json::{json, Value};
+use zip::{write::FileOptions, ZipWriter};
+
+/// Entry recording which files were split into parts, so extraction can put them back together
+pub const CHUNK_MANIFEST_NAME: &str = ".rust-zip-chunks.json";
+
+pub struct ChunkedFile {
+    pub name: String,
+    pub size: u64,
+    pub parts: u64,
+}
+
+pub fn part_name(name: &str, part: u64) -> String {
+    format!("{}.part{}", name, part)
+}
+
+/// Where a part is compressed before it is copied into the archive, the same for every run of this process
+pub fn part_temp_path(temp_dir: &Path, name: &str, part: u64) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    temp_dir.join(format!("rust-zip-{}-{:016x}.part{}.zip", std::process::id(), hasher.finish(), part))
+}
+
+// Each part is compressed into its own archive under the temp dir, so parts of one file compress in parallel
+// without holding the zip lock or the whole part in memory
+pub fn compress_part(source_path: &Path, name: &str, part: u64, chunk_size: u64, options: FileOptions<'_, ()>, temp_path: &Path) -> io::Result<()> {
+    let mut file = File::open(source_path)?;
+    file.seek(SeekFrom::Start(part * chunk_size))?;
+
+    let mut zip = ZipWriter::new(File::create(temp_path)?);
+    zip.start_file(part_name(name, part), options)?;
+    io::copy(&mut file.take(chunk_size), &mut zip)?;
+    zip.finish()?;
+    Ok(())
+}
+
+pub fn manifest_json(chunked_files: &[ChunkedFile]) -> String {
+    let files: Vec<Value> = chunked_files.iter()
+        .map(|file| json!({ "name": file.name, "size": file.size, "parts": file.parts }))
+        .collect();
+    json!({ "chunked_files": files }).to_string()
+}
+
+/// Joins the extracted parts listed in the chunk manifest back into their original files,
+/// then removes the parts and the manifest. Does nothing for archives without chunked files.
+pub fn reassemble_chunked_files(output_folder: &Path) -> io::Result<()> {
+    let manifest_path = output_folder.join(CHUNK_MANIFEST_NAME);
+    if !manifest_path.is_file() {
+        return Ok(());
+    }
+
+    let manifest: Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid chunk manifest: {}", e)))?;
+    let files = manifest["chunked_files"].as_array().cloned().unwrap_or_default();
+
+    for file in files {
+        let (Some(name), Some(size), Some(parts)) = (file["name"].as_str(), file["size"].as_u64(), file["parts"].as_u64()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid entry in chunk manifest"));
+        };
+        if !Path::new(name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in chunk manifest: {}", name)));
+        }
+
+        let output_path = output_folder.join(name);
+        let mut output = File::create(&output_path)?;
+        for part in 0..parts {
+            let part_path = output_folder.join(part_name(name, part));
+            io::copy(&mut File::open(&part_path)?, &mut output)?;
+            std::fs::remove_file(&part_path)?;
+        }
+
+        if output.metadata()?.len() != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Reassembled {} has the wrong size", name)));
+        }
+        println!("Reassembled {} from {} parts", name, parts);
+    }
+
+    std::fs::remove_file(&manifest_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompression_wo_conversion::decompress_files;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, entry_names, extract_options, settings, write_files, TempDir};
+
+    #[tokio::test]
+    async fn file_split_into_three_chunks_round_trips() {
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..2500u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_files(&dir.join("input"), &[("big.bin", &contents), ("small.txt", b"small")]);
+        let zip_path = dir.join("chunks.zip");
+        compress(&dir.join("input"), &CompressionSettings { chunk_large_files: Some(1024), ..settings(&zip_path) });
+
+        let mut names = entry_names(&zip_path);
+        names.sort();
+        assert_eq!(names, [CHUNK_MANIFEST_NAME, "big.bin.part0", "big.bin.part1", "big.bin.part2", "small.txt"]);
+
+        decompress_files(&zip_path, &dir.join("output"), &extract_options()).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/big.bin")).unwrap(), contents);
+        assert_eq!(std::fs::read(dir.join("output/small.txt")).unwrap(), b"small");
+        assert!(!dir.join("output/big.bin.part0").exists());
+        assert!(!dir.join("output").join(CHUNK_MANIFEST_NAME).exists());
+    }
+
+    #[test]
+    fn failed_part_leaves_no_parts_in_the_archive() {
+        let dir = TempDir::new();
+        let contents: Vec<u8> = (0..2500u32).map(|i| (i * 7 % 251) as u8).collect();
+        write_files(&dir.join("input"), &[("big.bin", &contents), ("small.txt", b"small")]);
+        let temp_dir = dir.join("tmp");
+        // A directory where the second part would be compressed makes that part fail
+        std::fs::create_dir_all(part_temp_path(&temp_dir, "big.bin", 1)).unwrap();
+        let zip_path = dir.join("chunks.zip");
+        let settings = CompressionSettings { chunk_large_files: Some(1024), keep_going: true, temp_dir: temp_dir.clone(), ..settings(&zip_path) };
+        let summary = compress(&dir.join("input"), &settings);
+
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(entry_names(&zip_path), ["small.txt"]);
+        let left: Vec<_> = std::fs::read_dir(&temp_dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(left, [part_temp_path(&temp_dir, "big.bin", 1)]);
+    }
+}
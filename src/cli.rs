@@ -0,0 +1,436 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Parser, Subcommand};
+
+use crate::binary_naming::BinaryNaming;
+use crate::central_directory::HostOs;
+use crate::checksum::ChecksumAlgorithm;
+use crate::color::ColorChoice;
+use crate::compression::{FileType, UnsupportedPolicy};
+use crate::decompression_wo_conversion::DuplicatePolicy;
+use crate::entry_writer::{MaxFilesAction, MethodRule, Zip64Mode};
+use crate::text_to_binary::LineEnding;
+
+#[derive(Parser)]
+#[command(about = "Blazingly fast Rust compressor and decompressor")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Commands,
+
+    /// Color the warnings and errors: auto, always or never. Auto colors them on a terminal unless NO_COLOR is set
+    #[arg(long, global = true, value_enum, default_value = "auto")]
+    pub color: ColorChoice,
+}
+
+// The commands are parsed once per run, so the size of the compression options doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Compress the files of a folder into a zip archive
+    Compression {
+        /// `-` compresses standard input into a single entry named by --entry-name
+        input_folder: PathBuf,
+        output_zip: PathBuf,
+        /// Defaults to the config value, then Zstd
+        compression_algorithm: Option<String>,
+        /// Defaults to the config value, then 3
+        #[arg(allow_negative_numbers = true)]
+        compression_level: Option<i64>,
+        /// Read settings from a TOML file, flags given on the command line take precedence
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Number of worker threads, defaults to one per CPU
+        #[arg(long)]
+        threads: Option<usize>,
+        /// Process the files in collection order instead of largest first
+        #[arg(long)]
+        no_lpt: bool,
+        /// Store the entries under this directory inside the archive
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Remove this leading path from the entry names, e.g. `/home/me/` for absolute paths read with --stdin
+        #[arg(long)]
+        strip_prefix: Option<String>,
+        /// Keep the names of the entries that don't start with --strip-prefix instead of failing
+        #[arg(long, requires = "strip_prefix")]
+        strip_prefix_lenient: bool,
+        /// Use another algorithm and level for one extension, e.g. `png=auto` or `txt=Zstd:19`, can be repeated
+        #[arg(long, value_parser = crate::utils::parse_method_rule)]
+        method_for: Vec<(String, MethodRule)>,
+        /// Retry an entry once with this algorithm, e.g. `Stored`, when compressing it fails
+        #[arg(long, value_parser = crate::utils::parse_fallback_method)]
+        fallback_method: Option<String>,
+        /// Also add this file under the given entry name, e.g. `dist/LICENSE=LICENSE`, can be repeated
+        #[arg(long, value_parser = crate::utils::parse_embed)]
+        embed: Vec<(PathBuf, String)>,
+        /// Convert the files to binary before compressing them
+        #[arg(long = "convert-to-binary", alias = "convert_to_binary")]
+        convert_to_binary: bool,
+        /// Only convert these types, e.g. `image,text`, and store the other files as they are. Implies --convert-to-binary
+        #[arg(long, value_enum, value_delimiter = ',')]
+        convert_types: Vec<FileType>,
+        /// Rewrite the line endings of text files while converting them (with --convert-to-binary)
+        #[arg(long, value_enum)]
+        normalize_eol: Option<LineEnding>,
+        /// How converted files are named in the archive (with --convert-to-binary)
+        #[arg(long, value_enum, default_value = "suffix")]
+        binary_naming: BinaryNaming,
+        /// What to do with files of a type no converter supports (with --convert-to-binary)
+        #[arg(long, value_enum, default_value = "copy")]
+        on_unsupported: UnsupportedPolicy,
+        /// Experimental: raise the Zstd level until a sample of the files reaches this ratio
+        #[arg(long)]
+        target_ratio: Option<f64>,
+        /// Also compress the files in subdirectories, keeping their relative paths
+        #[arg(long)]
+        recursive: bool,
+        /// Only walk this many directory levels below the input folder with --recursive, 0 keeps only its own files
+        #[arg(long)]
+        max_depth: Option<usize>,
+        /// Walk symlinks to directories with --recursive, by default they are skipped like in tar
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Walk Windows junctions with --recursive, by default they are skipped like symlinks
+        #[arg(long)]
+        dereference_junctions: bool,
+        /// Leave out the paths matching the patterns of this gitignore-style file, negations included
+        #[arg(long)]
+        exclude_from: Option<PathBuf>,
+        /// Leave out the files ignored by .gitignore files, and the .git directory
+        #[arg(long)]
+        respect_gitignore: bool,
+        /// Archive exactly the file paths read from stdin, stored relative to the input folder
+        #[arg(long)]
+        stdin: bool,
+        /// Name of the entry holding standard input when the input folder is `-`
+        #[arg(long, default_value = "stdin")]
+        entry_name: String,
+        /// The paths read with --stdin are NUL separated, as printed by `find -print0`
+        #[arg(long, requires = "stdin")]
+        null: bool,
+        /// Read FIFOs as streams instead of skipping them, other special files are always skipped
+        #[arg(long)]
+        include_special: bool,
+        /// Fail when two entry names only differ in case, as they collide when extracted on Windows or macOS
+        #[arg(long)]
+        case_fold_entries: bool,
+        /// Store the files under the input folder's name, e.g. `project/src/main.rs`
+        #[arg(long)]
+        include_root: bool,
+        /// Store the extended attributes of the files, restored by decompression with --xattrs
+        #[arg(long)]
+        xattrs: bool,
+        /// Store the uid and gid of the files, restored by decompression with --preserve-owner (Unix only)
+        #[arg(long)]
+        preserve_owner: bool,
+        /// Store the creation time of the files, restored by decompression with --preserve-ctime (Windows and macOS)
+        #[arg(long)]
+        preserve_ctime: bool,
+        /// Store the bytes of identical files only once, extraction with this tool restores the copies
+        #[arg(long)]
+        dedup: bool,
+        /// Write a JSON manifest with the name, original path, size, mtime and CRC32 of every entry to this path
+        #[arg(long)]
+        manifest: Option<PathBuf>,
+        /// Write a CSV with the name, original path, sizes, method, CRC32 and duration of every archived file to this path
+        #[arg(long)]
+        report: Option<PathBuf>,
+        /// Also write the archived files to a .tar.zst at this path, collected with the same filters as the zip
+        #[cfg(feature = "tar-zst")]
+        #[arg(long)]
+        also_tar_zst: Option<PathBuf>,
+        /// Store the absolute path of every source file in the comment of its entry, shown by `list`
+        #[arg(long)]
+        record_source_path: bool,
+        /// Host system recorded for the entries, which decides how other tools read their permissions back
+        #[arg(long, value_enum)]
+        host_os: Option<HostOs>,
+        /// When to write entries with zip64 headers
+        #[arg(long, value_enum, default_value = "auto")]
+        zip64: Zip64Mode,
+        /// Pad before every Stored entry so its data starts at a multiple of this many bytes, like zipalign
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        align: Option<u16>,
+        /// Split files larger than this size (e.g. 512M) into parts compressed in parallel
+        #[arg(long, value_parser = crate::utils::parse_size)]
+        chunk_large_files: Option<u64>,
+        /// Flush the archive every this many bytes (e.g. 64M) while a file is compressed, instead of once per file
+        #[arg(long, value_parser = crate::utils::parse_size)]
+        flush_interval: Option<u64>,
+        /// Store only the data of sparse files, such as VM images, and recreate their holes on extraction (Linux only)
+        #[arg(long)]
+        sparse: bool,
+        /// Seconds between progress lines when stdout isn't a terminal, 0 disables them
+        #[arg(long, default_value_t = 10)]
+        progress_interval: u64,
+        /// Stop starting new files after this long, e.g. 30s, 5m or 1h, and finish the archive with the files done so far
+        #[arg(long, value_parser = crate::utils::parse_duration)]
+        time_limit: Option<Duration>,
+        /// Stop after adding this many files, to catch an input folder much larger than expected
+        #[arg(long)]
+        max_files: Option<usize>,
+        /// Whether reaching --max-files finishes the archive with the files added so far or fails the run
+        #[arg(long, value_enum, default_value = "stop", requires = "max_files")]
+        max_files_action: MaxFilesAction,
+        /// Create the parent directory of the output zip if it doesn't exist
+        #[arg(long)]
+        mkdir: bool,
+        /// Directory for intermediate files, such as the converted copies, instead of the system temp directory
+        #[arg(long)]
+        temp_dir: Option<PathBuf>,
+        /// Build the archive in a temporary file and copy it to the output at the end, so the output can be a FIFO
+        #[arg(long)]
+        streaming: bool,
+        /// Write the output zip in place instead of to `<output_zip>.tmp` renamed once the archive is complete
+        #[arg(long)]
+        no_atomic: bool,
+        /// Write one archive per immediate subfolder of the input folder, named `<subfolder>.zip`, into the
+        /// output zip path used as a directory. Files directly in the input folder are left out
+        #[arg(long, conflicts_with_all = ["stdin", "streaming", "manifest", "report", "estimate"])]
+        per_subfolder: bool,
+        /// Print a rough estimate of the archive size, from compressing a sample of the files in memory
+        #[arg(long)]
+        estimate: bool,
+        /// Stop before writing the archive, e.g. after --estimate
+        #[arg(long)]
+        dry_run: bool,
+        /// List every failed file at the end and exit with an error if there were any
+        #[arg(long)]
+        keep_going: bool,
+        /// Don't print an error line for every failed file, only their number once all files are done
+        #[arg(long)]
+        quiet_errors: bool,
+        /// Encrypt the entries with AES-256 using this password, visible to other users in the process list
+        #[arg(long)]
+        password: Option<String>,
+        /// Read the password from this environment variable, used when --password isn't given
+        #[arg(long)]
+        password_env: Option<String>,
+        /// Read the password from the first line of stdin, used when neither --password nor --password-env is given
+        #[arg(long, conflicts_with = "stdin")]
+        password_stdin: bool,
+        /// Print how long opening, reading and compressing the files took in total
+        #[arg(long)]
+        verbose: bool,
+        /// Write a checksum file next to the archive, e.g. `archive.zip.sha256`, verifiable with `sha256sum -c`
+        #[arg(long, value_enum)]
+        checksum_algorithm: Option<ChecksumAlgorithm>,
+    },
+    /// Decompress a zip archive into a folder
+    Decompression {
+        zip_path: PathBuf,
+        #[arg(required_unless_present = "test")]
+        output_folder: Option<PathBuf>,
+        /// Extract into a subfolder of the output folder named after the archive, e.g. `backup/` for `backup.zip`
+        #[arg(long)]
+        into_subdir: bool,
+        /// Hash the archive before extracting it and stop if it doesn't match the checksum file next to it, e.g. `backup.zip.sha256`
+        #[arg(long)]
+        verify_checksum: bool,
+        /// Checksum file to verify the archive against instead of the one next to it, implies --verify-checksum
+        #[arg(long)]
+        checksum_file: Option<PathBuf>,
+        /// Read every entry in memory and discard it, reporting the entries that fail, without writing to disk
+        #[arg(long)]
+        test: bool,
+        /// Decompress the files without converting them back to their original format
+        #[arg(long = "decompress-without-conversion", alias = "decompress_without_conversion")]
+        decompress_without_conversion: bool,
+        /// Refuse to extract archives containing entries with absolute paths or drive letters
+        #[arg(long)]
+        safe: bool,
+        /// How to handle several entries extracted to the same path (entries with the exact same name only with --decompress-without-conversion)
+        #[arg(long, value_enum, default_value = "overwrite")]
+        on_duplicate: DuplicatePolicy,
+        /// Skip entries already extracted with a matching CRC32 (with --decompress-without-conversion)
+        #[arg(long)]
+        resume: bool,
+        /// Only extract entries missing on disk or newer than the existing file, like `unzip -u`
+        #[arg(long)]
+        update: bool,
+        /// Seconds between progress lines when stdout isn't a terminal, 0 disables them
+        #[arg(long, default_value_t = 10)]
+        progress_interval: u64,
+        /// Restore the extended attributes stored by compression with --xattrs
+        #[arg(long)]
+        xattrs: bool,
+        /// Restore the file owners stored by compression with --preserve-owner, which usually needs root
+        #[arg(long)]
+        preserve_owner: bool,
+        /// Restore the creation times stored by compression with --preserve-ctime, where the platform allows it
+        #[arg(long)]
+        preserve_ctime: bool,
+        /// Parse the JSON files while converting them back and warn about malformed ones
+        #[arg(long)]
+        validate_json: bool,
+        /// Only convert these types back, e.g. `image,text`, the other converted files are left as `.bin` files
+        #[arg(long, value_enum, value_delimiter = ',')]
+        convert_only: Vec<FileType>,
+        /// Stream entries larger than this size (e.g. 64M) to disk instead of reading them into memory first
+        #[arg(long, default_value = "64M", value_parser = crate::utils::parse_size)]
+        buffer_to_memory_threshold: u64,
+        /// Only extract the files of at least this uncompressed size (e.g. 10M)
+        #[arg(long, value_parser = crate::utils::parse_size)]
+        min_size: Option<u64>,
+        /// Only extract the files of at most this uncompressed size
+        #[arg(long, value_parser = crate::utils::parse_size)]
+        max_size: Option<u64>,
+        /// Extract the entries named FROM, or starting with FROM when it ends in /, as TO (e.g. old/=new/). Can be repeated
+        #[arg(long = "rename", value_name = "FROM=TO", value_parser = crate::utils::parse_rename)]
+        renames: Vec<(String, String)>,
+        /// Extract the entries on several threads instead of async tasks (with --decompress-without-conversion), or test them with --test
+        #[arg(long)]
+        parallel: bool,
+        /// Treat entry names differing only in case as duplicates, handled by --on-duplicate
+        #[arg(long)]
+        case_fold_entries: bool,
+        /// Password of the encrypted entries, visible to other users in the process list
+        #[arg(long)]
+        password: Option<String>,
+        /// Read the password from this environment variable, used when --password isn't given
+        #[arg(long)]
+        password_env: Option<String>,
+        /// Read the password from the first line of stdin, used when neither --password nor --password-env is given
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Append a single file to an existing zip archive
+    Add {
+        zip_path: PathBuf,
+        file: PathBuf,
+        #[arg(long, default_value = "Zstd")]
+        compression_algorithm: String,
+        #[arg(long, default_value_t = 3, allow_negative_numbers = true)]
+        compression_level: i64,
+        /// Name to store the file under, defaults to its file name
+        #[arg(long)]
+        entry_name: Option<String>,
+    },
+    /// Remove a single entry from an existing zip archive
+    Remove {
+        zip_path: PathBuf,
+        entry_name: String,
+    },
+    /// Rewrite a zip archive with another compression algorithm or level
+    Recompress {
+        input_zip: PathBuf,
+        output_zip: PathBuf,
+        compression_algorithm: String,
+        /// Without a level, the entries already using the algorithm are copied as they are
+        #[arg(allow_negative_numbers = true)]
+        compression_level: Option<i64>,
+        /// Only recompress the entries matching this glob, e.g. `*.txt`, the others are copied as they are. Can be repeated
+        #[arg(long)]
+        only: Vec<String>,
+    },
+    /// Copy the files a compression would archive into a folder instead of a zip, for debugging the filters
+    Copy {
+        input_folder: PathBuf,
+        output_folder: PathBuf,
+        /// Read the recursive, include, exclude and prefix settings from a TOML file
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// The options below work as for the compression command
+        #[arg(long)]
+        recursive: bool,
+        #[arg(long)]
+        max_depth: Option<usize>,
+        #[arg(long)]
+        follow_symlinks: bool,
+        #[arg(long)]
+        exclude_from: Option<PathBuf>,
+        #[arg(long)]
+        respect_gitignore: bool,
+        #[arg(long)]
+        include_root: bool,
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Report the files of a folder added, removed or modified since it was archived
+    Diff {
+        zip_path: PathBuf,
+        folder: PathBuf,
+        /// The options below work as for the compression command, and should match the ones the archive was written with
+        #[arg(long)]
+        recursive: bool,
+        #[arg(long)]
+        max_depth: Option<usize>,
+        #[arg(long)]
+        exclude_from: Option<PathBuf>,
+        #[arg(long)]
+        respect_gitignore: bool,
+        #[arg(long)]
+        include_root: bool,
+        #[arg(long)]
+        prefix: Option<String>,
+        #[arg(long)]
+        strip_prefix: Option<String>,
+        #[arg(long, requires = "strip_prefix")]
+        strip_prefix_lenient: bool,
+    },
+    /// Compress a sample of a folder's files at several levels and recommend a level, without writing an archive
+    Tune {
+        input_folder: PathBuf,
+        #[arg(long, default_value = "Zstd")]
+        compression_algorithm: String,
+    },
+    /// Tell whether a file is a valid zip archive, and print its entry count and compression methods
+    Probe {
+        path: PathBuf,
+    },
+    /// Print the supported compression algorithms with their valid levels
+    Algorithms,
+    /// Compress and extract a generated dataset with every supported algorithm and check the files come back unchanged
+    SelfTest,
+    /// List the entries of a zip archive with their sizes, method, CRC32 and mode
+    List {
+        zip_path: PathBuf,
+    },
+    /// Repackage a tar archive as a zip archive
+    #[cfg(feature = "tar")]
+    FromTar {
+        input_tar: PathBuf,
+        output_zip: PathBuf,
+        #[arg(long, default_value = "Zstd")]
+        compression_algorithm: String,
+        #[arg(long, default_value_t = 3, allow_negative_numbers = true)]
+        compression_level: i64,
+        /// Leave out the directory entries, for unzip tools that choke on them. Empty directories are lost
+        #[arg(long)]
+        no_dir_entries: bool,
+    },
+    /// Generate a deterministic folder of pseudo-random files for reproducing performance issues
+    #[cfg(feature = "dev")]
+    #[command(hide = true)]
+    GenCorpus {
+        dir: PathBuf,
+        #[arg(long, default_value_t = 1000)]
+        files: usize,
+        /// Average file size, e.g. 512, 4K or 1M
+        #[arg(long, default_value = "4K", value_parser = crate::utils::parse_size)]
+        avg_size: u64,
+        /// Share of random bytes in each file, from 0.0 (very compressible) to 1.0 (incompressible)
+        #[arg(long, default_value_t = 0.5)]
+        entropy: f64,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_lpt(args: &[&str]) -> bool {
+        let Commands::Compression { no_lpt, .. } = Args::parse_from(args).command else {
+            panic!("{:?} isn't a compression", args);
+        };
+        no_lpt
+    }
+
+    #[test]
+    fn largest_first_is_the_default() {
+        assert!(!no_lpt(&["rust-zip", "compression", "input", "out.zip"]));
+        assert!(no_lpt(&["rust-zip", "compression", "input", "out.zip", "--no-lpt"]));
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+
+use crate::entry_writer::CompressionSettings;
+use crate::streaming::atomic_temp_path;
+use crate::color;
+
+/// Collects the files to archive from a folder. Without `recursive` only the folder's own files
+/// are returned; with it, subdirectories are walked as well. Like tar, symlinks to directories are
+/// only walked with `follow_symlinks`, symlinks to files are always archived as the file they point to.
+/// Directories are tracked by their canonical path, so symlink cycles and directories reachable twice are only walked once.
+/// Paths matching the gitignore-style `exclude_from` file or `exclude_patterns` are left out, and when
+/// `include_patterns` are set only the files matching them are kept.
+/// With `paths_from_stdin` the folder isn't walked and exactly the files listed on stdin are returned.
+/// The output zip is left out when it is created inside the folder.
+pub fn collect_files(folder_path: &Path, settings: &CompressionSettings) -> io::Result<Vec<PathBuf>> {
+    let mut files = if settings.paths_from_stdin {
+        read_stdin_paths(settings.null_separated)?
+    } else {
+        let exclude = load_patterns(folder_path, settings.exclude_from.as_deref(), &settings.exclude_patterns)?;
+        let include = load_patterns(folder_path, None, &settings.include_patterns)?;
+        let mut walker = Walker { settings, exclude, include, visited: HashSet::new(), files: Vec::new() };
+        if settings.respect_gitignore {
+            walker.collect_gitignored(folder_path);
+        } else {
+            walker.collect_dir(folder_path, 0)?;
+        }
+        walker.files
+    };
+    let temp_zip = atomic_temp_path(&settings.output_zip);
+    files.retain(|path| !is_output_zip(path, &settings.output_zip) && !is_output_zip(path, &temp_zip));
+    #[cfg(feature = "tar-zst")]
+    if let Some(tar_zst) = &settings.also_tar_zst {
+        files.retain(|path| !is_output_zip(path, tar_zst));
+    }
+    Ok(files)
+}
+
+/// Lists the immediate subdirectories of a folder, sorted by name, each archived on its own with `--per-subfolder`.
+/// Symlinks to directories are only listed with `follow_symlinks`, and the output directory is left out when it
+/// is one of the subdirectories.
+pub fn subfolders(folder_path: &Path, output_dir: &Path, settings: &CompressionSettings) -> io::Result<Vec<PathBuf>> {
+    let output_dir = output_dir.canonicalize().ok();
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(folder_path)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if entry.file_type().is_ok_and(|own_type| own_type.is_symlink()) && !settings.follow_symlinks {
+            println!("Skipping directory symlink, pass --follow-symlinks to archive it: {:?}", path);
+            continue;
+        }
+        if output_dir.is_some() && path.canonicalize().ok() == output_dir {
+            println!("Skipping the output directory {:?}", path);
+            continue;
+        }
+        dirs.push(path);
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn read_stdin_paths(null_separated: bool) -> io::Result<Vec<PathBuf>> {
+    read_paths(io::stdin().lock(), null_separated)
+}
+
+// Reads newline separated paths, or NUL separated ones as printed by `find -print0`
+fn read_paths(mut reader: impl Read, null_separated: bool) -> io::Result<Vec<PathBuf>> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+    let separator = if null_separated { '\0' } else { '\n' };
+
+    let mut files = Vec::new();
+    for line in input.split(separator) {
+        let line = if null_separated { line } else { line.trim_end_matches('\r') };
+        if line.is_empty() {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if path.is_file() {
+            files.push(path);
+        } else {
+            println!("Skipping path from stdin that is not a file: {:?}", path);
+        }
+    }
+    Ok(files)
+}
+
+// The output zip may not exist yet, so its directory is canonicalized instead of the file itself
+pub fn canonical_output_path(output_zip: &Path) -> PathBuf {
+    let parent = output_zip.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match (parent.canonicalize(), output_zip.file_name()) {
+        (Ok(parent), Some(file_name)) => parent.join(file_name),
+        _ => output_zip.to_path_buf(),
+    }
+}
+
+// Only files sharing the output's file name are canonicalized, so large folders don't pay a syscall per file
+fn is_output_zip(path: &Path, output_zip: &Path) -> bool {
+    if path.file_name() != output_zip.file_name() {
+        return false;
+    }
+    if path.canonicalize().is_ok_and(|path| path == output_zip) {
+        println!("Skipping the output archive: {:?}", path);
+        return true;
+    }
+    false
+}
+
+// Patterns are matched relative to the input folder, like a .gitignore at its top
+fn load_patterns(folder_path: &Path, pattern_file: Option<&Path>, patterns: &[String]) -> io::Result<Option<Gitignore>> {
+    if pattern_file.is_none() && patterns.is_empty() {
+        return Ok(None);
+    }
+    let invalid = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid exclude pattern: {}", e));
+    let mut builder = GitignoreBuilder::new(folder_path);
+    if let Some(pattern_file) = pattern_file {
+        if let Some(e) = builder.add(pattern_file) {
+            return Err(invalid(e));
+        }
+    }
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(invalid)?;
+    }
+    builder.build().map(Some).map_err(invalid)
+}
+
+struct Walker<'a> {
+    settings: &'a CompressionSettings,
+    exclude: Option<Gitignore>,
+    include: Option<Gitignore>,
+    visited: HashSet<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl Walker<'_> {
+    // `depth` is the number of directory levels `dir` is below the input folder
+    fn collect_dir(&mut self, dir: &Path, depth: usize) -> io::Result<()> {
+        if !self.visited.insert(dir.canonicalize()?) {
+            println!("Skipping already visited directory: {:?}", dir);
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            // Symlinks are classified by what they point to
+            let file_type = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.file_type(),
+                Err(e) => {
+                    println!("Skipping unreadable path: {:?}, {:?}", path, e);
+                    continue;
+                }
+            };
+            if file_type.is_dir() && entry.file_type().is_ok_and(|own_type| own_type.is_symlink()) && !self.follows_link(&path) {
+                continue;
+            }
+            // As in git, files inside an excluded directory can't be re-included, since it isn't walked
+            if self.exclude.as_ref().is_some_and(|exclude| exclude.matched(&path, file_type.is_dir()).is_ignore()) {
+                println!("Excluding {:?}", path);
+                continue;
+            }
+            if file_type.is_dir() {
+                if !self.settings.recursive {
+                    println!("Skipping directory: {:?}", path);
+                } else if !self.too_deep(&path, depth + 1) {
+                    self.collect_dir(&path, depth + 1)?;
+                }
+            } else {
+                self.add_non_directory(path, &file_type);
+            }
+        }
+        Ok(())
+    }
+
+    // With `respect_gitignore` the walk of the ignore crate is used instead, which skips the paths
+    // matched by .gitignore files and the .git directory itself. It can only follow every link or none,
+    // so for `dereference_junctions` it follows them all and leaves out the directory symlinks here.
+    fn collect_gitignored(&mut self, folder_path: &Path) {
+        let mut builder = WalkBuilder::new(folder_path);
+        let follow_symlinks = self.settings.follow_symlinks;
+        builder.hidden(false).require_git(false).follow_links(follow_symlinks || self.settings.dereference_junctions);
+        if !self.settings.recursive {
+            builder.max_depth(Some(1));
+        }
+        let exclude = self.exclude.clone();
+        let max_depth = self.settings.max_depth;
+        builder.filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+            if entry.file_name() == ".git" && is_dir {
+                return false;
+            }
+            if is_dir && entry.path_is_symlink() && !follow_symlinks && !is_junction(entry.path()) {
+                println!("Skipping directory symlink, pass --follow-symlinks to walk it: {:?}", entry.path());
+                return false;
+            }
+            // The walk counts the input folder's own entries as depth 1, a directory there is one level down
+            if is_dir && max_depth.is_some_and(|max_depth| entry.depth() > max_depth) {
+                warn_too_deep(entry.path(), max_depth.unwrap_or_default());
+                return false;
+            }
+            !exclude.as_ref().is_some_and(|exclude| exclude.matched(entry.path(), is_dir).is_ignore())
+        });
+
+        for result in builder.build() {
+            let entry = match result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    println!("Skipping unreadable path: {:?}", e);
+                    continue;
+                }
+            };
+            let Some(mut file_type) = entry.file_type() else { continue };
+            // Without following links the walk reports symlinks as they are, so symlinks to files are resolved here
+            if file_type.is_symlink() {
+                match std::fs::metadata(entry.path()) {
+                    Ok(metadata) if metadata.is_dir() => {
+                        self.follows_link(entry.path());
+                        continue;
+                    }
+                    Ok(metadata) => file_type = metadata.file_type(),
+                    Err(e) => {
+                        println!("Skipping unreadable path: {:?}, {:?}", entry.path(), e);
+                        continue;
+                    }
+                }
+            }
+            if !file_type.is_dir() {
+                self.add_non_directory(entry.into_path(), &file_type);
+            }
+        }
+    }
+
+    // Directories more than `--max-depth` levels below the input folder aren't walked
+    fn too_deep(&self, dir: &Path, depth: usize) -> bool {
+        match self.settings.max_depth {
+            Some(max_depth) if depth > max_depth => {
+                warn_too_deep(dir, max_depth);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Decides whether a symlink to a directory is walked, printing why when it isn't. Windows junctions
+    // are reported as symlinks too, they are also walked with `dereference_junctions`.
+    fn follows_link(&self, path: &Path) -> bool {
+        if self.settings.follow_symlinks {
+            return true;
+        }
+        if is_junction(path) {
+            if self.settings.dereference_junctions {
+                return true;
+            }
+            println!("Skipping junction, pass --dereference-junctions to walk it: {:?}", path);
+            return false;
+        }
+        println!("Skipping directory symlink, pass --follow-symlinks to walk it: {:?}", path);
+        false
+    }
+
+    fn add_non_directory(&mut self, path: PathBuf, file_type: &std::fs::FileType) {
+        // A directory pattern such as `src` or `docs/` includes every file under it
+        if self.include.as_ref().is_some_and(|include| !include.matched_path_or_any_parents(&path, false).is_ignore()) {
+            return;
+        }
+        if file_type.is_file() {
+            self.files.push(path);
+        } else if let Some(kind) = special_file_kind(file_type) {
+            if kind == "FIFO" && self.settings.include_special {
+                self.files.push(path);
+            } else {
+                println!("{} skipping special file ({}): {:?}", color::warning(), kind, path);
+            }
+        } else {
+            println!("Skipping non-file or directory: {:?}", path);
+        }
+    }
+}
+
+fn warn_too_deep(dir: &Path, max_depth: usize) {
+    println!("{} skipping directory deeper than --max-depth {}: {:?}", color::warning(), max_depth, dir);
+}
+
+// Junctions are directory reparse points. The standard library doesn't expose the reparse tag that
+// tells them apart from directory symlinks, so every directory reparse point counts as a junction.
+#[cfg(windows)]
+fn is_junction(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+    std::fs::symlink_metadata(path).is_ok_and(|metadata| {
+        let attributes = metadata.file_attributes();
+        attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0 && attributes & FILE_ATTRIBUTE_DIRECTORY != 0
+    })
+}
+
+#[cfg(not(windows))]
+fn is_junction(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn special_file_kind(file_type: &std::fs::FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(_file_type: &std::fs::FileType) -> Option<&'static str> {
+    None
+}
+
+// Entry names are the paths relative to the input folder. Paths outside of it, which can come from stdin,
+// keep their own path without the root or `.` and `..` components. Paths that aren't UTF-8 have no entry name.
+pub fn entry_name(folder_path: &Path, path: &Path) -> io::Result<String> {
+    let relative = match path.strip_prefix(folder_path) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path.components().filter(|c| matches!(c, Component::Normal(_))).collect(),
+    };
+    match relative.to_str() {
+        Some(relative) => Ok(normalize_separators(relative)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} has no UTF-8 path to use as the entry name", path))),
+    }
+}
+
+// The directory all entry names start with: the configured prefix, then with `--include-root` the input
+// folder's own name, like GitHub zipballs. The folder is canonicalized so inputs like `.` get their real name.
+pub fn entry_prefix(folder_path: &Path, settings: &CompressionSettings) -> io::Result<String> {
+    let mut prefix = String::new();
+    if let Some(configured) = settings.prefix.as_deref().map(|p| normalize_separators(p.trim_matches(['/', '\\']))) {
+        if !configured.is_empty() {
+            prefix = format!("{}/", configured);
+        }
+    }
+    if settings.include_root {
+        let folder_path = folder_path.canonicalize()?;
+        if let Some(name) = folder_path.file_name().and_then(|name| name.to_str()) {
+            prefix += &format!("{}/", normalize_separators(name));
+        }
+    }
+    Ok(prefix)
+}
+
+/// The name a file is archived under: its entry name without `--strip-prefix`, after the `root` from [`entry_prefix`].
+/// The prefix is matched by whole components, so `/home/me` doesn't strip `/home/meg/`. Its leading separators
+/// are ignored, since entry names never start with one. Names not starting with it are an error, unless
+/// `strip_prefix_lenient` keeps them unchanged.
+pub fn archive_entry_name(folder_path: &Path, path: &Path, root: &str, settings: &CompressionSettings) -> io::Result<String> {
+    let name = entry_name(folder_path, path)?;
+    let Some(strip_prefix) = settings.strip_prefix.as_deref().map(|p| normalize_separators(p.trim_matches(['/', '\\']))).filter(|p| !p.is_empty()) else {
+        return Ok(format!("{}{}", root, name));
+    };
+    match name.strip_prefix(strip_prefix.as_str()).and_then(|rest| rest.strip_prefix('/')) {
+        Some(rest) => Ok(format!("{}{}", root, rest)),
+        None if settings.strip_prefix_lenient => Ok(format!("{}{}", root, name)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Entry {} doesn't start with --strip-prefix {}, pass --strip-prefix-lenient to keep it", name, strip_prefix))),
+    }
+}
+
+/// Sorts the files largest first, so with `par_iter` the big files start early and the small ones fill in
+/// at the end instead of one big file keeping a single thread busy after the others are done.
+/// Files whose size can't be read go last.
+pub fn sort_largest_first(files: &mut [PathBuf]) {
+    files.sort_by_cached_key(|path| std::cmp::Reverse(std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)));
+}
+
+/// Fails when two entry names differ only in case, as they would overwrite each other when extracted
+/// on a case-insensitive filesystem such as the defaults on Windows and macOS
+pub fn check_case_collisions<'a>(names: impl Iterator<Item = &'a str>) -> io::Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for name in names {
+        if let Some(previous) = seen.insert(name.to_lowercase(), name) {
+            if previous != name {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Entries {} and {} only differ in case", previous, name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Zip entry names always use forward slashes, backslashes from Windows paths break extraction on Unix
+pub fn normalize_separators(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compress, compress_converted, entry_names, settings, write_files, TempDir};
+    #[cfg(unix)]
+    use crate::test_support::make_fifo;
+
+    // The entry names of the collected files, sorted
+    fn collected(folder: &Path, settings: &CompressionSettings) -> Vec<String> {
+        let mut names: Vec<String> = collect_files(folder, settings).unwrap().iter().map(|path| entry_name(folder, path).unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_cycle_is_walked_once() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("top.txt", b"top"), ("a/inner.txt", b"inner")]);
+        std::os::unix::fs::symlink("..", input.join("a/loop")).unwrap();
+        let settings = CompressionSettings { follow_symlinks: true, ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), ["a/inner.txt", "top.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directory_symlink_is_not_walked_by_default() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("top.txt", b"top")]);
+        write_files(&dir.join("outside"), &[("secret.txt", b"outside the tree")]);
+        std::os::unix::fs::symlink(dir.join("outside"), input.join("linked")).unwrap();
+        assert_eq!(collected(&input, &settings(&dir.join("out.zip"))), ["top.txt"]);
+
+        let settings = CompressionSettings { follow_symlinks: true, ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), ["linked/secret.txt", "top.txt"]);
+
+        // The gitignore walk follows links for junctions, a symlink still isn't walked there
+        let settings = CompressionSettings { follow_symlinks: false, respect_gitignore: true, dereference_junctions: true, ..settings };
+        assert_eq!(collected(&input, &settings), ["top.txt"]);
+        let settings = CompressionSettings { follow_symlinks: true, ..settings };
+        assert_eq!(collected(&input, &settings), ["linked/secret.txt", "top.txt"]);
+    }
+
+    // Junctions need no privileges to create, unlike directory symlinks on Windows
+    #[cfg(windows)]
+    #[test]
+    fn junction_is_not_walked_by_default() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("top.txt", b"top")]);
+        write_files(&dir.join("outside"), &[("secret.txt", b"outside the tree")]);
+        let created = std::process::Command::new("cmd")
+            .args(["/C", "mklink", "/J"])
+            .arg(input.join("junction"))
+            .arg(dir.join("outside"))
+            .status()
+            .unwrap();
+        assert!(created.success());
+        assert!(is_junction(&input.join("junction")));
+        assert_eq!(collected(&input, &settings(&dir.join("out.zip"))), ["top.txt"]);
+
+        let settings = CompressionSettings { dereference_junctions: true, ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), ["junction/secret.txt", "top.txt"]);
+        let settings = CompressionSettings { respect_gitignore: true, ..settings };
+        assert_eq!(collected(&input, &settings), ["junction/secret.txt", "top.txt"]);
+    }
+
+    #[test]
+    fn backslashes_become_forward_slashes() {
+        assert_eq!(normalize_separators("docs\\guide\\intro.md"), "docs/guide/intro.md");
+        assert_eq!(normalize_separators("docs/intro.md"), "docs/intro.md");
+        assert_eq!(entry_name(Path::new("input"), Path::new("input/docs/intro.md")).unwrap(), "docs/intro.md");
+        let settings = CompressionSettings { prefix: Some("release\\v1\\".to_owned()), ..CompressionSettings::default() };
+        assert_eq!(entry_prefix(Path::new("input"), &settings).unwrap(), "release/v1/");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_without_a_utf8_name_fails_alone() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a")]);
+        std::fs::write(input.join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt")), b"cafe").unwrap();
+
+        let summary = compress(&input, &settings(&dir.join("out.zip")));
+        assert_eq!(summary.failures.len(), 1, "{:?}", summary.failures);
+        assert_eq!(entry_names(&dir.join("out.zip")), ["a.txt"]);
+
+        let summary = compress_converted(&input, &settings(&dir.join("converted.zip")));
+        assert_eq!(summary.failures.len(), 1, "{:?}", summary.failures);
+        assert_eq!(entry_names(&dir.join("converted.zip")), ["a.txt.bin"]);
+    }
+
+    #[test]
+    fn include_root_puts_the_folder_name_first() {
+        let dir = TempDir::new();
+        let project = dir.join("project");
+        write_files(&project, &[("src/main.rs", b"fn main() {}"), ("Cargo.toml", b"[package]")]);
+        let zip_path = dir.join("project.zip");
+        compress(&project, &CompressionSettings { include_root: true, ..settings(&zip_path) });
+
+        let mut names = entry_names(&zip_path);
+        names.sort();
+        assert_eq!(names, ["project/Cargo.toml", "project/src/main.rs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifo_is_skipped_as_a_special_file() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a")]);
+        make_fifo(&input.join("pipe"));
+        let file_type = std::fs::metadata(input.join("pipe")).unwrap().file_type();
+        assert_eq!(special_file_kind(&file_type), Some("FIFO"));
+        assert_eq!(special_file_kind(&std::fs::metadata(input.join("a.txt")).unwrap().file_type()), None);
+
+        assert_eq!(collected(&input, &settings(&dir.join("out.zip"))), ["a.txt"]);
+        let settings = CompressionSettings { include_special: true, ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), ["a.txt", "pipe"]);
+    }
+
+    #[test]
+    fn paths_are_read_one_per_line_or_nul_separated() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("a.txt", b"a"), ("with space.txt", b"b")]);
+        let (a, spaced) = (dir.join("a.txt"), dir.join("with space.txt"));
+
+        let lines = format!("{}\r\n{}\n\n{}\n", a.display(), spaced.display(), dir.join("missing.txt").display());
+        assert_eq!(read_paths(lines.as_bytes(), false).unwrap(), [a.clone(), spaced.clone()]);
+        let nul_separated = format!("{}\0{}\0", a.display(), spaced.display());
+        assert_eq!(read_paths(nul_separated.as_bytes(), true).unwrap(), [a, spaced]);
+    }
+
+    #[test]
+    fn strip_prefix_removes_home_me_from_absolute_paths() {
+        let input = Path::new("/srv/input");
+        let settings = CompressionSettings { strip_prefix: Some("/home/me/".to_owned()), ..CompressionSettings::default() };
+        assert_eq!(archive_entry_name(input, Path::new("/home/me/docs/a.txt"), "backup/", &settings).unwrap(), "backup/docs/a.txt");
+        // Whole components are matched, so `/home/meg/` isn't under it
+        let e = archive_entry_name(input, Path::new("/home/meg/b.txt"), "", &settings).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("home/meg/b.txt"), "{}", e);
+
+        let settings = CompressionSettings { strip_prefix_lenient: true, ..settings };
+        assert_eq!(archive_entry_name(input, Path::new("/home/meg/b.txt"), "", &settings).unwrap(), "home/meg/b.txt");
+        assert_eq!(archive_entry_name(input, Path::new("/home/me/c.txt"), "", &settings).unwrap(), "c.txt");
+    }
+
+    #[test]
+    fn exclude_file_negation_re_includes_a_file() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("src/main.rs", b"main"), ("build/out.o", b"o"), ("build/keep.txt", b"keep"), ("logs/a.log", b"a"), ("logs/keep.log", b"keep")]);
+        std::fs::write(dir.join("ignore"), "build/*\n!build/keep.txt\n# As in git, a file of an excluded directory stays excluded\nlogs/\n!logs/keep.log\n").unwrap();
+        let settings = CompressionSettings { exclude_from: Some(dir.join("ignore")), ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), ["build/keep.txt", "src/main.rs"]);
+    }
+
+    #[test]
+    fn include_patterns_keep_the_files_under_a_directory() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("src/main.rs", b"main"), ("src/nested/lib.rs", b"lib"), ("docs/guide.md", b"guide"), ("build/out.o", b"o"), ("readme.md", b"readme")]);
+        let settings = CompressionSettings { include_patterns: vec!["src".to_owned(), "docs/".to_owned()], ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), ["docs/guide.md", "src/main.rs", "src/nested/lib.rs"]);
+
+        // The gitignore walk applies them the same way
+        let settings = CompressionSettings { respect_gitignore: true, ..settings };
+        assert_eq!(collected(&input, &settings), ["docs/guide.md", "src/main.rs", "src/nested/lib.rs"]);
+    }
+
+    #[test]
+    fn gitignored_files_and_the_git_directory_are_left_out() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[(".gitignore", b"*.log\ntarget/\n"), (".git/HEAD", b"ref"), ("src/lib.rs", b"lib"), ("debug.log", b"log"), ("target/app", b"app"), ("src/nested.log", b"log")]);
+        let settings = CompressionSettings { respect_gitignore: true, ..settings(&dir.join("out.zip")) };
+        assert_eq!(collected(&input, &settings), [".gitignore", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn names_differing_only_in_case_fail_the_run() {
+        assert!(check_case_collisions(["docs/README", "docs/guide.md"].into_iter()).is_ok());
+        let e = check_case_collisions(["docs/README", "docs/readme"].into_iter()).unwrap_err();
+        assert_eq!(e.to_string(), "Entries docs/README and docs/readme only differ in case");
+
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("README", b"upper"), ("readme", b"lower")]);
+        let output = dir.join("out.zip");
+        let settings = CompressionSettings { case_fold_entries: true, ..settings(&output) };
+        let Err(e) = crate::archive_output::write_archive(&output, &output, &dir.join("input"), None, &settings, Default::default()) else {
+            panic!("entries differing only in case were archived");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn max_depth_bounds_the_walk() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("top.txt", b"0"), ("one/a.txt", b"1"), ("one/two/b.txt", b"2"), ("one/two/three/c.txt", b"3")]);
+        // Both walks count the levels the same way, the input folder's own files are at depth 0
+        for respect_gitignore in [false, true] {
+            let settings = CompressionSettings { max_depth: Some(1), respect_gitignore, ..settings(&dir.join("out.zip")) };
+            assert_eq!(collected(&input, &settings), ["one/a.txt", "top.txt"]);
+            let settings = CompressionSettings { max_depth: Some(0), ..settings };
+            assert_eq!(collected(&input, &settings), ["top.txt"]);
+        }
+
+        let settings = CompressionSettings { max_depth: Some(1), ..settings(&dir.join("out.zip")) };
+        compress(&input, &settings);
+        let mut names = entry_names(&settings.output_zip);
+        names.sort();
+        assert_eq!(names, ["one/a.txt", "top.txt"]);
+    }
+}
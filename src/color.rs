@@ -0,0 +1,64 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::ValueEnum;
+
+/// When the `Warning`/`Error` prefixes of the printed messages are colored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Colored when stdout and stderr are terminals and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // https://no-color.org: any non-empty value turns colors off
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stdout().is_terminal()
+                && std::io::stderr().is_terminal()
+        }
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn paint(text: &str, code: &str) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// The `Warning:` prefix, yellow when colors are on
+pub fn warning() -> String {
+    paint("Warning:", "1;33")
+}
+
+/// The `Error` word starting error messages, red when colors are on
+pub fn error() -> String {
+    paint("Error", "1;31")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single test, since the choice is global to the process
+    #[test]
+    fn never_leaves_out_the_ansi_escapes() {
+        init(ColorChoice::Always);
+        assert_eq!(warning(), "\x1b[1;33mWarning:\x1b[0m");
+        init(ColorChoice::Never);
+        for text in [warning(), error(), format!("{} reading {:?}", error(), "a.txt")] {
+            assert!(!text.contains('\x1b'), "{:?}", text);
+        }
+        assert_eq!(error(), "Error");
+    }
+}
@@ -1,16 +1,24 @@
 use std::fs::File;
-use std::io;
+use std::io::{self, Write};
 use std::path::Path;
 use std::sync::Mutex;
 use std::path::PathBuf;
 use std::time::Instant;
+use clap::ValueEnum;
 use rayon::prelude::*;
 use zip::{write::FileOptions, ZipWriter};
 
-use crate::image_processing::image_to_binary_file;
-use crate::text_to_binary::text_to_binary_file;
-use crate::utils::get_compression_method;
+use crate::binary_naming::{sidecar_json, BinaryNaming, BINARY_SIDECAR_NAME};
+use crate::cancel::is_cancelled;
 
+use crate::collector::{archive_entry_name, check_case_collisions, collect_files, entry_name, entry_prefix, sort_largest_first};
+use crate::entry_writer::{CompressionSettings, EntryWriter, RunSummary};
+use crate::binary_converter::{BinaryConverter, MediaConverter};
+use crate::image_processing::ImageConverter;
+use crate::text_to_binary::{LineEnding, TextConverter};
+
+/// Category of a file by its extension, `--convert-types` picks the ones converted to binary
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
 pub enum FileType {
     Image,
     Video,
@@ -19,72 +27,173 @@ pub enum FileType {
     Other,
 }
 
+/// What to do with the files no converter supports, e.g. `.dat` files, with `--convert-to-binary`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum UnsupportedPolicy {
+    /// Leave them out of the archive
+    Skip,
+    /// Store them unchanged under their own name
+    #[default]
+    Copy,
+    /// Stop before writing anything
+    Error,
+}
+
 pub fn add_files_to_zip(
     zip: &Mutex<ZipWriter<File>>,
     folder_path: &Path,
-    compression_algorithm: &str,
-    compression_level: i64,
-    file_type: FileType,
-) -> io::Result<()> {
-    let output_folder = folder_path.join("output");
+    settings: &CompressionSettings,
+) -> io::Result<RunSummary> {
+    // The converted copies are written to a folder of their own under the temp directory, removed once they are archived
+    let output_folder = settings.temp_dir.join(format!("rust-zip-{}-converted", std::process::id()));
     let start = Instant::now();
     std::fs::create_dir_all(&output_folder)?;
+    println!("Writing the converted files to {:?}", output_folder);
 
-    let entries: Vec<_> = std::fs::read_dir(folder_path)?.filter_map(|e| e.ok()).collect();
+    // The temp directory may be inside the input folder, the converted files must not be picked up themselves
+    let mut files: Vec<_> = collect_files(folder_path, settings)?
+        .into_iter()
+        .filter(|path| !path.starts_with(&output_folder))
+        .collect();
+    match settings.on_unsupported {
+        UnsupportedPolicy::Copy => {}
+        UnsupportedPolicy::Skip => files.retain(|path| {
+            let supported = !is_unsupported(path);
+            if !supported {
+                println!("Skipping unsupported file: {:?}", path);
+            }
+            supported
+        }),
+        UnsupportedPolicy::Error => {
+            if let Some(path) = files.iter().find(|path| is_unsupported(path)) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported file type: {:?}", path)));
+            }
+        }
+    }
+    let root = entry_prefix(folder_path, settings)?;
+    if settings.largest_first {
+        sort_largest_first(&mut files);
+    }
+    // The names are checked before anything is written, so a name without the `--strip-prefix` fails the run early
+    if settings.case_fold_entries || settings.strip_prefix.is_some() {
+        let names = files.iter().map(|path| archive_entry_name(folder_path, path, &root, settings)).collect::<io::Result<Vec<String>>>()?;
+        if settings.case_fold_entries {
+            check_case_collisions(names.iter().map(String::as_str))?;
+        }
+    }
 
-    entries.par_iter().for_each(|entry| {
-        let path = entry.path();
-        if path.is_file() && file_type_matches(&path, &file_type) {
-            match convert_to_target_format(&path, &output_folder, &file_type) {
+    let writer = EntryWriter::new(zip, settings, files.len());
+    let binary_entries = Mutex::new(Vec::new());
+    files.par_iter().for_each(|path| {
+        if writer.deadline_passed() || is_cancelled(&settings.cancel) || writer.limit_reached() {
+            return;
+        }
+        if converts(path, settings) {
+            // Subdirectories are mirrored in the output folder so same-named files don't collide
+            let relative_parent = match entry_name(folder_path, path) {
+                Ok(name) => Path::new(&name).parent().map(Path::to_path_buf).unwrap_or_default(),
+                Err(e) => {
+                    writer.record_failure(path, format!("Error naming file: {:?}", e));
+                    return;
+                }
+            };
+            let converted_folder = output_folder.join(&relative_parent);
+            if let Err(e) = std::fs::create_dir_all(&converted_folder) {
+                writer.record_failure(path, format!("Error creating folder {:?}: {:?}", converted_folder, e));
+                return;
+            }
+            match convert_to_target_format(path, &converted_folder, settings.normalize_eol) {
                 Ok(output_file_path) => {
-                    let file_name = output_file_path.file_name().unwrap().to_str().unwrap();
-
-                    let (compression_method, valid_level) = match get_compression_method(compression_algorithm, compression_level) {
-                        Ok((method, level)) => (method, level),
+                    // Files that were converted, rather than copied, are listed in the sidecar under their original name
+                    let converted = output_file_path.file_name() != path.file_name();
+                    let file_name = if settings.binary_naming == BinaryNaming::Sidecar {
+                        archive_entry_name(folder_path, path, &root, settings)
+                    } else {
+                        archive_entry_name(&output_folder, &output_file_path, &root, settings)
+                    };
+                    let file_name = match file_name {
+                        Ok(file_name) => file_name,
                         Err(e) => {
-                            println!("Error getting compression method: {:?}", e);
+                            writer.record_failure(path, format!("Error naming file: {:?}", e));
                             return;
                         }
                     };
-
-                    let options: FileOptions<()> = FileOptions::default()
-                        .compression_method(compression_method).compression_level(Option::from(valid_level));
-
-                    let mut zip_guard = zip.lock().unwrap();
-                    match zip_guard.start_file(file_name, options) {
-                        Ok(_) => {
-                            let mut file = match File::open(&output_file_path) {
-                                Ok(file) => file,
-                                Err(e) => {
-                                    println!("Error opening file: {:?}", e);
-                                    return;
-                                }
-                            };
-                            if std::io::copy(&mut file, &mut *zip_guard).is_err() {
-                                println!("Error adding file to zip: {}", file_name);
-                            }
-                        },
-                        Err(e) => println!("Error starting file in zip: {}, {:?}", file_name, e),
+                    match writer.write_converted(&file_name, &output_file_path, path) {
+                        Ok(()) if converted => binary_entries.lock().unwrap().push(file_name),
+                        Ok(()) => {}
+                        Err(e) => writer.record_failure(path, format!("Error adding file to zip: {}, {:?}", file_name, e)),
                     }
                 }
                 Err(e) => {
-                    println!("Error converting file: {:?}", e);
+                    writer.record_failure(path, format!("Error converting file: {:?}", e));
                 }
             }
         } else {
-            println!("Skipping non-target file or directory: {:?}", path);
+            // Files of the types left out of `--convert-types` are stored as they are
+            let file_name = match archive_entry_name(folder_path, path, &root, settings) {
+                Ok(file_name) => file_name,
+                Err(e) => {
+                    writer.record_failure(path, format!("Error naming file: {:?}", e));
+                    return;
+                }
+            };
+            if let Err(e) = writer.write(&file_name, path) {
+                writer.record_failure(path, format!("Error adding file to zip: {}, {:?}", file_name, e));
+            }
         }
     });
+    let finished = writer.finish();
+    remove_converted_files(&output_folder)?;
+    let summary = finished?;
+
+    if settings.binary_naming == BinaryNaming::Sidecar {
+        let mut zip_guard = zip.lock().unwrap();
+        zip_guard.start_file(BINARY_SIDECAR_NAME, FileOptions::<()>::default())?;
+        zip_guard.write_all(sidecar_json(&binary_entries.into_inner().unwrap()).as_bytes())?;
+    }
 
     let duration = start.elapsed();
     println!("Time elapsed: {:?}", duration);
-    Ok(())
+    Ok(summary)
+}
+
+
+// The converted copies keep the permissions of their source, and read-only files can't be removed on Windows.
+// This is only used on the converted folder, which the tool writes itself, never on files the user owns.
+fn remove_converted_files(output_folder: &Path) -> io::Result<()> {
+    for entry in converted_files(output_folder)? {
+        let mut permissions = std::fs::metadata(&entry)?.permissions();
+        if permissions.readonly() {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                permissions.set_mode(permissions.mode() | 0o200);
+            }
+            #[cfg(not(unix))]
+            #[allow(clippy::permissions_set_readonly_false)]
+            permissions.set_readonly(false);
+            std::fs::set_permissions(&entry, permissions)?;
+        }
+    }
+    std::fs::remove_dir_all(output_folder)
 }
 
+fn converted_files(folder: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(converted_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
 
 pub fn get_file_type(extension: &str) -> FileType {
     match extension {
-        "png" | "jpg" | "gif" | "jpeg" => FileType::Image,
+        "png" | "jpg" | "gif" | "jpeg" | "avif" | "qoi" => FileType::Image,
         "mp4" | "avi" | "mov"=> FileType::Video,
         "mp3" | "wav" => FileType::Audio,
         "txt" => FileType::Text,
@@ -92,27 +201,134 @@ pub fn get_file_type(extension: &str) -> FileType {
     }
 }
 
-pub fn file_type_matches(path: &Path, file_type: &FileType) -> bool {
-    match file_type {
-        FileType::Image => path.extension().map_or(false, |ext| ext == "png" || ext == "jpg"),
-        FileType::Video => path.extension().map_or(false, |ext| ext == "mp4" || ext == "avi"),
-        FileType::Audio => path.extension().map_or(false, |ext| ext == "mp3" || ext == "wav"),
-        FileType::Text => path.extension().map_or(false, |ext| ext == "txt"),
-        FileType::Other => true, // Or implement specific logic for other file types
-    }
+fn is_unsupported(path: &Path) -> bool {
+    let extension = path.extension().and_then(std::ffi::OsStr::to_str).unwrap_or_default();
+    matches!(get_file_type(extension), FileType::Other)
+}
+
+// Without `--convert-types` every file goes through its converter
+fn converts(path: &Path, settings: &CompressionSettings) -> bool {
+    let extension = path.extension().and_then(std::ffi::OsStr::to_str).unwrap_or_default();
+    settings.convert_types.as_ref().is_none_or(|types| types.contains(&get_file_type(extension)))
 }
 
-pub fn convert_to_target_format(path: &Path, output_folder: &Path, _file_type: &FileType) -> io::Result<PathBuf> {
+pub fn convert_to_target_format(path: &Path, output_folder: &Path, line_ending: Option<LineEnding>) -> io::Result<PathBuf> {
     let extension = path.extension().and_then(std::ffi::OsStr::to_str).unwrap_or_default();
     let target_file_type = get_file_type(extension);
 
-    match target_file_type {
-        FileType::Image => image_to_binary_file(path, output_folder),
-        FileType::Text => text_to_binary_file(path, output_folder),
-        FileType::Video | FileType::Audio | FileType::Other => {
-            let output_path = output_folder.join(path.file_name().unwrap());
-            std::fs::copy(path, &output_path)?;
-            Ok(output_path)
-        }
+    let converter: &dyn BinaryConverter = match target_file_type {
+        FileType::Image => &ImageConverter,
+        FileType::Text => &TextConverter { line_ending },
+        FileType::Video | FileType::Audio | FileType::Other => &MediaConverter,
+    };
+    converter.to_binary(path, output_folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+    use crate::archive_output::{write_archive, OutputOptions};
+    use crate::test_support::{compress, compress_converted, entry_names, settings, write_files, TempDir};
+
+    #[test]
+    fn read_only_converted_files_are_removed() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("photo.png", b"png"), ("docs/notes.txt", b"notes")]);
+        // The converted copies keep the permissions of the read-only sources
+        let set_readonly = |readonly| for name in ["photo.png", "docs/notes.txt"] {
+            let mut permissions = std::fs::metadata(input.join(name)).unwrap().permissions();
+            permissions.set_readonly(readonly);
+            std::fs::set_permissions(input.join(name), permissions).unwrap();
+        };
+        set_readonly(true);
+        let output = dir.join("out.zip");
+        compress_converted(&input, &settings(&output));
+        set_readonly(false);
+
+        let mut names = entry_names(&output);
+        names.sort();
+        assert_eq!(names, ["docs/notes.txt.bin", "photo.png.bin"]);
+        assert!(!dir.join(format!("rust-zip-{}-converted", std::process::id())).exists());
+    }
+
+    #[test]
+    fn avif_and_qoi_files_are_converted_as_images() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("picture.avif", b"avif"), ("picture.qoi", b"qoif"), ("notes.txt", b"notes")]);
+        let output = dir.join("out.zip");
+        let settings = CompressionSettings { convert_types: Some(HashSet::from([FileType::Image])), ..settings(&output) };
+        compress_converted(&input, &settings);
+
+        let mut names = entry_names(&output);
+        names.sort();
+        assert_eq!(names, ["notes.txt", "picture.avif.bin", "picture.qoi.bin"]);
+    }
+
+    #[test]
+    fn unsupported_policy_decides_what_happens_to_a_dat_file() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("notes.txt", b"notes"), ("data.dat", b"\x00\x01")]);
+        let output = dir.join("out.zip");
+        let settings_with = |on_unsupported| CompressionSettings { on_unsupported, ..settings(&output) };
+
+        compress_converted(&dir.join("input"), &settings_with(UnsupportedPolicy::Copy));
+        let mut names = entry_names(&output);
+        names.sort();
+        assert_eq!(names, ["data.dat", "notes.txt.bin"]);
+
+        compress_converted(&dir.join("input"), &settings_with(UnsupportedPolicy::Skip));
+        assert_eq!(entry_names(&output), ["notes.txt.bin"]);
+
+        let options = OutputOptions { convert_to_binary: true, ..OutputOptions::default() };
+        let Err(e) = write_archive(&output, &output, &dir.join("input"), None, &settings_with(UnsupportedPolicy::Error), options) else {
+            panic!("an unsupported file was archived with the error policy");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("data.dat"));
+    }
+
+    #[test]
+    fn intermediates_are_written_under_the_temp_dir() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("notes.txt", b"notes"), ("more.txt", b"more")]);
+        std::fs::create_dir(dir.join("scratch")).unwrap();
+        // The callback looks into the temp dir while the converted files are being archived
+        let scratch = dir.join("scratch");
+        let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let seen_by_callback = seen.clone();
+        let settings = CompressionSettings {
+            temp_dir: scratch.clone(),
+            progress: Some(Box::new(move |_| {
+                let names = std::fs::read_dir(&scratch).unwrap().map(|entry| entry.unwrap().file_name()).collect::<Vec<_>>();
+                seen_by_callback.lock().unwrap().extend(names);
+            })),
+            ..settings(&dir.join("out.zip"))
+        };
+        compress_converted(&dir.join("input"), &settings);
+
+        let converted_folder = format!("rust-zip-{}-converted", std::process::id());
+        assert!(seen.lock().unwrap().iter().any(|name| *name == *converted_folder));
+        assert_eq!(std::fs::read_dir(dir.join("scratch")).unwrap().count(), 0);
+        assert!(!dir.join("input").join(&converted_folder).exists());
+    }
+
+    #[test]
+    fn only_the_listed_types_are_converted() {
+        let dir = TempDir::new();
+        let mut png = io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(4, 4).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        write_files(&dir.join("input"), &[("photo.png", png.get_ref()), ("clip.mp4", b"not really a video")]);
+        let output = dir.join("out.zip");
+        let settings = CompressionSettings { convert_types: Some(HashSet::from([FileType::Image])), ..settings(&output) };
+        compress(&dir.join("input"), &settings);
+
+        let mut names = entry_names(&output);
+        names.sort();
+        assert_eq!(names, ["clip.mp4", "photo.png.bin"]);
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("clip.mp4").unwrap()).unwrap(), "not really a video");
     }
 }
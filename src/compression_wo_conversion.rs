@@ -1,63 +1,154 @@
 use std::fs::File;
-use std::io;
+use std::io::{self, Read};
 use std::path::Path;
 use std::sync::Mutex;
-use std::path::PathBuf;
 use std::time::Instant;
 use rayon::prelude::*;
-use zip::{write::FileOptions, ZipWriter};
+use zip::ZipWriter;
 
-use crate::image_processing::image_to_binary_file;
-use crate::text_to_binary::text_to_binary_file;
-use crate::utils::get_compression_method;
+use crate::collector::{archive_entry_name, check_case_collisions, collect_files, entry_prefix, sort_largest_first};
+use crate::entry_writer::{CompressionSettings, EntryWriter, RunSummary};
 
 
 pub fn add_files_directly_to_zip(
     zip: &Mutex<ZipWriter<File>>,
     folder_path: &Path,
-    compression_algorithm: &str,
-    compression_level: i64,
-) -> io::Result<()> {
+    settings: &CompressionSettings,
+) -> io::Result<RunSummary> {
     let start = Instant::now();
-    let entries: Vec<_> = std::fs::read_dir(folder_path)?.filter_map(|e| e.ok()).collect();
-
-    entries.par_iter().for_each(|entry| {
-        let path = entry.path();
-        if path.is_file() {
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-
-            let (compression_method, valid_level) = match get_compression_method(compression_algorithm, compression_level) {
-                Ok((method, level)) => (method, level),
-                Err(e) => {
-                    println!("Error getting compression method: {:?}", e);
-                    return;
-                }
-            };
-
-            let options: FileOptions<()> = FileOptions::default()
-                .compression_method(compression_method).compression_level(Option::from(valid_level));
-
-            let mut zip_guard = zip.lock().unwrap();
-            match zip_guard.start_file(file_name, options) {
-                Ok(_) => {
-                    let mut file = match File::open(&path) {
-                        Ok(file) => file,
-                        Err(e) => {
-                            println!("Error opening file: {:?}", e);
-                            return;
-                        }
-                    };
-                    if std::io::copy(&mut file, &mut *zip_guard).is_err() {
-                        println!("Error adding file to zip: {}", file_name);
-                    }
-                },
-                Err(e) => println!("Error starting file in zip: {}, {:?}", file_name, e),
+    let mut files = collect_files(folder_path, settings)?;
+    let root = entry_prefix(folder_path, settings)?;
+    if settings.largest_first {
+        sort_largest_first(&mut files);
+    }
+    // The names are checked before anything is written, so a name without the `--strip-prefix` fails the run early
+    if settings.case_fold_entries || settings.strip_prefix.is_some() {
+        let names = files.iter().map(|path| archive_entry_name(folder_path, path, &root, settings)).collect::<io::Result<Vec<String>>>()?;
+        if settings.case_fold_entries {
+            check_case_collisions(names.iter().map(String::as_str))?;
+        }
+    }
+
+    let writer = EntryWriter::new(zip, settings, files.len());
+    files.par_iter().for_each(|path| {
+        if writer.limit_reached() {
+            return;
+        }
+        let file_name = match archive_entry_name(folder_path, path, &root, settings) {
+            Ok(file_name) => file_name,
+            Err(e) => {
+                writer.record_failure(path, format!("Error naming file: {:?}", e));
+                return;
             }
-        } else {
-            println!("Skipping non-file or directory: {:?}", path);
+        };
+        if let Err(e) = writer.write(&file_name, path) {
+            writer.record_failure(path, format!("Error adding file to zip: {}, {:?}", file_name, e));
         }
     });
+    let summary = writer.finish()?;
+
     let duration = start.elapsed();
     println!("Time elapsed: {:?}", duration);
-    Ok(())
-}
\ No newline at end of file
+    Ok(summary)
+}
+
+/// Compresses standard input into a single entry, for `<command> | rust-zip compression - out.zip`.
+/// The entry name picks the `--method-for` rule like a file name would.
+pub fn add_stdin_to_zip(
+    zip: &Mutex<ZipWriter<File>>,
+    entry_name: &str,
+    settings: &CompressionSettings,
+) -> io::Result<RunSummary> {
+    println!("Reading {} from stdin", entry_name);
+    add_reader_to_zip(zip, entry_name, &mut io::stdin().lock(), settings)
+}
+
+/// Compresses everything read from the reader into a single entry, standard input for `add_stdin_to_zip`
+pub fn add_reader_to_zip(
+    zip: &Mutex<ZipWriter<File>>,
+    entry_name: &str,
+    reader: &mut impl Read,
+    settings: &CompressionSettings,
+) -> io::Result<RunSummary> {
+    let start = Instant::now();
+    let writer = EntryWriter::new(zip, settings, 1);
+    writer.write_stream(entry_name, Path::new(entry_name), reader)?;
+    let summary = writer.finish()?;
+
+    println!("Time elapsed: {:?}", start.elapsed());
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+    use crate::collector::entry_name;
+    use crate::test_support::{compress, entry_names, random_bytes, settings, write_files, TempDir};
+
+    // With a single thread the files are compressed one after the other, in the order they are handed out
+    fn compress_on_one_thread(folder: &Path, settings: &CompressionSettings) {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        pool.install(|| compress(folder, settings));
+    }
+
+    #[test]
+    fn piped_bytes_become_a_single_entry() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("out.zip");
+        let piped = b"line one\nline two\n".repeat(1000);
+        let zip = Mutex::new(ZipWriter::new(File::create(&zip_path).unwrap()));
+        add_reader_to_zip(&zip, "data.txt", &mut &piped[..], &settings(&zip_path)).unwrap();
+        zip.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(entry_names(&zip_path), ["data.txt"]);
+        let mut archive = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let mut extracted = Vec::new();
+        archive.by_name("data.txt").unwrap().read_to_end(&mut extracted).unwrap();
+        assert_eq!(extracted, piped);
+    }
+
+    #[test]
+    fn files_are_handed_out_largest_first_unless_no_lpt() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", &[b'a'; 10]), ("b.txt", &[b'b'; 5000]), ("c.txt", &[b'c'; 100]), ("d/e.txt", &[b'e'; 20000])]);
+
+        let lpt = CompressionSettings { largest_first: true, ..settings(&dir.join("lpt.zip")) };
+        compress_on_one_thread(&input, &lpt);
+        assert_eq!(entry_names(&lpt.output_zip), ["d/e.txt", "b.txt", "c.txt", "a.txt"]);
+
+        let no_lpt = CompressionSettings { largest_first: false, ..settings(&dir.join("no-lpt.zip")) };
+        compress_on_one_thread(&input, &no_lpt);
+        let collected: Vec<String> = collect_files(&input, &no_lpt).unwrap().iter().map(|path| entry_name(&input, path).unwrap()).collect();
+        assert_eq!(entry_names(&no_lpt.output_zip), collected);
+    }
+
+    // A few large files among many small ones, the large ones alone take about as long as all the small ones
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture lpt`"]
+    fn lpt_shortens_the_makespan_of_a_skewed_corpus() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        let large = random_bytes(4 * 1024 * 1024, 1);
+        let small = random_bytes(16 * 1024, 2);
+        let names: Vec<String> = (0..4).map(|i| format!("large-{}.bin", i)).chain((0..1000).map(|i| format!("small-{:04}.bin", i))).collect();
+        let files: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), if name.starts_with("large") { &large[..] } else { &small[..] })).collect();
+        write_files(&input, &files);
+
+        let threads = std::thread::available_parallelism().map_or(4, |threads| threads.get().max(4));
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let makespan = |largest_first: bool| -> Duration {
+            let settings = CompressionSettings { compression_algorithm: "Bzip2".to_owned(), compression_level: 9, largest_first, ..settings(&dir.join("out.zip")) };
+            (0..3).map(|_| {
+                let start = Instant::now();
+                pool.install(|| compress(&input, &settings));
+                start.elapsed()
+            }).min().unwrap()
+        };
+        let (collection_order, largest_first) = (makespan(false), makespan(true));
+        println!("{} threads on {} cores: {:?} in collection order, {:?} largest first, {:.2}x",
+            threads, std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            collection_order, largest_first, collection_order.as_secs_f64() / largest_first.as_secs_f64());
+    }
+}
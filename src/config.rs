@@ -0,0 +1,82 @@
+use std::io;
+use std::path::Path;
+use serde::Deserialize;
+
+/// Compression settings read from a TOML file with `--config`. Every key is optional,
+/// flags given on the command line take precedence over the values here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub compression_algorithm: Option<String>,
+    pub compression_level: Option<i64>,
+    pub recursive: Option<bool>,
+    /// Gitignore-style patterns, when set only matching files are archived
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Gitignore-style patterns of the paths to leave out
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    pub threads: Option<usize>,
+    /// Directory the entries are stored under inside the archive
+    pub prefix: Option<String>,
+}
+
+pub fn load_config(path: &Path) -> io::Result<Config> {
+    let text = std::fs::read_to_string(path)?;
+    toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid config {:?}: {}", path, e)))
+}
+
+impl Config {
+    /// The settings given as flags, falling back to the config file's values for the ones left out
+    pub fn overridden_by(self, flags: Config) -> Config {
+        Config {
+            compression_algorithm: flags.compression_algorithm.or(self.compression_algorithm),
+            compression_level: flags.compression_level.or(self.compression_level),
+            recursive: flags.recursive.or(self.recursive),
+            include: if flags.include.is_empty() { self.include } else { flags.include },
+            exclude: if flags.exclude.is_empty() { self.exclude } else { flags.exclude },
+            threads: flags.threads.or(self.threads),
+            prefix: flags.prefix.or(self.prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{write_files, TempDir};
+
+    const CONFIG: &[u8] = b"compression_algorithm = \"Bzip2\"\ncompression_level = 9\nrecursive = true\ninclude = [\"src\", \"docs/\"]\nexclude = [\"*.log\"]\nthreads = 2\nprefix = \"backup\"\n";
+
+    #[test]
+    fn config_values_are_used_when_flags_are_absent() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("rustzip.toml", CONFIG)]);
+        let config = load_config(&dir.join("rustzip.toml")).unwrap().overridden_by(Config::default());
+        assert_eq!(config.compression_algorithm.as_deref(), Some("Bzip2"));
+        assert_eq!(config.compression_level, Some(9));
+        assert_eq!(config.recursive, Some(true));
+        assert_eq!(config.include, ["src", "docs/"]);
+        assert_eq!(config.exclude, ["*.log"]);
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.prefix.as_deref(), Some("backup"));
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_config() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("rustzip.toml", CONFIG)]);
+        let flags = Config { compression_level: Some(1), prefix: Some("flags".to_owned()), ..Config::default() };
+        let config = load_config(&dir.join("rustzip.toml")).unwrap().overridden_by(flags);
+        assert_eq!(config.compression_level, Some(1));
+        assert_eq!(config.prefix.as_deref(), Some("flags"));
+        assert_eq!(config.compression_algorithm.as_deref(), Some("Bzip2"));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("rustzip.toml", b"level = 3\n")]);
+        assert_eq!(load_config(&dir.join("rustzip.toml")).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use rayon::prelude::*;
+
+use crate::collector::{collect_files, entry_name, entry_prefix};
+use crate::entry_writer::CompressionSettings;
+use crate::color;
+
+/// Copies the files the compression would archive into `output_folder`, under the same names
+/// their entries would get, to check the collection, filtering and prefix settings without a zip
+pub fn copy_files(folder_path: &Path, output_folder: &Path, settings: &CompressionSettings) -> io::Result<usize> {
+    let start = Instant::now();
+    std::fs::create_dir_all(output_folder)?;
+
+    // An output folder inside the input folder must not be copied into itself
+    let canonical_output = output_folder.canonicalize()?;
+    let output_inside_input = canonical_output.starts_with(folder_path.canonicalize()?);
+    let files: Vec<_> = collect_files(folder_path, settings)?
+        .into_iter()
+        .filter(|path| !output_inside_input || !path.canonicalize().is_ok_and(|path| path.starts_with(&canonical_output)))
+        .collect();
+    let root = entry_prefix(folder_path, settings)?;
+
+    let failed_files = AtomicUsize::new(0);
+    files.par_iter().for_each(|path| {
+        let result = entry_name(folder_path, path).and_then(|name| {
+            let destination = output_folder.join(format!("{}{}", root, name));
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(path, &destination).map(|_| destination)
+        });
+        match result {
+            Ok(destination) => println!("Copied {:?} to {:?}", path, destination),
+            Err(e) => {
+                println!("{} copying file: {:?}, {:?}", color::error(), path, e);
+                failed_files.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+
+    println!("Time elapsed: {:?}", start.elapsed());
+    Ok(failed_files.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compress, entry_names, settings, write_files, TempDir};
+
+    #[test]
+    fn copied_tree_matches_the_archived_entries() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("nested/b.txt", b"b"), ("nested/debug.log", b"log")]);
+        let settings = CompressionSettings {
+            exclude_patterns: vec!["*.log".to_owned()],
+            prefix: Some("copy".to_owned()),
+            ..settings(&dir.join("out.zip"))
+        };
+        assert_eq!(copy_files(&dir.join("input"), &dir.join("copied"), &settings).unwrap(), 0);
+        assert_eq!(std::fs::read(dir.join("copied/copy/nested/b.txt")).unwrap(), b"b");
+
+        compress(&dir.join("input"), &settings);
+        let mut names = entry_names(&dir.join("out.zip"));
+        names.sort();
+        let mut copied: Vec<String> = collect_files(&dir.join("copied"), &settings).unwrap().iter()
+            .map(|path| entry_name(&dir.join("copied"), path).unwrap())
+            .collect();
+        copied.sort();
+        assert_eq!(copied, names);
+        assert_eq!(names, ["copy/a.txt", "copy/nested/b.txt"]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::path::Path;
+
+// splitmix64, small and stable so the same seed always produces the same corpus
+struct SeededRng(u64);
+
+impl SeededRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+const FILLER: &[u8] = b"the quick brown fox jumps over the lazy dog ";
+
+/// Writes `files` pseudo-random files into `dir`. Sizes are spread between half and one and a half
+/// times `avg_size`, and `entropy` (0.0 to 1.0) is the share of random bytes, the rest being
+/// repeated text, so 0.0 compresses very well and 1.0 not at all.
+pub fn generate_corpus(dir: &Path, files: usize, avg_size: u64, entropy: f64, seed: u64) -> io::Result<()> {
+    if !(0.0..=1.0).contains(&entropy) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Entropy must be between 0.0 and 1.0"));
+    }
+    std::fs::create_dir_all(dir)?;
+    let mut rng = SeededRng(seed);
+
+    for i in 0..files {
+        let size = if avg_size == 0 { 0 } else { avg_size / 2 + rng.next_u64() % (avg_size + 1) };
+        let mut data = Vec::with_capacity(size as usize);
+        for position in 0..size as usize {
+            if rng.next_f64() < entropy {
+                data.push(rng.next_u64() as u8);
+            } else {
+                data.push(FILLER[position % FILLER.len()]);
+            }
+        }
+        std::fs::write(dir.join(format!("file_{:05}.dat", i)), &data)?;
+    }
+
+    println!("Generated {} files in {:?}", files, dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn file_sizes(dir: &Path) -> Vec<(String, u64)> {
+        let mut sizes: Vec<(String, u64)> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.metadata().unwrap().len()))
+            .collect();
+        sizes.sort();
+        sizes
+    }
+
+    #[test]
+    fn corpus_has_the_requested_files_and_sizes() {
+        let dir = TempDir::new();
+        generate_corpus(&dir.join("corpus"), 50, 4096, 0.5, 7).unwrap();
+        let sizes = file_sizes(&dir.join("corpus"));
+        assert_eq!(sizes.len(), 50);
+        assert!(sizes.iter().all(|(_, size)| (2048..=6144).contains(size)));
+
+        // The same seed gives the same corpus
+        generate_corpus(&dir.join("again"), 50, 4096, 0.5, 7).unwrap();
+        assert_eq!(file_sizes(&dir.join("again")), sizes);
+    }
+}
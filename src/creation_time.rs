@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Map, Value};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::color;
+
+/// Entry holding the creation times of the archived files, written with `--preserve-ctime`
+pub const CREATION_TIMES_NAME: &str = ".rust-zip-created.json";
+
+// Filesystems without a creation time, and Linux systems without statx, archive their files without one
+pub fn read_creation_time(path: &Path) -> Option<SystemTime> {
+    match std::fs::metadata(path).and_then(|metadata| metadata.created()) {
+        Ok(created) => Some(created),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => None,
+        Err(e) => {
+            eprintln!("{} could not read the creation time of {:?}: {}", color::warning(), path, e);
+            None
+        }
+    }
+}
+
+// Times before 1970 can't be written as a duration since the epoch, those files are left out
+pub fn creation_times_json(files: &[(String, SystemTime)]) -> String {
+    let mut entries = Map::new();
+    for (name, created) in files {
+        if let Ok(since_epoch) = created.duration_since(UNIX_EPOCH) {
+            entries.insert(name.clone(), json!({ "secs": since_epoch.as_secs(), "nanos": since_epoch.subsec_nanos() }));
+        }
+    }
+    json!({ "files": entries }).to_string()
+}
+
+/// Gives the extracted files the creation times stored in the archive. Only Windows and macOS let programs set
+/// the creation time, elsewhere a warning is printed and the files keep the time they were extracted at.
+/// Does nothing for archives written without `--preserve-ctime`.
+pub fn restore_creation_times(archive: &mut ZipArchive<File>, output_folder: &Path) -> io::Result<()> {
+    let mut entry = match archive.by_name(CREATION_TIMES_NAME) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    let stored: Value = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid creation times entry: {}", e)))?;
+
+    let files = stored["files"].as_object().cloned().unwrap_or_default();
+    for (name, created) in files {
+        if !Path::new(&name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in creation times entry: {}", name)));
+        }
+        let (Some(secs), Some(nanos)) = (created["secs"].as_u64(), created["nanos"].as_u64()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid creation time of {}", name)));
+        };
+        let path = output_folder.join(&name);
+        if !path.is_file() {
+            continue;
+        }
+        match set_creation_time(&path, UNIX_EPOCH + Duration::new(secs, nanos as u32)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                println!("{} creation times can only be restored on Windows and macOS, skipping them", color::warning());
+                return Ok(());
+            }
+            Err(e) => eprintln!("{} could not set the creation time of {:?}: {}", color::warning(), path, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_creation_time(path: &Path, created: SystemTime) -> io::Result<()> {
+    use std::os::windows::fs::FileTimesExt;
+    File::options().write(true).open(path)?.set_times(std::fs::FileTimes::new().set_created(created))
+}
+
+#[cfg(target_vendor = "apple")]
+fn set_creation_time(path: &Path, created: SystemTime) -> io::Result<()> {
+    use std::os::darwin::fs::FileTimesExt;
+    File::options().write(true).open(path)?.set_times(std::fs::FileTimes::new().set_created(created))
+}
+
+#[cfg(not(any(windows, target_vendor = "apple")))]
+fn set_creation_time(_path: &Path, _created: SystemTime) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompression_wo_conversion::{decompress_files, ExtractOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, extract_options, settings, write_files, TempDir};
+
+    #[cfg(any(windows, target_vendor = "apple"))]
+    #[tokio::test]
+    async fn creation_times_survive_the_round_trip() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a"), ("nested/b.txt", b"b")]);
+        // A time long before the test ran, so the extracted files can't have it by chance
+        let created = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for name in ["a.txt", "nested/b.txt"] {
+            set_creation_time(&input.join(name), created).unwrap();
+        }
+        let settings = CompressionSettings { preserve_ctime: true, ..settings(&dir.join("out.zip")) };
+        compress(&input, &settings);
+
+        let options = ExtractOptions { preserve_ctime: true, ..extract_options() };
+        decompress_files(&settings.output_zip, &dir.join("output"), &options).await.unwrap();
+        for name in ["a.txt", "nested/b.txt"] {
+            assert_eq!(std::fs::metadata(dir.join("output").join(name)).unwrap().created().unwrap(), created, "{}", name);
+        }
+    }
+
+    // Elsewhere the files are still extracted, only their creation times are left as they are
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    #[tokio::test]
+    async fn creation_times_are_skipped_where_they_cant_be_set() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a")]);
+        let settings = CompressionSettings { preserve_ctime: true, ..settings(&dir.join("out.zip")) };
+        compress(&input, &settings);
+
+        let options = ExtractOptions { preserve_ctime: true, ..extract_options() };
+        decompress_files(&settings.output_zip, &dir.join("output"), &options).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/a.txt")).unwrap(), b"a");
+        assert_eq!(set_creation_time(&dir.join("output/a.txt"), UNIX_EPOCH).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
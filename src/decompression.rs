@@ -1,36 +1,37 @@
 use std::fs::File;
 use std::io::{self, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use tokio::fs as async_fs;
-use tokio::fs::{self, remove_file, read_dir};
 use tokio::task;
 use futures::future;
 use zip::ZipArchive;
 use std::ffi::OsStr;
-use std::path::PathBuf;
-use tokio::time::{sleep, Duration};
-use std::error::Error;
-
-use crate::image_processing::{convert_binary_to_image, determine_image_format};
-use crate::text_to_binary::convert_binary_to_text;
-
-#[derive(Debug)]
-pub enum FileType {
-    Image,
-    Video,
-    Audio,
-    Text,
-    Other,
-}
 
-pub async fn decompress_and_convert_to_files(zip_path: &Path, output_folder: &Path) -> io::Result<()> {
+use crate::binary_naming::{read_sidecar, BINARY_SIDECAR_NAME};
+use crate::brotli_entries::{restore_brotli_files, BROTLI_MANIFEST_NAME};
+use crate::compression::get_file_type;
+use crate::cancel::{cancelled_error, is_cancelled};
+use crate::dedup::{restore_duplicates, DEDUP_MANIFEST_NAME};
+use crate::chunking::{reassemble_chunked_files, CHUNK_MANIFEST_NAME};
+use crate::decompression_wo_conversion::{check_entry_filters, entry_by_index, is_up_to_date, resolve_duplicate, ExtractOptions, ExtractedPaths};
+use crate::progress::Progress;
+use crate::sparse::{restore_sparse_files, SPARSE_MANIFEST_NAME};
+use crate::text_to_binary::warn_if_invalid_json;
+use crate::utils::{check_entry_name, create_directory_entry};
+use crate::ownership::{restore_ownership, OWNERSHIP_NAME};
+use crate::creation_time::{restore_creation_times, CREATION_TIMES_NAME};
+use crate::xattrs::{restore_xattrs, XATTRS_NAME};
+use crate::color;
+
+pub async fn decompress_and_convert_to_files(zip_path: &Path, output_folder: &Path, options: &ExtractOptions) -> io::Result<()> {
     println!("Starting decompression and conversion process...");
     let overall_start = Instant::now();
     async_fs::create_dir_all(output_folder).await?;
 
     let file = File::open(zip_path)?;
     let mut archive = ZipArchive::new(file)?;
+    check_entry_filters(&archive, options)?;
 
     let archive_len = archive.len();
     println!("Archive contains {} entries", archive_len);
@@ -40,139 +41,316 @@ pub async fn decompress_and_convert_to_files(zip_path: &Path, output_folder: &Pa
         return Ok(());
     }
 
-    let mut tasks = vec![];
+    // With a sidecar the converted entries are known by name and every entry already has its original name,
+    // so nothing is converted based on file names and the bytes are restored as they are
+    let binary_entries = read_sidecar(&mut archive)?;
+    let progress = Progress::new(archive_len, options.progress_interval, options.progress.as_ref());
+    let mut tasks: Vec<Option<task::JoinHandle<()>>> = vec![];
+    let mut extracted = ExtractedPaths::new();
 
     for i in 0..archive_len {
+        if is_cancelled(&options.cancel) {
+            // Let the writes already started finish, so no file is left half written
+            future::join_all(tasks.into_iter().flatten()).await;
+            return Err(cancelled_error());
+        }
         let start = Instant::now();
-        let file_result = archive.by_index(i);
+        let file_result = entry_by_index(&mut archive, i, options.password.as_deref());
         if file_result.is_err() {
-            eprintln!("Error accessing file at index {}: {:?}", i, file_result.err().unwrap());
+            eprintln!("{} accessing file at index {}: {:?}", color::error(), i, file_result.err().unwrap());
             continue;
         }
         let mut file = file_result.unwrap();
-        let outpath = match file.enclosed_name() {
+        if file.name() == BINARY_SIDECAR_NAME || file.name() == XATTRS_NAME || file.name() == OWNERSHIP_NAME || file.name() == CREATION_TIMES_NAME {
+            continue;
+        }
+        check_entry_name(file.name(), options.safe)?;
+        if file.is_dir() {
+            create_directory_entry(output_folder, file.name(), options.enclosed_name(&file))?;
+            progress.record(file.name(), 0);
+            continue;
+        }
+        if !options.size_selected(file.size()) {
+            println!("Skipping file outside the size range at index {}: {}", i, file.name());
+            progress.record(file.name(), file.size());
+            continue;
+        }
+        let restore_as_is = binary_entries.is_some();
+        let mut binary_converted = binary_entries.as_ref().is_some_and(|entries| entries.contains(file.name()));
+        let mut outpath = match options.enclosed_name(&file) {
             Some(path) => output_folder.join(path),
             None => {
                 println!("Skipping file at index {}: invalid file name", i);
                 continue;
             }
         };
+        // With a sidecar the types left out of `--convert-only` get back the `.bin` name they would have had
+        if binary_converted && !converts_back(&outpath, options) {
+            binary_converted = false;
+            outpath = binary_path_of(&outpath);
+        }
 
-        println!("Processing file at index {}: {:?}", i, outpath.file_name().unwrap());
+        // Converted entries named `<name>.bin` hold the original bytes, so they are written straight under
+        // the original name, without an intermediate `.bin` file being written, read back and removed
+        let original_path = if restore_as_is { None } else { original_path_of_binary(&outpath) }
+            .filter(|original_path| converts_back(original_path, options));
+        let converted_from_bin = original_path.is_some();
+        let outpath = original_path.unwrap_or(outpath);
+        let outpath = resolve_duplicate(file.name(), outpath, &mut extracted, &mut tasks, options).await?;
 
-        let mut buffer = Vec::new();
-        if let Err(e) = file.read_to_end(&mut buffer) {
-            eprintln!("Error reading file at index {}: {:?}", i, e);
+        if options.update && is_up_to_date(&file, &outpath) {
+            println!("Skipping file not older than the existing one at index {}: {:?}", i, outpath.file_name().unwrap());
+            tasks.push(None);
+            progress.record(file.name(), file.size());
             continue;
         }
 
-        let output_folder = output_folder.to_path_buf();
+        println!("Processing file at index {}: {:?}", i, outpath.file_name().unwrap());
+        // Archives don't always have entries for the directories of their files
+        if let Some(parent) = outpath.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
-        tasks.push(task::spawn(async move {
-            if let Err(e) = async_fs::write(&outpath, &buffer).await {
-                eprintln!("Error writing file {:?}: {}", outpath, e);
-                return;
+        // Large entries are streamed straight to their output file instead of being held in memory,
+        // the conversion below then works on that file like on a buffered one
+        let buffer = if file.size() > options.buffer_to_memory_threshold {
+            match File::create(&outpath).and_then(|mut output| io::copy(&mut file, &mut output)) {
+                Ok(written) => progress.record(file.name(), written),
+                Err(e) => {
+                    eprintln!("{} extracting file at index {}: {:?}", color::error(), i, e);
+                    tasks.push(None);
+                    continue;
+                }
             }
+            None
+        } else {
+            let mut buffer = Vec::new();
+            if let Err(e) = file.read_to_end(&mut buffer) {
+                eprintln!("{} reading file at index {}: {:?}", color::error(), i, e);
+                tasks.push(None);
+                continue;
+            }
+            progress.record(file.name(), buffer.len() as u64);
+            Some(buffer)
+        };
 
-            let extension = outpath.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        let validate_json = options.validate_json;
 
-            match extension {
-                "bin" => {
-                    // Handle binary files
+        tasks.push(Some(task::spawn(async move {
+            if let Some(buffer) = &buffer {
+                if let Err(e) = async_fs::write(&outpath, buffer).await {
+                    eprintln!("{} writing file {:?}: {}", color::error(), outpath, e);
+                    return;
                 }
-                "txt" | "json" => {
-                    if let Err(e) = convert_and_cleanup_json_file(&outpath, &output_folder).await {
-                        eprintln!("Error converting/cleaning up file {:?}: {}", outpath, e);
+            }
+            let extension = outpath.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_owned();
+            if validate_json && extension == "json" {
+                let bytes = match buffer {
+                    Some(buffer) => Ok(buffer),
+                    None => async_fs::read(&outpath).await,
+                };
+                match bytes {
+                    Ok(bytes) => {
+                        warn_if_invalid_json(&outpath, &bytes);
                     }
+                    Err(e) => eprintln!("{} reading file {:?}: {}", color::error(), outpath, e),
                 }
+            }
+            if converted_from_bin {
+                println!("Restored binary-converted file {:?}", outpath.file_name().unwrap());
+                return;
+            }
+
+            // The manifests are read after extraction to reassemble split files and duplicates, not converted
+            if outpath.file_name().and_then(OsStr::to_str).is_some_and(|name| name == CHUNK_MANIFEST_NAME || name == DEDUP_MANIFEST_NAME || name == SPARSE_MANIFEST_NAME || name == BROTLI_MANIFEST_NAME) {
+                return;
+            }
+            if restore_as_is {
+                if binary_converted {
+                    println!("Restored binary-converted file {:?}", outpath.file_name().unwrap());
+                }
+                return;
+            }
+
+            match extension.as_str() {
+                // Text files, and `.bin` files without an original extension, are stored as they are,
+                // so they are already restored
+                "txt" | "json" | "bin" => {}
                 _ => println!("Unsupported file extension: {:?}", extension),
             }
 
             let duration = start.elapsed();
             println!("File processed in {} ms", duration.as_millis());
-        }));
+        })));
     }
 
-    future::join_all(tasks).await;
+    future::join_all(tasks.into_iter().flatten()).await;
+    reassemble_chunked_files(output_folder)?;
+    restore_sparse_files(output_folder)?;
+    restore_brotli_files(output_folder)?;
+    restore_duplicates(output_folder)?;
+    if options.xattrs {
+        restore_xattrs(&mut archive, output_folder)?;
+    }
+    if options.preserve_owner {
+        restore_ownership(&mut archive, output_folder)?;
+    }
+    if options.preserve_ctime {
+        restore_creation_times(&mut archive, output_folder)?;
+    }
+    progress.finish();
     let overall_duration = overall_start.elapsed();
     println!("Decompression and conversion process completed in {} ms", overall_duration.as_millis());
-    delete_remaining_bin_files(output_folder).await?;
-    println!("Removed remaining binary files");
     Ok(())
 }
 
-async fn delete_remaining_bin_files(output_folder:&Path) -> io::Result<()> {
-    let binary_files_folder = output_folder.join("binary_files");
-    if binary_files_folder.exists(){
-        let mut entries = read_dir(binary_files_folder.clone()).await?;
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry.path();
-            if path.is_file() {
-                remove_file(path).await?;
-            }
-        }
-        fs::remove_dir(binary_files_folder).await?;
-    }
-    Ok(())
+// The type is read from the extension the same way compression picks the types of `--convert-types`
+fn converts_back(original_path: &Path, options: &ExtractOptions) -> bool {
+    let extension = original_path.extension().and_then(OsStr::to_str).unwrap_or_default();
+    options.convert_only.as_ref().is_none_or(|types| types.contains(&get_file_type(extension)))
+}
+
+fn binary_path_of(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".bin");
+    path.with_file_name(file_name)
 }
 
-async fn convert_and_cleanup_json_file(file_path: &Path, output_folder: &PathBuf) -> Result<(), Box<dyn Error>> {
-    let conversion_result = convert_binary_to_text(file_path, output_folder).await;
-    if let Err(e) = conversion_result {
-        eprintln!("Error converting file {:?}: {}", file_path, e);
-        // Implement retry logic for conversion if necessary, similar to file removal
+// `photo.png.bin` was converted from `photo.png`, while a plain `data.bin` is an original file
+fn original_path_of_binary(path: &Path) -> Option<PathBuf> {
+    if path.extension() != Some(OsStr::new("bin")) {
+        return None;
     }
+    let stem = path.file_stem()?;
+    Path::new(stem).extension()?;
+    Some(path.with_file_name(stem))
+}
 
-    let mut attempts = 0;
-    let max_attempts = 5;
-    let mut delay = 100; // Starting delay in milliseconds
-    while attempts < max_attempts {
-        match remove_file(file_path).await {
-            Ok(_) => {
-                println!("Successfully removed file: {:?}", file_path);
-                return Ok(());
-            },
-            Err(e) if e.kind() == io::ErrorKind::Other && e.raw_os_error() == Some(1224) => {
-                eprintln!("Error removing file {:?}: {}. Retrying after {}ms...", file_path, e, delay);
-                sleep(Duration::from_millis(delay)).await;
-                attempts += 1;
-                delay *= 2; // Exponential backoff
-            },
-            Err(e) => {
-                eprintln!("Failed to remove file {:?}: {}", file_path, e);
-                attempts = max_attempts; // Ensure exit from loop
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+    use crate::compression::FileType;
+    use crate::decompression_wo_conversion::DuplicatePolicy;
+    use crate::test_support::{compress_converted, extract_options, settings, write_files, write_zip, TempDir};
+
+    // Every file and directory under the folder, relative to it
+    fn tree(folder: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(folder).unwrap() {
+            let path = entry.unwrap().path();
+            let relative = path.strip_prefix(folder).unwrap().to_path_buf();
+            if path.is_dir() {
+                paths.extend(tree(&path).into_iter().map(|inner| relative.join(inner)));
             }
+            paths.push(relative);
         }
+        paths
     }
 
-    Err(Box::new(io::Error::new(io::ErrorKind::Other, "Failed to remove file after multiple attempts")))
-}
+    #[tokio::test]
+    async fn bom_prefixed_json_round_trips_through_the_conversion() {
+        let dir = TempDir::new();
+        let bom_json = b"\xEF\xBB\xBF{\"name\": \"value\"}\n";
+        write_files(&dir.join("input"), &[("data.json", bom_json)]);
+        let zip_path = dir.join("converted.zip");
+        compress_converted(&dir.join("input"), &settings(&zip_path));
 
-fn determine_file_type(path: &Path) -> FileType {
-    let extension = path.extension().and_then(OsStr::to_str);
-    let mut file_type = FileType::Other;
-
-    if extension == Some("bin") {
-        if let Some(stem) = path.file_stem().and_then(OsStr::to_str) {
-            if stem.ends_with(".txt") {
-                file_type = FileType::Text;
-            } else if stem.ends_with(".mp4") || stem.ends_with(".avi") || stem.ends_with(".mov") {
-                file_type = FileType::Video;
-            } else if stem.ends_with(".mp3") || stem.ends_with(".wav") {
-                file_type = FileType::Audio;
-            } else if stem.ends_with(".png") || stem.ends_with(".jpg") || stem.ends_with(".jpeg") {
-                file_type = FileType::Image;
-            }
-        }
-    } else {
-        match extension {
-            Some("txt") | Some("json") => file_type = FileType::Text,
-            Some("mp4") | Some("avi") | Some("mov") => file_type = FileType::Video,
-            Some("mp3") | Some("wav") => file_type = FileType::Audio,
-            Some("png") | Some("jpg") | Some("jpeg") => file_type = FileType::Image,
-            _ => file_type = FileType::Other,
-        }
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &extract_options()).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/data.json")).unwrap(), bom_json);
+    }
+
+    #[tokio::test]
+    async fn entries_above_and_below_the_buffer_threshold_are_restored() {
+        let dir = TempDir::new();
+        let large = "a line of the large file\n".repeat(4000);
+        write_files(&dir.join("input"), &[("large.txt", large.as_bytes()), ("small.txt", b"small"), ("small.dat", b"other")]);
+        let zip_path = dir.join("converted.zip");
+        compress_converted(&dir.join("input"), &settings(&zip_path));
+
+        let options = ExtractOptions { buffer_to_memory_threshold: 1024, ..extract_options() };
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/large.txt")).unwrap(), large.as_bytes());
+        assert_eq!(std::fs::read(dir.join("output/small.txt")).unwrap(), b"small");
+        assert_eq!(std::fs::read(dir.join("output/small.dat")).unwrap(), b"other");
     }
 
-    file_type
+    #[tokio::test]
+    async fn conversion_leaves_no_intermediate_files() {
+        let dir = TempDir::new();
+        let large = "a line of the large file\n".repeat(4000);
+        write_files(&dir.join("input"), &[("large.txt", large.as_bytes()), ("nested/small.txt", b"small")]);
+        let zip_path = dir.join("converted.zip");
+        compress_converted(&dir.join("input"), &settings(&zip_path));
+
+        // The large entry goes through a file on disk, the small one is converted from memory
+        let options = ExtractOptions { buffer_to_memory_threshold: 1024, ..extract_options() };
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+        let mut paths = tree(&dir.join("output"));
+        paths.sort();
+        assert_eq!(paths, [Path::new("large.txt"), Path::new("nested"), Path::new("nested/small.txt")]);
+    }
+
+    #[tokio::test]
+    async fn round_trip_leaves_no_bin_files() {
+        let dir = TempDir::new();
+        let large = "a line of the large file\n".repeat(4000);
+        write_files(&dir.join("input"), &[("notes.txt", b"notes"), ("data.json", b"{}"), ("nested/large.txt", large.as_bytes())]);
+        let zip_path = dir.join("converted.zip");
+        compress_converted(&dir.join("input"), &settings(&zip_path));
+        let options = ExtractOptions { buffer_to_memory_threshold: 1024, ..extract_options() };
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+
+        // Neither the input, the temp dir of the run nor the output keeps an intermediate
+        let bin_files: Vec<PathBuf> = tree(dir.path()).into_iter().filter(|path| path.extension() == Some(OsStr::new("bin"))).collect();
+        assert!(bin_files.is_empty(), "{:?}", bin_files);
+        assert_eq!(std::fs::read(dir.join("output/nested/large.txt")).unwrap(), large.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn only_text_is_converted_back() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("photo.png", b"\x89PNG not decoded"), ("notes.txt", b"notes")]);
+        let zip_path = dir.join("converted.zip");
+        compress_converted(&dir.join("input"), &settings(&zip_path));
+
+        let options = ExtractOptions { convert_only: Some(HashSet::from([FileType::Text])), ..extract_options() };
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+        let mut paths = tree(&dir.join("output"));
+        paths.sort();
+        assert_eq!(paths, [Path::new("notes.txt"), Path::new("photo.png.bin")]);
+        assert_eq!(std::fs::read(dir.join("output/notes.txt")).unwrap(), b"notes");
+        assert_eq!(std::fs::read(dir.join("output/photo.png.bin")).unwrap(), b"\x89PNG not decoded");
+    }
+
+    #[tokio::test]
+    async fn entries_differing_in_case_collide_with_case_fold_when_converting() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("case.zip");
+        write_zip(&zip_path, &[("README.txt", b"upper"), ("readme.txt.bin", b"lower")]);
+
+        let options = ExtractOptions { case_fold_entries: true, on_duplicate: DuplicatePolicy::Rename, ..extract_options() };
+        decompress_and_convert_to_files(&zip_path, &dir.join("renamed"), &options).await.unwrap();
+        let mut paths = tree(&dir.join("renamed"));
+        paths.sort();
+        assert_eq!(paths, [Path::new("README.txt"), Path::new("readme_1.txt")]);
+        assert_eq!(std::fs::read(dir.join("renamed/README.txt")).unwrap(), b"upper");
+        assert_eq!(std::fs::read(dir.join("renamed/readme_1.txt")).unwrap(), b"lower");
+
+        let options = ExtractOptions { case_fold_entries: true, ..extract_options() };
+        let e = decompress_and_convert_to_files(&zip_path, &dir.join("refused"), &options).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn bin_entry_without_an_original_extension_is_kept() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("mystery.bin", b"\0\x01 raw bytes"), ("notes.txt.bin", b"notes")]);
+
+        decompress_and_convert_to_files(&zip_path, &dir.join("output"), &extract_options()).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/mystery.bin")).unwrap(), b"\0\x01 raw bytes");
+        assert_eq!(std::fs::read(dir.join("output/notes.txt")).unwrap(), b"notes");
+        assert!(!dir.join("output/notes.txt.bin").exists());
+    }
 }
@@ -1,13 +1,170 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
-use std::time::Instant;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, UNIX_EPOCH};
 use tokio::fs as async_fs;
 use tokio::task;
 use futures::future;
-use zip::ZipArchive;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use zip::read::read_zipfile_from_stream;
+use zip::read::ZipFile;
+use zip::result::ZipResult;
+use zip::{DateTime, ZipArchive};
 
-pub async fn decompress_files(zip_path: &Path, output_folder: &Path) -> io::Result<()> {
+use crate::brotli_entries::{restore_brotli_files, BROTLI_MANIFEST_NAME};
+use crate::cancel::{check_cancelled, CancelFlag};
+use crate::central_directory::shadowed_entry_offsets;
+use crate::compression::FileType;
+use crate::dedup::{restore_duplicates, DEDUP_MANIFEST_NAME};
+use crate::chunking::{reassemble_chunked_files, CHUNK_MANIFEST_NAME};
+use crate::progress::{Progress, ProgressCallback};
+use crate::sparse::{restore_sparse_files, SPARSE_MANIFEST_NAME};
+use crate::utils::{check_entry_name, create_directory_entry, file_crc32};
+use crate::ownership::{restore_ownership, OWNERSHIP_NAME};
+use crate::creation_time::{restore_creation_times, CREATION_TIMES_NAME};
+use crate::xattrs::{restore_xattrs, XATTRS_NAME};
+use crate::color;
+
+/// What to do when an archive contains several entries with the same name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DuplicatePolicy {
+    /// The last entry with the name wins
+    Overwrite,
+    /// Later entries are extracted under a numbered name, e.g. `dup_1.txt`
+    Rename,
+    /// Stop the extraction
+    Error,
+}
+
+enum EntrySource {
+    Index(usize),
+    Shadowed(u64),
+}
+
+pub struct ExtractOptions {
+    pub safe: bool,
+    pub on_duplicate: DuplicatePolicy,
+    /// Skip entries whose output file already exists with a matching CRC32
+    pub resume: bool,
+    /// Skip entries whose output file exists and isn't older than the entry, like `unzip -u`
+    pub update: bool,
+    /// Seconds between progress lines when stdout isn't a terminal, 0 disables them
+    pub progress_interval: u64,
+    /// Restore the extended attributes stored with `--xattrs`
+    pub xattrs: bool,
+    /// Restore the file owners stored with `--preserve-owner`
+    pub preserve_owner: bool,
+    /// Restore the creation times stored with `--preserve-ctime`
+    pub preserve_ctime: bool,
+    /// Warn about malformed JSON files while converting them back
+    pub validate_json: bool,
+    /// The file types converted back, all of them when None
+    pub convert_only: Option<HashSet<FileType>>,
+    /// Entries larger than this are streamed to their output file instead of being read into memory first,
+    /// when converting them back
+    pub buffer_to_memory_threshold: u64,
+    /// Only entries with an uncompressed size in this range are extracted, `--min-size`/`--max-size`
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// `--rename` mappings of entry names to output names, the first matching one is applied
+    pub renames: Vec<(String, String)>,
+    /// Extract the entries on the Rayon thread pool, each thread reading through its own archive handle
+    pub parallel: bool,
+    /// Treat names differing only in case as duplicates, as they collide on Windows and macOS
+    pub case_fold_entries: bool,
+    /// Password of the encrypted entries
+    pub password: Option<String>,
+    /// Checked between entries, the extraction fails with a `Cancelled` error once it is set
+    pub cancel: CancelFlag,
+    /// Called with every progress event, for embedders rendering their own progress
+    pub progress: Option<ProgressCallback>,
+}
+
+impl ExtractOptions {
+    // The size is the one in the entry's header, so skipped entries are never decompressed
+    pub fn size_selected(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min_size| size >= min_size) && self.max_size.is_none_or(|max_size| size <= max_size)
+    }
+
+    /// The path the entry is extracted to inside the output folder, after `--rename`. A mapping ending in `/`
+    /// replaces that prefix of the name, any other one only the exact name. Only the first matching mapping is
+    /// applied, so a renamed entry isn't renamed again. None when the name would leave the output folder.
+    pub fn enclosed_name(&self, file: &ZipFile) -> Option<PathBuf> {
+        let name = file.name();
+        let renamed = self.renames.iter().find_map(|(from, to)| match name.strip_prefix(from.as_str()) {
+            Some(rest) if from.ends_with('/') => Some(format!("{}{}", to, rest)),
+            Some("") => Some(to.clone()),
+            _ => None,
+        });
+        let Some(renamed) = renamed else {
+            return file.enclosed_name();
+        };
+        let path = Path::new(&renamed);
+        let enclosed = !renamed.contains('\0') && path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        enclosed.then(|| path.to_path_buf())
+    }
+}
+
+/// The folder `--into-subdir` extracts to, named after the archive's file stem, e.g. `backup/` for `backup.zip`
+pub fn archive_subdir(output_folder: &Path, zip_path: &Path) -> PathBuf {
+    match zip_path.file_stem() {
+        Some(stem) => output_folder.join(stem),
+        None => output_folder.to_path_buf(),
+    }
+}
+
+/// Refuses a size filter or `--rename` on archives whose files are rebuilt from several entries after extraction.
+/// Their entries don't have the size or name of the file they restore, so filtering or renaming them would break
+/// the restored files.
+pub fn check_entry_filters(archive: &ZipArchive<File>, options: &ExtractOptions) -> io::Result<()> {
+    if options.min_size.is_none() && options.max_size.is_none() && options.renames.is_empty() {
+        return Ok(());
+    }
+    for manifest in [CHUNK_MANIFEST_NAME, DEDUP_MANIFEST_NAME, SPARSE_MANIFEST_NAME, BROTLI_MANIFEST_NAME] {
+        if archive.index_for_name(manifest).is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("--min-size, --max-size and --rename can't be used with archives holding {}", manifest)));
+        }
+    }
+    Ok(())
+}
+
+/// Reads an entry, decrypting it when a password is given. Unencrypted entries are read as they are either way.
+pub fn entry_by_index<'a>(archive: &'a mut ZipArchive<File>, index: usize, password: Option<&str>) -> ZipResult<ZipFile<'a>> {
+    match password {
+        Some(password) => archive.by_index_decrypt(index, password.as_bytes()),
+        None => archive.by_index(index),
+    }
+}
+
+/// True when the output file exists and was modified at or after the entry. Entries hold a local time without
+/// a time zone, read as UTC as the zip crate writes them, rounded down to the 2 seconds zip times have.
+pub fn is_up_to_date(file: &ZipFile, outpath: &Path) -> bool {
+    let Some(entry_time) = file.last_modified().map(unix_seconds) else {
+        return false;
+    };
+    let Some(disk_time) = std::fs::metadata(outpath).and_then(|metadata| metadata.modified()).ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()) else {
+        return false;
+    };
+    let disk_time = disk_time.as_secs() as i64;
+    disk_time - disk_time % 2 >= entry_time
+}
+
+// Days from 1970-01-01 by the civil calendar algorithm, so the time crate isn't needed for this one conversion
+fn unix_seconds(time: DateTime) -> i64 {
+    let (month, day) = (time.month() as i64, time.day() as i64);
+    let year = time.year() as i64 - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64
+}
+
+pub async fn decompress_files(zip_path: &Path, output_folder: &Path, options: &ExtractOptions) -> io::Result<()> {
     let start = Instant::now();
     println!("Starting decompression process...");
     async_fs::create_dir_all(output_folder).await?;
@@ -15,6 +172,7 @@ pub async fn decompress_files(zip_path: &Path, output_folder: &Path) -> io::Resu
     let file = File::open(zip_path)?;
     let mut archive = ZipArchive::new(file)?;
 
+    check_entry_filters(&archive, options)?;
     let archive_len = archive.len();
     println!("Archive contains {} entries", archive_len);
 
@@ -23,11 +181,50 @@ pub async fn decompress_files(zip_path: &Path, output_folder: &Path) -> io::Resu
         return Ok(());
     }
 
-    let mut tasks = vec![];
+    // Entries hidden behind a later entry with the same name are extracted first, so the
+    // entry the zip crate exposes is still the last one written under that name.
+    let mut raw_reader = File::open(zip_path)?;
+    let shadowed = shadowed_entry_offsets(&mut archive, &mut raw_reader)?;
+    if options.parallel {
+        if shadowed.is_empty() && !options.case_fold_entries {
+            return extract_in_parallel(zip_path, output_folder, archive, options, start);
+        }
+        println!("Archive has duplicate entry names or --case-fold-entries is set, extracting sequentially");
+    }
+    let entries = shadowed.iter().map(|&offset| EntrySource::Shadowed(offset))
+        .chain((0..archive_len).map(EntrySource::Index));
+
+    let progress = Progress::new(archive_len + shadowed.len(), options.progress_interval, options.progress.as_ref());
+    let mut tasks: Vec<Option<task::JoinHandle<()>>> = vec![];
+    let mut extracted = ExtractedPaths::new();
 
-    for i in 0..archive_len {
-        let mut file = archive.by_index(i).unwrap();
-        let outpath = match file.enclosed_name() {
+    for (i, entry) in entries.enumerate() {
+        check_cancelled(&options.cancel)?;
+        let mut file = match entry {
+            EntrySource::Index(index) => entry_by_index(&mut archive, index, options.password.as_deref())?,
+            EntrySource::Shadowed(header_start) => {
+                raw_reader.seek(SeekFrom::Start(header_start))?;
+                match read_zipfile_from_stream(&mut raw_reader)? {
+                    Some(file) => file,
+                    None => continue,
+                }
+            }
+        };
+        if file.name() == XATTRS_NAME || file.name() == OWNERSHIP_NAME || file.name() == CREATION_TIMES_NAME {
+            continue;
+        }
+        check_entry_name(file.name(), options.safe)?;
+        if file.is_dir() {
+            create_directory_entry(output_folder, file.name(), options.enclosed_name(&file))?;
+            progress.record(file.name(), 0);
+            continue;
+        }
+        if !options.size_selected(file.size()) {
+            println!("Skipping file outside the size range at index {}: {}", i, file.name());
+            progress.record(file.name(), file.size());
+            continue;
+        }
+        let outpath = match options.enclosed_name(&file) {
             Some(path) => output_folder.join(path),
             None => {
                 println!("Skipping file at index {}: invalid file name", i);
@@ -35,22 +232,520 @@ pub async fn decompress_files(zip_path: &Path, output_folder: &Path) -> io::Resu
             }
         };
 
+        let outpath = resolve_duplicate(file.name(), outpath, &mut extracted, &mut tasks, options).await?;
+
+        if options.resume && outpath.is_file() && file_crc32(&outpath)? == file.crc32() {
+            println!("Skipping already extracted file at index {}: {:?}", i, outpath.file_name().unwrap());
+            tasks.push(None);
+            progress.record(file.name(), file.size());
+            continue;
+        }
+        if options.update && is_up_to_date(&file, &outpath) {
+            println!("Skipping file not older than the existing one at index {}: {:?}", i, outpath.file_name().unwrap());
+            tasks.push(None);
+            progress.record(file.name(), file.size());
+            continue;
+        }
+
         println!("Processing file at index {}: {:?}", i, outpath.file_name().unwrap());
 
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
+        progress.record(file.name(), buffer.len() as u64);
+        // Archives don't always have entries for the directories of their files
+        if let Some(parent) = outpath.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
-        let output_folder = output_folder.to_path_buf();
-
-        tasks.push(task::spawn(async move {
+        tasks.push(Some(task::spawn(async move {
             async_fs::write(&outpath, &buffer).await.unwrap();
             println!("Extracted file: {:?}", outpath.file_name().unwrap());
-        }));
+        })));
     }
 
     let duration = start.elapsed();
     println!("Time elapsed: {:?}", duration);
-    future::join_all(tasks).await;
+    future::join_all(tasks.into_iter().flatten()).await;
+    reassemble_chunked_files(output_folder)?;
+    restore_sparse_files(output_folder)?;
+    restore_brotli_files(output_folder)?;
+    restore_duplicates(output_folder)?;
+    if options.xattrs {
+        restore_xattrs(&mut archive, output_folder)?;
+    }
+    if options.preserve_owner {
+        restore_ownership(&mut archive, output_folder)?;
+    }
+    if options.preserve_ctime {
+        restore_creation_times(&mut archive, output_folder)?;
+    }
+    progress.finish();
+    println!("Decompression process completed.");
+    Ok(())
+}
+
+// The zip reader isn't shared between threads, so every Rayon worker opens its own handle on the archive
+// and the entries are split between them by index
+fn extract_in_parallel(zip_path: &Path, output_folder: &Path, mut archive: ZipArchive<File>, options: &ExtractOptions, start: Instant) -> io::Result<()> {
+    let progress = Progress::new(archive.len(), options.progress_interval, options.progress.as_ref());
+    (0..archive.len()).into_par_iter().try_for_each_init(
+        || File::open(zip_path).and_then(|file| ZipArchive::new(file).map_err(io::Error::from)),
+        |handle, index| -> io::Result<()> {
+            check_cancelled(&options.cancel)?;
+            let archive = handle.as_mut().map_err(|e| io::Error::new(e.kind(), e.to_string()))?;
+            let mut file = entry_by_index(archive, index, options.password.as_deref())?;
+            if file.name() == XATTRS_NAME || file.name() == OWNERSHIP_NAME || file.name() == CREATION_TIMES_NAME {
+                return Ok(());
+            }
+            check_entry_name(file.name(), options.safe)?;
+            if file.is_dir() {
+                create_directory_entry(output_folder, file.name(), options.enclosed_name(&file))?;
+                progress.record(file.name(), 0);
+                return Ok(());
+            }
+            if !options.size_selected(file.size()) {
+                println!("Skipping file outside the size range at index {}: {}", index, file.name());
+                progress.record(file.name(), file.size());
+                return Ok(());
+            }
+            let Some(outpath) = options.enclosed_name(&file).map(|path| output_folder.join(path)) else {
+                println!("Skipping file at index {}: invalid file name", index);
+                return Ok(());
+            };
+            if options.resume && outpath.is_file() && file_crc32(&outpath)? == file.crc32() {
+                println!("Skipping already extracted file at index {}: {:?}", index, outpath.file_name().unwrap());
+                progress.record(file.name(), file.size());
+                return Ok(());
+            }
+            if options.update && is_up_to_date(&file, &outpath) {
+                println!("Skipping file not older than the existing one at index {}: {:?}", index, outpath.file_name().unwrap());
+                progress.record(file.name(), file.size());
+                return Ok(());
+            }
+            if let Some(parent) = outpath.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let written = io::copy(&mut file, &mut File::create(&outpath)?)?;
+            progress.record(file.name(), written);
+            println!("Extracted file: {:?}", outpath.file_name().unwrap());
+            Ok(())
+        },
+    )?;
+
+    println!("Time elapsed: {:?}", start.elapsed());
+    reassemble_chunked_files(output_folder)?;
+    restore_sparse_files(output_folder)?;
+    restore_brotli_files(output_folder)?;
+    restore_duplicates(output_folder)?;
+    if options.xattrs {
+        restore_xattrs(&mut archive, output_folder)?;
+    }
+    if options.preserve_owner {
+        restore_ownership(&mut archive, output_folder)?;
+    }
+    if options.preserve_ctime {
+        restore_creation_times(&mut archive, output_folder)?;
+    }
+    progress.finish();
     println!("Decompression process completed.");
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Decompresses every entry into `io::sink()`, which checks its CRC32, without touching the disk.
+/// Every failing entry is reported and the test fails if there were any. With `parallel` the entries are
+/// split between Rayon workers, each reading through its own handle on the archive like `extract_in_parallel`.
+pub fn test_archive(zip_path: &Path, password: Option<&str>, cancel: &CancelFlag, parallel: bool) -> io::Result<()> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let passed: Vec<bool> = if parallel {
+        (0..archive.len()).into_par_iter().map_init(
+            || File::open(zip_path).and_then(|file| ZipArchive::new(file).map_err(io::Error::from)),
+            |handle, index| -> io::Result<bool> {
+                check_cancelled(cancel)?;
+                let archive = handle.as_mut().map_err(|e| io::Error::new(e.kind(), e.to_string()))?;
+                Ok(test_entry(archive, index, password))
+            },
+        ).collect::<io::Result<_>>()?
+    } else {
+        let mut passed = Vec::with_capacity(archive.len());
+        for i in 0..archive.len() {
+            check_cancelled(cancel)?;
+            passed.push(test_entry(&mut archive, i, password));
+        }
+        passed
+    };
+    let failures = passed.iter().filter(|passed| !**passed).count();
+    if failures > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} of {} entries failed the test", failures, archive.len())));
+    }
+    println!("All {} entries of {:?} are extractable", archive.len(), zip_path);
+    Ok(())
+}
+
+// Prints whether the entry decompresses with the right CRC32
+fn test_entry(archive: &mut ZipArchive<File>, index: usize, password: Option<&str>) -> bool {
+    let mut file = match entry_by_index(archive, index, password) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("FAILED entry at index {}: {}", index, e);
+            return false;
+        }
+    };
+    match io::copy(&mut file, &mut io::sink()) {
+        Ok(_) => {
+            println!("OK {}", file.name());
+            true
+        }
+        Err(e) => {
+            eprintln!("FAILED {}: {}", file.name(), e);
+            false
+        }
+    }
+}
+
+/// Output paths already written, by their collision key, with the path and the index of their task
+pub type ExtractedPaths = HashMap<PathBuf, (PathBuf, usize)>;
+
+/// Applies `--on-duplicate` to an entry whose output path collides with one already extracted, with names differing
+/// only in case colliding under `--case-fold-entries`. Returns the path the entry is written to, recorded with the
+/// index of the task the caller pushes next.
+pub async fn resolve_duplicate(name: &str, mut outpath: PathBuf, extracted: &mut ExtractedPaths, tasks: &mut [Option<task::JoinHandle<()>>], options: &ExtractOptions) -> io::Result<PathBuf> {
+    if let Some((previous_path, previous_task)) = extracted.get(&collision_key(&outpath, options.case_fold_entries)).cloned() {
+        match options.on_duplicate {
+            DuplicatePolicy::Overwrite => {
+                eprintln!("{} duplicate entry {}, overwriting the previous one", color::warning(), name);
+                // Let the earlier write finish first so the last entry reliably wins
+                if let Some(task) = tasks[previous_task].take() {
+                    let _ = task.await;
+                }
+                // On case-sensitive filesystems the earlier file has its own name, so it is removed
+                if previous_path != outpath {
+                    async_fs::remove_file(&previous_path).await?;
+                }
+            }
+            DuplicatePolicy::Rename => {
+                let renamed = unique_duplicate_path(&outpath, extracted, options.case_fold_entries);
+                eprintln!("{} duplicate entry {}, extracting it as {:?}", color::warning(), name, renamed.file_name().unwrap());
+                outpath = renamed;
+            }
+            DuplicatePolicy::Error => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Duplicate entry in archive: {}", name)));
+            }
+        }
+    }
+    extracted.insert(collision_key(&outpath, options.case_fold_entries), (outpath.clone(), tasks.len()));
+    Ok(outpath)
+}
+
+fn collision_key(path: &Path, case_fold: bool) -> PathBuf {
+    if case_fold {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn unique_duplicate_path(path: &Path, extracted: &ExtractedPaths, case_fold: bool) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let extension = path.extension().and_then(|e| e.to_str());
+    let mut counter = 1;
+    loop {
+        let file_name = match extension {
+            Some(extension) => format!("{}_{}.{}", stem, counter, extension),
+            None => format!("{}_{}", stem, counter),
+        };
+        let candidate = path.with_file_name(file_name);
+        if !extracted.contains_key(&collision_key(&candidate, case_fold)) {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use super::*;
+    use crate::test_support::{extract_options, fixture, write_files, write_zip, TempDir};
+
+    const ABSOLUTE_ENTRY: &str = "/tmp/rust-zip-absolute-path.txt";
+
+    #[tokio::test]
+    async fn absolute_entry_is_refused_in_safe_mode() {
+        let dir = TempDir::new();
+        let e = decompress_files(&fixture("absolute-path.zip"), &dir.join("output"), &extract_options()).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains(ABSOLUTE_ENTRY));
+        assert!(!Path::new(ABSOLUTE_ENTRY).exists());
+    }
+
+    #[tokio::test]
+    async fn absolute_entry_is_skipped_without_safe_mode() {
+        let dir = TempDir::new();
+        let options = ExtractOptions { safe: false, ..extract_options() };
+        decompress_files(&fixture("absolute-path.zip"), &dir.join("output"), &options).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/safe.txt")).unwrap(), b"inside\n");
+        assert!(!Path::new(ABSOLUTE_ENTRY).exists());
+        assert!(!dir.join("output/tmp").exists());
+    }
+
+    async fn extract_duplicates(dir: &TempDir, on_duplicate: DuplicatePolicy) -> io::Result<()> {
+        let options = ExtractOptions { on_duplicate, ..extract_options() };
+        decompress_files(&fixture("duplicate-names.zip"), &dir.join("output"), &options).await
+    }
+
+    #[tokio::test]
+    async fn duplicate_entry_overwrites_the_earlier_one() {
+        let dir = TempDir::new();
+        extract_duplicates(&dir, DuplicatePolicy::Overwrite).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/dup.txt")).unwrap(), b"second\n");
+        assert_eq!(std::fs::read(dir.join("output/other.txt")).unwrap(), b"other\n");
+        assert!(!dir.join("output/dup_1.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn duplicate_entry_is_renamed() {
+        let dir = TempDir::new();
+        extract_duplicates(&dir, DuplicatePolicy::Rename).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("output/dup.txt")).unwrap(), b"first\n");
+        assert_eq!(std::fs::read(dir.join("output/dup_1.txt")).unwrap(), b"second\n");
+    }
+
+    #[tokio::test]
+    async fn duplicate_entry_stops_the_extraction() {
+        let dir = TempDir::new();
+        let e = extract_duplicates(&dir, DuplicatePolicy::Error).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(!dir.join("output/dup_1.txt").exists());
+    }
+
+    fn modified(path: &Path) -> SystemTime {
+        std::fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    #[tokio::test]
+    async fn resume_only_writes_the_missing_and_changed_files() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        write_zip(&zip_path, &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c"), ("d.txt", b"d")]);
+        // Half the archive was extracted, and c.txt was cut short
+        let output = dir.join("output");
+        write_files(&output, &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"")]);
+        let long_ago = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            File::options().write(true).open(output.join(name)).unwrap().set_modified(long_ago).unwrap();
+        }
+
+        decompress_files(&zip_path, &output, &ExtractOptions { resume: true, ..extract_options() }).await.unwrap();
+        for name in ["a.txt", "b.txt"] {
+            assert_eq!(modified(&output.join(name)), long_ago, "{}", name);
+        }
+        assert_ne!(modified(&output.join("c.txt")), long_ago);
+        for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+            assert_eq!(std::fs::read(output.join(name)).unwrap(), &name.as_bytes()[..1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn update_only_overwrites_files_older_than_their_entry() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("archive.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        // 2020-01-01 00:00:00, which is 1577836800 seconds after the epoch
+        let options = zip::write::SimpleFileOptions::default()
+            .last_modified_time(DateTime::from_date_and_time(2020, 1, 1, 0, 0, 0).unwrap());
+        for (name, bytes) in [("newer.txt", b"archived"), ("older.txt", b"archived"), ("missing.txt", b"archived")] {
+            zip.start_file(name, options).unwrap();
+            std::io::Write::write_all(&mut zip, bytes).unwrap();
+        }
+        zip.finish().unwrap();
+        assert_eq!(unix_seconds(DateTime::from_date_and_time(2020, 1, 1, 0, 0, 0).unwrap()), 1_577_836_800);
+
+        let output = dir.join("output");
+        write_files(&output, &[("newer.txt", b"on disk"), ("older.txt", b"on disk")]);
+        let before = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        File::options().write(true).open(output.join("older.txt")).unwrap().set_modified(before).unwrap();
+
+        decompress_files(&zip_path, &output, &ExtractOptions { update: true, ..extract_options() }).await.unwrap();
+        assert_eq!(std::fs::read(output.join("newer.txt")).unwrap(), b"on disk");
+        assert_eq!(std::fs::read(output.join("older.txt")).unwrap(), b"archived");
+        assert_eq!(std::fs::read(output.join("missing.txt")).unwrap(), b"archived");
+    }
+
+    // An archive whose last entry has one byte of its compressed data flipped
+    fn write_corrupt_zip(zip_path: &Path, entries: &[(&str, &[u8])], corrupt: &str) {
+        write_zip(zip_path, entries);
+        let mut bytes = std::fs::read(zip_path).unwrap();
+        let name = corrupt.as_bytes();
+        let header = bytes.windows(name.len()).position(|window| window == name).unwrap() - 30;
+        let name_len = u16::from_le_bytes([bytes[header + 26], bytes[header + 27]]) as usize;
+        let extra_len = u16::from_le_bytes([bytes[header + 28], bytes[header + 29]]) as usize;
+        bytes[header + 30 + name_len + extra_len + 10] ^= 0xff;
+        std::fs::write(zip_path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_mode_passes_a_valid_archive_and_fails_a_corrupt_one() {
+        let dir = TempDir::new();
+        let text = "some text to compress\n".repeat(500);
+        write_zip(&dir.join("valid.zip"), &[("a.txt", b"a"), ("b.txt", text.as_bytes())]);
+        test_archive(&dir.join("valid.zip"), None, &None, false).unwrap();
+
+        write_corrupt_zip(&dir.join("corrupt.zip"), &[("a.txt", b"a"), ("b.txt", text.as_bytes())], "b.txt");
+        let e = test_archive(&dir.join("corrupt.zip"), None, &None, false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "1 of 2 entries failed the test");
+    }
+
+    #[test]
+    fn parallel_test_finds_the_one_corrupt_entry() {
+        let dir = TempDir::new();
+        let text = "some text to compress\n".repeat(500);
+        let names: Vec<String> = (0..40).map(|i| format!("entry-{:03}.txt", i)).collect();
+        let entries: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), text.as_bytes())).collect();
+        write_zip(&dir.join("valid.zip"), &entries);
+        test_archive(&dir.join("valid.zip"), None, &None, true).unwrap();
+
+        write_corrupt_zip(&dir.join("corrupt.zip"), &entries, "entry-017.txt");
+        for parallel in [true, false] {
+            let e = test_archive(&dir.join("corrupt.zip"), None, &None, parallel).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), "1 of 40 entries failed the test", "parallel: {}", parallel);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_entries_in_the_size_range_are_extracted() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("mixed.zip");
+        let (medium, large) = (b"m".repeat(1000), b"l".repeat(100_000));
+        write_zip(&zip_path, &[("empty.txt", b""), ("small.txt", b"small"), ("nested/medium.txt", &medium), ("large.txt", &large)]);
+
+        let options = ExtractOptions { min_size: Some(1000), ..extract_options() };
+        decompress_files(&zip_path, &dir.join("large"), &options).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("large/nested/medium.txt")).unwrap(), medium);
+        assert_eq!(std::fs::read(dir.join("large/large.txt")).unwrap(), large);
+        assert!(!dir.join("large/small.txt").exists());
+        assert!(!dir.join("large/empty.txt").exists());
+
+        // Both bounds are inclusive
+        let options = ExtractOptions { min_size: Some(5), max_size: Some(1000), ..extract_options() };
+        decompress_files(&zip_path, &dir.join("middle"), &options).await.unwrap();
+        assert!(dir.join("middle/small.txt").is_file());
+        assert!(dir.join("middle/nested/medium.txt").is_file());
+        assert!(!dir.join("middle/large.txt").exists());
+        assert!(!dir.join("middle/empty.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn renamed_prefix_moves_the_entries() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("renamed.zip");
+        write_zip(&zip_path, &[("old/a.txt", b"a"), ("old/deep/b.txt", b"b"), ("older/c.txt", b"c"), ("notes.txt", b"notes")]);
+
+        // The second mapping would match the renamed entries, but every entry is renamed once
+        let renames = [("old/", "new/"), ("new/", "newer/"), ("notes.txt", "docs/notes.txt")];
+        let options = ExtractOptions { renames: renames.iter().map(|(from, to)| (from.to_string(), to.to_string())).collect(), ..extract_options() };
+        decompress_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+        let output = dir.join("output");
+        assert_eq!(std::fs::read(output.join("new/a.txt")).unwrap(), b"a");
+        assert_eq!(std::fs::read(output.join("new/deep/b.txt")).unwrap(), b"b");
+        assert_eq!(std::fs::read(output.join("older/c.txt")).unwrap(), b"c");
+        assert_eq!(std::fs::read(output.join("docs/notes.txt")).unwrap(), b"notes");
+        assert!(!output.join("old").exists());
+        assert!(!output.join("newer").exists());
+        assert!(!output.join("notes.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_directory_entries_are_created() {
+        let dir = TempDir::new();
+        let zip_path = dir.join("dirs.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        for name in ["empty/", "nested/deep/", "../outside/"] {
+            zip.add_directory(name, zip::write::SimpleFileOptions::default()).unwrap();
+        }
+        zip.start_file("a.txt", zip::write::SimpleFileOptions::default()).unwrap();
+        zip.finish().unwrap();
+
+        decompress_files(&zip_path, &dir.join("output"), &extract_options()).await.unwrap();
+        assert!(dir.join("output/empty").is_dir());
+        assert!(dir.join("output/nested/deep").is_dir());
+        assert!(dir.join("output/a.txt").is_file());
+        // The directory entry leaving the output folder is skipped with a warning
+        assert!(!dir.join("outside").exists());
+    }
+
+    #[tokio::test]
+    async fn parallel_extraction_restores_every_entry() {
+        let dir = TempDir::new();
+        let contents: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| (format!("dir_{}/file_{}.txt", i % 7, i), format!("entry {} ", i).repeat(i + 1).into_bytes()))
+            .collect();
+        let entries: Vec<(&str, &[u8])> = contents.iter().map(|(name, bytes)| (name.as_str(), bytes.as_slice())).collect();
+        write_zip(&dir.join("many.zip"), &entries);
+
+        let options = ExtractOptions { parallel: true, ..extract_options() };
+        decompress_files(&dir.join("many.zip"), &dir.join("output"), &options).await.unwrap();
+        for (name, bytes) in &contents {
+            assert_eq!(&std::fs::read(dir.join("output").join(name)).unwrap(), bytes, "{}", name);
+        }
+    }
+
+    // The runtime has worker threads as under `main`, so the async extractor isn't held back by a single thread
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture extraction_benchmark`"]
+    async fn extraction_benchmark_parallel_against_async() {
+        let dir = TempDir::new();
+        let words = ["alpha ", "beta ", "gamma ", "delta "];
+        let text: String = crate::test_support::random_bytes(64 * 1024, 4).iter().map(|byte| words[*byte as usize % words.len()]).collect();
+        let names: Vec<String> = (0..2000).map(|i| format!("dir_{}/file_{}.txt", i % 20, i)).collect();
+        let entries: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), text.as_bytes())).collect();
+        write_zip(&dir.join("many.zip"), &entries);
+
+        let dir = &dir;
+        let best_of_three = |parallel: bool| async move {
+            let mut best = Duration::MAX;
+            for run in 0..3 {
+                let output = dir.join(format!("output-{}-{}", parallel, run));
+                let start = std::time::Instant::now();
+                decompress_files(&dir.join("many.zip"), &output, &ExtractOptions { parallel, ..extract_options() }).await.unwrap();
+                best = best.min(start.elapsed());
+                std::fs::remove_dir_all(output).unwrap();
+            }
+            best
+        };
+        let serial = best_of_three(false).await;
+        let parallel = best_of_three(true).await;
+        println!("{} entries on {} cores: {:?} with the async extractor, {:?} with --parallel, {:.2}x",
+            names.len(), std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            serial, parallel, serial.as_secs_f64() / parallel.as_secs_f64());
+    }
+
+    #[tokio::test]
+    async fn entries_differing_in_case_collide_with_case_fold() {
+        let dir = TempDir::new();
+        write_zip(&dir.join("case.zip"), &[("README", b"upper"), ("readme", b"lower")]);
+
+        let options = ExtractOptions { case_fold_entries: true, on_duplicate: DuplicatePolicy::Rename, ..extract_options() };
+        decompress_files(&dir.join("case.zip"), &dir.join("renamed"), &options).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("renamed/README")).unwrap(), b"upper");
+        assert_eq!(std::fs::read(dir.join("renamed/readme_1")).unwrap(), b"lower");
+
+        let options = ExtractOptions { case_fold_entries: true, ..extract_options() };
+        let e = decompress_files(&dir.join("case.zip"), &dir.join("refused"), &options).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn archives_extracted_into_subdirs_do_not_collide() {
+        let dir = TempDir::new();
+        write_zip(&dir.join("backup.zip"), &[("a.txt", b"backup")]);
+        write_zip(&dir.join("photos.zip"), &[("a.txt", b"photos")]);
+        for name in ["backup.zip", "photos.zip"] {
+            let output_folder = archive_subdir(&dir.join("output"), &dir.join(name));
+            decompress_files(&dir.join(name), &output_folder, &extract_options()).await.unwrap();
+        }
+        assert_eq!(std::fs::read(dir.join("output/backup/a.txt")).unwrap(), b"backup");
+        assert_eq!(std::fs::read(dir.join("output/photos/a.txt")).unwrap(), b"photos");
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+use serde_json::{json, Map, Value};
+
+/// Entry mapping the duplicate entries of archives written with `--dedup` to the entry holding their bytes
+pub const DEDUP_MANIFEST_NAME: &str = ".rust-zip-dedup.json";
+
+/// Remembers the first entry written for each content hash during one compression run
+#[derive(Default)]
+pub struct Deduplicator {
+    canonical_entries: Mutex<HashMap<[u8; 32], String>>,
+    duplicates: Mutex<Vec<(String, String)>>,
+}
+
+impl Deduplicator {
+    // Returns the entry already holding the same bytes, or None when this entry is the first one with them
+    pub fn canonical_entry(&self, entry_name: &str, source_path: &Path) -> io::Result<Option<String>> {
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut File::open(source_path)?, &mut hasher)?;
+        let hash = *hasher.finalize().as_bytes();
+
+        let mut canonical_entries = self.canonical_entries.lock().unwrap();
+        match canonical_entries.get(&hash) {
+            Some(canonical) => {
+                self.duplicates.lock().unwrap().push((entry_name.to_owned(), canonical.clone()));
+                Ok(Some(canonical.clone()))
+            }
+            None => {
+                canonical_entries.insert(hash, entry_name.to_owned());
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn into_duplicates(self) -> Vec<(String, String)> {
+        self.duplicates.into_inner().unwrap()
+    }
+}
+
+pub fn dedup_manifest_json(duplicates: &[(String, String)]) -> String {
+    let duplicates: Map<String, Value> = duplicates.iter()
+        .map(|(duplicate, canonical)| (duplicate.clone(), Value::String(canonical.clone())))
+        .collect();
+    json!({ "duplicates": duplicates }).to_string()
+}
+
+/// Copies the extracted canonical entries over the empty marker files of their duplicates,
+/// then removes the manifest. Does nothing for archives written without `--dedup`.
+pub fn restore_duplicates(output_folder: &Path) -> io::Result<()> {
+    let manifest_path = output_folder.join(DEDUP_MANIFEST_NAME);
+    if !manifest_path.is_file() {
+        return Ok(());
+    }
+
+    let manifest: Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid dedup manifest: {}", e)))?;
+    let duplicates = manifest["duplicates"].as_object().cloned().unwrap_or_default();
+
+    for (duplicate, canonical) in &duplicates {
+        let Some(canonical) = canonical.as_str() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid entry in dedup manifest"));
+        };
+        for name in [duplicate.as_str(), canonical] {
+            if !Path::new(name).components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in dedup manifest: {}", name)));
+            }
+        }
+        std::fs::copy(output_folder.join(canonical), output_folder.join(duplicate))?;
+    }
+    if !duplicates.is_empty() {
+        println!("Restored {} duplicate files", duplicates.len());
+    }
+
+    std::fs::remove_file(&manifest_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use zip::ZipArchive;
+    use super::*;
+    use crate::decompression_wo_conversion::{decompress_files, ExtractOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, extract_options, settings, write_files, TempDir};
+
+    const NAMES: [&str; 3] = ["a.txt", "b.txt", "nested/c.txt"];
+
+    // Archives three identical files with `--dedup`, checks they are restored and returns the archive
+    async fn dedup_round_trip(dir: &TempDir, password: Option<&str>) -> ZipArchive<File> {
+        let contents = b"the same bytes in every file ".repeat(100);
+        let files: Vec<(&str, &[u8])> = NAMES.iter().map(|name| (*name, &contents[..])).collect();
+        write_files(&dir.join("input"), &files);
+        let zip_path = dir.join("dedup.zip");
+        let settings = CompressionSettings { dedup: true, password: password.map(str::to_owned), ..settings(&zip_path) };
+        compress(&dir.join("input"), &settings);
+
+        let options = ExtractOptions { password: password.map(str::to_owned), ..extract_options() };
+        decompress_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+        for name in NAMES {
+            assert_eq!(std::fs::read(dir.join("output").join(name)).unwrap(), contents, "{}", name);
+        }
+        assert!(!dir.join("output").join(DEDUP_MANIFEST_NAME).exists());
+        ZipArchive::new(File::open(&zip_path).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn identical_files_are_stored_once() {
+        let dir = TempDir::new();
+        let mut archive = dedup_round_trip(&dir, None).await;
+        let sizes: Vec<u64> = NAMES.iter().map(|name| archive.by_name(name).unwrap().size()).collect();
+        assert_eq!(sizes.iter().filter(|&&size| size > 0).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_encrypted_like_the_other_entries() {
+        let dir = TempDir::new();
+        let mut archive = dedup_round_trip(&dir, Some("secret")).await;
+        for name in NAMES {
+            // Encrypted entries can't be opened without the password
+            assert!(archive.by_name(name).is_err(), "{}", name);
+            assert_eq!(archive.by_name_decrypt(name, b"secret").unwrap().compression(), zip::CompressionMethod::Zstd, "{}", name);
+        }
+    }
+}
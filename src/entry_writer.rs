@@ -0,0 +1,1083 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use zip::{write::FileOptions, AesMode, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::binary_naming::BinaryNaming;
+#[cfg(feature = "brotli")]
+use crate::brotli_entries::compress_into;
+use crate::brotli_entries::{brotli_manifest_json, BROTLI_MANIFEST_NAME};
+use crate::cancel::{check_cancelled, is_cancelled, CancelFlag};
+use crate::checksum::{hash_entry_file, ChecksumAlgorithm, EntryHashes, HashingReader};
+use crate::compression::{FileType, UnsupportedPolicy};
+use crate::dedup::{dedup_manifest_json, Deduplicator, DEDUP_MANIFEST_NAME};
+use crate::progress::{Progress, ProgressCallback};
+use crate::report::ReportRow;
+use crate::text_to_binary::LineEnding;
+use crate::stats::{CompressionStats, TimedReader};
+use crate::chunking::{compress_part, manifest_json, part_temp_path, ChunkedFile, CHUNK_MANIFEST_NAME};
+use crate::manifest::{write_manifest, ManifestEntry};
+use crate::sparse::{data_segments, sparse_manifest_json, SparseFile, SPARSE_MANIFEST_NAME};
+use crate::utils::{choose_method_for, get_compression_method, get_zip_compression_method};
+use crate::ownership::{ownership_json, read_owner, Owner, OWNERSHIP_NAME};
+use crate::creation_time::{creation_times_json, read_creation_time, CREATION_TIMES_NAME};
+use crate::xattrs::{read_xattrs, xattrs_json, Attributes, XATTRS_NAME};
+
+// Entries this large no longer fit the 32-bit size fields of a regular zip header
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+
+/// What happens once `--max-files` files have been added
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MaxFilesAction {
+    /// Finish the archive with the files added so far
+    #[default]
+    Stop,
+    /// Fail the run, so no archive is written
+    Error,
+}
+
+/// When entries are written with zip64 headers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Zip64Mode {
+    /// Only for entries of 4 GiB or more
+    #[default]
+    Auto,
+    /// For every entry
+    Always,
+    /// Never, entries of 4 GiB or more fail to compress
+    Never,
+}
+
+impl Zip64Mode {
+    fn large_file(self, file_size: u64) -> bool {
+        match self {
+            Zip64Mode::Auto => file_size >= ZIP64_THRESHOLD,
+            Zip64Mode::Always => true,
+            Zip64Mode::Never => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CompressionSettings {
+    /// Canonical path of the zip being written, so it is never added to itself
+    pub output_zip: PathBuf,
+    pub compression_algorithm: String,
+    pub compression_level: i64,
+    /// Per-extension overrides of the algorithm and level, keyed on the lowercase extension
+    pub method_rules: HashMap<String, MethodRule>,
+    /// Algorithm an entry is retried with once when compressing it fails, `Stored` included
+    pub fallback_method: Option<String>,
+    pub recursive: bool,
+    /// Directory levels walked below the input folder with `recursive`, deeper directories are skipped
+    pub max_depth: Option<usize>,
+    /// Gitignore-style file with the paths to leave out
+    pub exclude_from: Option<PathBuf>,
+    /// Walk the folder with the ignore crate, leaving out gitignored files and `.git`
+    pub respect_gitignore: bool,
+    /// Walk the directories symlinks point to, which may lead outside of the input folder
+    pub follow_symlinks: bool,
+    /// Walk the directories Windows junctions point to
+    pub dereference_junctions: bool,
+    /// Gitignore-style patterns from the config, only matching files are archived when there are any
+    pub include_patterns: Vec<String>,
+    /// Gitignore-style patterns from the config of the paths to leave out
+    pub exclude_patterns: Vec<String>,
+    /// Directory the entries are stored under inside the archive
+    pub prefix: Option<String>,
+    /// Leading path removed from the entry names before the prefix is added
+    pub strip_prefix: Option<String>,
+    /// Keep the names not starting with `strip_prefix` instead of failing
+    pub strip_prefix_lenient: bool,
+    /// Archive the paths listed on stdin instead of walking the input folder
+    pub paths_from_stdin: bool,
+    /// The stdin paths are separated by NUL bytes instead of newlines
+    pub null_separated: bool,
+    /// Read FIFOs as streams instead of skipping them
+    pub include_special: bool,
+    /// Store the entries under the input folder's name instead of at the archive root
+    pub include_root: bool,
+    /// Store the extended attributes of the files
+    pub xattrs: bool,
+    /// Store the uid and gid of every file
+    pub preserve_owner: bool,
+    /// Store the creation time of every file where the platform reports one
+    pub preserve_ctime: bool,
+    /// Store the bytes of identical files only once
+    pub dedup: bool,
+    /// Path of an external JSON manifest listing every archived file
+    pub manifest: Option<PathBuf>,
+    /// Path of a CSV report with the sizes, method, CRC32 and duration of every archived file
+    pub report: Option<PathBuf>,
+    /// Canonical path of the `.tar.zst` written next to the zip, kept out of the collected files like the zip
+    #[cfg(feature = "tar-zst")]
+    pub also_tar_zst: Option<PathBuf>,
+    pub zip64: Zip64Mode,
+    /// Stored entries start their data at a multiple of this many bytes
+    pub align: Option<u16>,
+    /// How files converted with `--convert-to-binary` are named
+    pub binary_naming: BinaryNaming,
+    /// Fail when two entry names only differ in case
+    pub case_fold_entries: bool,
+    /// The file types converted with `--convert-to-binary`, all of them when None
+    pub convert_types: Option<HashSet<FileType>>,
+    /// Line endings text files are rewritten to while they are converted
+    pub normalize_eol: Option<LineEnding>,
+    /// What happens to the files no converter supports with `--convert-to-binary`
+    pub on_unsupported: UnsupportedPolicy,
+    /// Files larger than this are split into parts compressed in parallel
+    pub chunk_large_files: Option<u64>,
+    /// The zip writer is flushed every this many bytes of an entry, so large entries reach the disk as they are compressed
+    pub flush_interval: Option<u64>,
+    /// Store only the data segments of sparse files, Linux only
+    pub sparse: bool,
+    /// Seconds between progress lines when stdout isn't a terminal, 0 disables them
+    pub progress_interval: u64,
+    /// List every failed file once all files are done, and fail the run if there were any
+    pub keep_going: bool,
+    /// Don't print the failed files as they happen, only their number at the end
+    pub quiet_errors: bool,
+    /// No new files are started once this much time has passed
+    pub time_limit: Option<Duration>,
+    /// No new files are started once this many have been
+    pub max_files: Option<usize>,
+    pub max_files_action: MaxFilesAction,
+    /// Print where the time went once all files are added
+    pub verbose: bool,
+    /// Extra files added under the given entry names after the folder contents
+    pub embeds: Vec<(PathBuf, String)>,
+    /// Start the largest files first, longest-processing-time-first scheduling for the worker threads
+    pub largest_first: bool,
+    /// Directory for the converted copies of the files and other intermediates
+    pub temp_dir: PathBuf,
+    /// Encrypt the file entries with this password
+    pub password: Option<String>,
+    /// No new files are started once this is set, and the run fails with a `Cancelled` error
+    pub cancel: CancelFlag,
+    /// Called with every progress event, for embedders rendering their own progress
+    pub progress: Option<ProgressCallback>,
+    /// Digest written next to the archive, and for every entry in the manifest
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Store the absolute path of every source file in the comment of its entry
+    pub record_source_path: bool,
+}
+
+/// Algorithm and optional level for the files with one extension, from `--method-for`
+#[derive(Clone, Debug)]
+pub struct MethodRule {
+    pub algorithm: String,
+    pub level: Option<i64>,
+}
+
+impl CompressionSettings {
+    // Rules are keyed on the extension of the original file, so converted `.bin` files still match
+    fn method_for(&self, original_path: &Path) -> (&str, i64) {
+        let extension = original_path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        match extension.and_then(|extension| self.method_rules.get(&extension)) {
+            Some(rule) => (&rule.algorithm, rule.level.unwrap_or(self.compression_level)),
+            None => (&self.compression_algorithm, self.compression_level),
+        }
+    }
+
+    /// Options for an entry read from `source_path`, which is `original_path` itself or its converted copy
+    pub fn file_options(&self, source_path: &Path, original_path: &Path, file_size: u64) -> io::Result<FileOptions<'_, ()>> {
+        let (algorithm, level) = self.method_for(original_path);
+        let (compression_method, valid_level) = if algorithm == "auto" {
+            match choose_method_for(source_path)? {
+                CompressionMethod::Stored => (CompressionMethod::Stored, None),
+                _ => get_compression_method("Zstd", level)?,
+            }
+        } else {
+            get_compression_method(algorithm, level)?
+        };
+        let valid_level = if algorithm == "Brotli" { None } else { valid_level };
+        Ok(self.aligned(self.encrypted(FileOptions::default()
+            .compression_method(compression_method)
+            .compression_level(valid_level)
+            .large_file(self.zip64.large_file(file_size))), compression_method, algorithm))
+    }
+
+    // FIFOs have no size up front and can't be probed without consuming them, so `auto` uses Zstd
+    // and zip64 headers are written unless they are disabled
+    fn stream_options(&self, original_path: &Path) -> io::Result<FileOptions<'_, ()>> {
+        let (algorithm, level) = self.method_for(original_path);
+        let algorithm = if algorithm == "auto" { "Zstd" } else { algorithm };
+        let (compression_method, valid_level) = get_compression_method(algorithm, level)?;
+        let valid_level = if algorithm == "Brotli" { None } else { valid_level };
+        Ok(self.aligned(self.encrypted(FileOptions::default()
+            .compression_method(compression_method)
+            .compression_level(valid_level)
+            .large_file(self.zip64 != Zip64Mode::Never)), compression_method, algorithm))
+    }
+
+    // The quality files are compressed with before being stored when their algorithm is Brotli
+    fn brotli_quality(&self, original_path: &Path) -> io::Result<Option<u32>> {
+        let (algorithm, level) = self.method_for(original_path);
+        if algorithm != "Brotli" {
+            return Ok(None);
+        }
+        Ok(get_compression_method(algorithm, level)?.1.map(|quality| quality as u32))
+    }
+
+    // The fallback always uses the zip crate, so it has no Brotli quality
+    fn fallback_options(&self, file_size: u64) -> io::Result<Option<(&str, FileOptions<'_, ()>)>> {
+        let Some(algorithm) = self.fallback_method.as_deref() else {
+            return Ok(None);
+        };
+        let (compression_method, valid_level) = if algorithm == "Stored" {
+            (CompressionMethod::Stored, None)
+        } else {
+            get_zip_compression_method(algorithm, self.compression_level)?
+        };
+        Ok(Some((algorithm, self.aligned(self.encrypted(FileOptions::default()
+            .compression_method(compression_method)
+            .compression_level(valid_level)
+            .large_file(self.zip64.large_file(file_size))), compression_method, algorithm))))
+    }
+
+    // Only the data of stored entries can be mapped straight out of the archive, so only they are padded
+    // to `--align`. Brotli and encrypted entries are stored too, but their bytes still need decoding.
+    fn aligned<'k>(&self, options: FileOptions<'k, ()>, compression_method: CompressionMethod, algorithm: &str) -> FileOptions<'k, ()> {
+        match self.align {
+            Some(align) if compression_method == CompressionMethod::Stored && algorithm != "Brotli" && self.password.is_none() => options.with_alignment(align),
+            _ => options,
+        }
+    }
+
+    // With a password the entries are encrypted with AES-256, which unlike ZipCrypto isn't trivially broken
+    fn encrypted<'k>(&'k self, options: FileOptions<'k, ()>) -> FileOptions<'k, ()> {
+        match &self.password {
+            Some(password) => options.with_aes_encryption(AesMode::Aes256, password),
+            None => options,
+        }
+    }
+}
+
+/// What is printed about the failed files once the run is done: every failure again with `--keep-going`,
+/// only their count with `--quiet-errors`, which doesn't print them as they happen
+pub fn failure_summary(failures: &[(PathBuf, String)], settings: &CompressionSettings) -> Option<String> {
+    if failures.is_empty() {
+        return None;
+    }
+    if settings.keep_going {
+        let lines: Vec<String> = failures.iter().map(|(path, error)| format!("  {:?}: {}", path, error)).collect();
+        return Some(format!("Failures:\n{}", lines.join("\n")));
+    }
+    settings.quiet_errors.then(|| format!("{} files failed", failures.len()))
+}
+
+/// What a compression run leaves to do once the archive is finished
+pub struct RunSummary {
+    /// The files that couldn't be added, with their error
+    pub failures: Vec<(PathBuf, String)>,
+    /// Entry names with the absolute path of their source file, for `--record-source-path`
+    pub source_paths: Vec<(String, PathBuf)>,
+    /// The files written, for the `--report` written once the archive is finished
+    pub report_rows: Vec<ReportRow>,
+}
+
+/// Adds files to the shared zip. The writer is shared by the worker threads of one compression run
+/// and keeps what has to be written once all files are in, see `finish`.
+pub struct EntryWriter<'a> {
+    zip: &'a Mutex<ZipWriter<File>>,
+    settings: &'a CompressionSettings,
+    chunked_files: Mutex<Vec<ChunkedFile>>,
+    sparse_files: Mutex<Vec<SparseFile>>,
+    brotli_files: Mutex<Vec<String>>,
+    progress: Progress<'a>,
+    deadline: Option<Instant>,
+    skipped_files: AtomicUsize,
+    started_files: AtomicUsize,
+    files_over_limit: AtomicUsize,
+    out_of_space: AtomicBool,
+    xattrs: Mutex<Vec<(String, Attributes)>>,
+    owners: Mutex<Vec<(String, Owner)>>,
+    creation_times: Mutex<Vec<(String, SystemTime)>>,
+    deduplicator: Option<Deduplicator>,
+    manifest_entries: Mutex<Vec<ManifestEntry>>,
+    failures: Mutex<Vec<(PathBuf, String)>>,
+    source_paths: Mutex<Vec<(String, PathBuf)>>,
+    report_rows: Mutex<Vec<ReportRow>>,
+    stats: CompressionStats,
+    start: Instant,
+}
+
+impl<'a> EntryWriter<'a> {
+    pub fn new(zip: &'a Mutex<ZipWriter<File>>, settings: &'a CompressionSettings, total_files: usize) -> Self {
+        EntryWriter {
+            zip,
+            settings,
+            chunked_files: Mutex::new(Vec::new()),
+            sparse_files: Mutex::new(Vec::new()),
+            brotli_files: Mutex::new(Vec::new()),
+            progress: Progress::new(total_files, settings.progress_interval, settings.progress.as_ref()),
+            deadline: settings.time_limit.map(|limit| Instant::now() + limit),
+            skipped_files: AtomicUsize::new(0),
+            started_files: AtomicUsize::new(0),
+            files_over_limit: AtomicUsize::new(0),
+            out_of_space: AtomicBool::new(false),
+            xattrs: Mutex::new(Vec::new()),
+            owners: Mutex::new(Vec::new()),
+            creation_times: Mutex::new(Vec::new()),
+            deduplicator: settings.dedup.then(Deduplicator::default),
+            manifest_entries: Mutex::new(Vec::new()),
+            failures: Mutex::new(Vec::new()),
+            source_paths: Mutex::new(Vec::new()),
+            report_rows: Mutex::new(Vec::new()),
+            stats: CompressionStats::default(),
+            start: Instant::now(),
+        }
+    }
+
+    // Once the time limit has passed no new files are started, files already being added still finish.
+    // Callers with work to do before `write`, like converting the file, check this first.
+    pub fn deadline_passed(&self) -> bool {
+        let passed = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if passed {
+            self.skipped_files.fetch_add(1, Ordering::Relaxed);
+        }
+        passed
+    }
+
+    // Counts the files of the entry loops against `--max-files`, embedded files aren't counted.
+    // Files are counted when they are started, so files failing afterwards count as well.
+    pub fn limit_reached(&self) -> bool {
+        let Some(max_files) = self.settings.max_files else {
+            return false;
+        };
+        let reached = self.started_files.fetch_add(1, Ordering::Relaxed) >= max_files;
+        if reached {
+            self.files_over_limit.fetch_add(1, Ordering::Relaxed);
+        }
+        reached
+    }
+
+    // Adds one file to the shared zip under the given entry name
+    pub fn write(&self, entry_name: &str, source_path: &Path) -> io::Result<()> {
+        self.write_converted(entry_name, source_path, source_path)
+    }
+
+    // Adds the converted copy of a file, the original path picks the `--method-for` rule.
+    // Once the disk is full the remaining files are skipped, `finish` then fails instead of finishing the archive.
+    pub fn write_converted(&self, entry_name: &str, source_path: &Path, original_path: &Path) -> io::Result<()> {
+        if self.out_of_space.load(Ordering::Relaxed) || self.deadline_passed() || is_cancelled(&self.settings.cancel) {
+            return Ok(());
+        }
+        let written = self.write_entry(entry_name, source_path, original_path);
+        if written.as_ref().is_err_and(|e| e.kind() == io::ErrorKind::StorageFull) {
+            self.out_of_space.store(true, Ordering::Relaxed);
+        }
+        written
+    }
+
+    fn write_entry(&self, entry_name: &str, source_path: &Path, original_path: &Path) -> io::Result<()> {
+        // Made absolute before anything is written, so a path that can't be resolved fails the file
+        let absolute_path = self.settings.record_source_path.then(|| std::path::absolute(original_path)).transpose()?;
+        let opening = Instant::now();
+        let mut file = File::open(source_path)?;
+        let metadata = file.metadata()?;
+        let file_size = metadata.len();
+        self.stats.add_opening(opening.elapsed());
+
+        if !metadata.is_file() {
+            self.write_stream(entry_name, original_path, &mut file)?;
+            self.record_metadata(entry_name, original_path, absolute_path);
+            return Ok(());
+        }
+
+        let hashes = self.write_file(entry_name, source_path, original_path, &mut file, file_size)?;
+        self.record_metadata(entry_name, original_path, absolute_path);
+        if self.settings.manifest.is_some() {
+            let hashes = match hashes {
+                Some(hashes) => hashes,
+                None => hash_entry_file(source_path, self.settings.checksum_algorithm)?,
+            };
+            self.manifest_entries.lock().unwrap().push(ManifestEntry {
+                name: entry_name.to_owned(),
+                original_path: original_path.to_path_buf(),
+                size: file_size,
+                modified: metadata.modified().ok(),
+                crc32: hashes.crc32,
+                digest: self.settings.checksum_algorithm.map(ChecksumAlgorithm::name).zip(hashes.digest),
+            });
+        }
+        self.progress.record(entry_name, file_size);
+        self.record_report_row(entry_name, original_path, opening);
+        Ok(())
+    }
+
+    // The metadata kept with each entry is only recorded once the entry is in the archive,
+    // so files failing to open or copy don't leave records for entries that don't exist
+    fn record_metadata(&self, entry_name: &str, original_path: &Path, absolute_path: Option<PathBuf>) {
+        if self.settings.xattrs {
+            let attributes = read_xattrs(original_path);
+            if !attributes.is_empty() {
+                self.xattrs.lock().unwrap().push((entry_name.to_owned(), attributes));
+            }
+        }
+        if self.settings.preserve_owner {
+            if let Some(owner) = read_owner(original_path) {
+                self.owners.lock().unwrap().push((entry_name.to_owned(), owner));
+            }
+        }
+        if self.settings.preserve_ctime {
+            if let Some(created) = read_creation_time(original_path) {
+                self.creation_times.lock().unwrap().push((entry_name.to_owned(), created));
+            }
+        }
+        if let Some(absolute_path) = absolute_path {
+            self.source_paths.lock().unwrap().push((entry_name.to_owned(), absolute_path));
+        }
+    }
+
+    // Streams without a size up front, such as FIFOs or stdin, are copied into the entry as they are read
+    pub fn write_stream(&self, entry_name: &str, original_path: &Path, reader: &mut impl Read) -> io::Result<()> {
+        let start = Instant::now();
+        let options = self.settings.stream_options(original_path)?;
+        let brotli_quality = self.settings.brotli_quality(original_path)?;
+        let mut zip_guard = self.lock_zip();
+        zip_guard.start_file(entry_name, options)?;
+        let written = self.copy_entry(entry_name, reader, &mut zip_guard, brotli_quality)?;
+        self.progress.record(entry_name, written);
+        self.record_report_row(entry_name, original_path, start);
+        Ok(())
+    }
+
+    fn record_report_row(&self, entry_name: &str, original_path: &Path, start: Instant) {
+        if self.settings.report.is_some() {
+            self.report_rows.lock().unwrap().push(ReportRow { name: entry_name.to_owned(), original_path: original_path.to_path_buf(), duration: start.elapsed() });
+        }
+    }
+
+    // With a manifest the entry is hashed while it is compressed, the hashes are None when the file
+    // isn't copied in one stream, as for duplicates and split files
+    fn write_file(&self, entry_name: &str, source_path: &Path, original_path: &Path, file: &mut File, file_size: u64) -> io::Result<Option<EntryHashes>> {
+        if let Some(deduplicator) = &self.deduplicator {
+            if let Some(canonical) = deduplicator.canonical_entry(entry_name, source_path)? {
+                // Duplicates are stored as empty marker entries, filled from the canonical entry on extraction.
+                // They get the options of any other entry, so they are encrypted with the rest of the archive.
+                println!("Storing {} as a duplicate of {}", entry_name, canonical);
+                let options = self.settings.file_options(source_path, original_path, 0)?;
+                self.lock_zip().start_file(entry_name, options)?;
+                return Ok(None);
+            }
+        }
+
+        // Brotli files aren't stored sparse or split, the whole file goes through one compressor
+        let brotli_quality = self.settings.brotli_quality(original_path)?;
+        if self.settings.sparse && brotli_quality.is_none() {
+            if let Some(segments) = data_segments(file, file_size)? {
+                self.write_sparse(entry_name, source_path, original_path, file, file_size, segments)?;
+                return Ok(None);
+            }
+        }
+
+        match self.settings.chunk_large_files {
+            Some(chunk_size) if file_size > chunk_size && brotli_quality.is_none() => {
+                self.write_chunked(entry_name, source_path, original_path, file_size, chunk_size)?;
+                Ok(None)
+            }
+            _ => {
+                let options = self.settings.file_options(source_path, original_path, file_size)?;
+                let mut zip_guard = self.lock_zip();
+                self.write_with_fallback(entry_name, file, &mut zip_guard, options, brotli_quality, file_size)
+            }
+        }
+    }
+
+    // An entry that fails is written again from the start of the reader with `--fallback-method`
+    fn write_with_fallback(&self, entry_name: &str, reader: &mut (impl Read + Seek), zip: &mut ZipWriter<File>, options: FileOptions<'_, ()>, brotli_quality: Option<u32>, file_size: u64) -> io::Result<Option<EntryHashes>> {
+        match self.write_whole_file(entry_name, reader, zip, options, brotli_quality) {
+            Err(e) => match self.settings.fallback_options(file_size)? {
+                Some((algorithm, fallback)) => {
+                    println!("Compressing {} failed, {:?}, retrying with {}", entry_name, e, algorithm);
+                    reader.rewind()?;
+                    self.write_whole_file(entry_name, reader, zip, fallback, None)
+                }
+                None => Err(e),
+            },
+            written => written,
+        }
+    }
+
+    // A failed copy leaves no partial entry behind, so the entry can be written again. A failed
+    // `start_file` is undone by zip itself, aborting it here would drop the entry written before.
+    fn write_whole_file(&self, entry_name: &str, file: &mut impl Read, zip: &mut ZipWriter<File>, options: FileOptions<'_, ()>, brotli_quality: Option<u32>) -> io::Result<Option<EntryHashes>> {
+        zip.start_file(entry_name, options)?;
+        let copied = if self.settings.manifest.is_none() {
+            self.copy_entry(entry_name, file, zip, brotli_quality).map(|_| None)
+        } else {
+            let mut reader = HashingReader::new(file, self.settings.checksum_algorithm);
+            self.copy_entry(entry_name, &mut reader, zip, brotli_quality).map(|_| Some(reader.finish()))
+        };
+        if copied.is_err() {
+            zip.abort_file()?;
+            self.brotli_files.lock().unwrap().retain(|name| name != entry_name);
+        }
+        copied
+    }
+
+    fn lock_zip(&self) -> MutexGuard<'_, ZipWriter<File>> {
+        let waiting = Instant::now();
+        let zip_guard = self.zip.lock().unwrap();
+        self.stats.add_lock_waiting(waiting.elapsed());
+        zip_guard
+    }
+
+    fn timed_copy(&self, file: &mut impl Read, zip: &mut ZipWriter<File>) -> io::Result<u64> {
+        let copying = Instant::now();
+        let mut reader = TimedReader::new(file);
+        let written = match self.settings.flush_interval.filter(|&interval| interval > 0) {
+            Some(interval) => {
+                let mut written = 0;
+                loop {
+                    let copied = io::copy(&mut (&mut reader).take(interval), zip)?;
+                    zip.flush()?;
+                    written += copied;
+                    if copied < interval {
+                        break written;
+                    }
+                }
+            }
+            None => io::copy(&mut reader, zip)?,
+        };
+        self.stats.add_copy(copying.elapsed(), reader.elapsed);
+        Ok(written)
+    }
+
+    // Brotli isn't a zip method, so with it the bytes are compressed here into the stored entry,
+    // which is listed in the Brotli manifest written by `finish`
+    fn copy_entry(&self, entry_name: &str, reader: &mut impl Read, zip: &mut ZipWriter<File>, brotli_quality: Option<u32>) -> io::Result<u64> {
+        if brotli_quality.is_some() {
+            self.brotli_files.lock().unwrap().push(entry_name.to_owned());
+        }
+        match brotli_quality {
+            #[cfg(feature = "brotli")]
+            Some(quality) => {
+                let copying = Instant::now();
+                let mut reader = TimedReader::new(reader);
+                let read = compress_into(&mut reader, zip, quality)?;
+                self.stats.add_copy(copying.elapsed(), reader.elapsed);
+                Ok(read)
+            }
+            _ => self.timed_copy(reader, zip),
+        }
+    }
+
+    // The data segments are stored back to back, the manifest written by `finish` holds their offsets
+    fn write_sparse(&self, entry_name: &str, source_path: &Path, original_path: &Path, file: &mut File, file_size: u64, segments: Vec<(u64, u64)>) -> io::Result<()> {
+        let options = self.settings.file_options(source_path, original_path, file_size)?;
+        let mut zip_guard = self.lock_zip();
+        zip_guard.start_file(entry_name, options)?;
+        for &(offset, length) in &segments {
+            file.seek(SeekFrom::Start(offset))?;
+            self.timed_copy(&mut (&mut *file).take(length), &mut zip_guard)?;
+        }
+        drop(zip_guard);
+
+        let data_size: u64 = segments.iter().map(|(_, length)| length).sum();
+        println!("Stored {} of {} bytes of sparse file {}", data_size, file_size, entry_name);
+        self.sparse_files.lock().unwrap().push(SparseFile { name: entry_name.to_owned(), size: file_size, segments });
+        Ok(())
+    }
+
+    fn write_chunked(&self, entry_name: &str, source_path: &Path, original_path: &Path, file_size: u64, chunk_size: u64) -> io::Result<()> {
+        let parts = file_size.div_ceil(chunk_size);
+        let options = self.settings.file_options(source_path, original_path, chunk_size)?;
+        let temp_paths: Vec<PathBuf> = (0..parts).map(|part| part_temp_path(&self.settings.temp_dir, entry_name, part)).collect();
+        // The parts are only copied into the archive once all of them compressed, a failed part leaves no entries behind
+        let result = temp_paths.par_iter().enumerate()
+            .try_for_each(|(part, temp_path)| compress_part(source_path, entry_name, part as u64, chunk_size, options, temp_path))
+            .and_then(|()| temp_paths.iter().try_for_each(|temp_path| {
+                let mut compressed = ZipArchive::new(File::open(temp_path)?)?;
+                self.zip.lock().unwrap().raw_copy_file(compressed.by_index_raw(0)?)?;
+                Ok(())
+            }));
+        for temp_path in &temp_paths {
+            let _ = std::fs::remove_file(temp_path);
+        }
+        result?;
+
+        println!("Split {} into {} parts", entry_name, parts);
+        self.chunked_files.lock().unwrap().push(ChunkedFile { name: entry_name.to_owned(), size: file_size, parts });
+        Ok(())
+    }
+
+    // Failed files are printed as they happen unless `--quiet-errors` is set, and listed again together
+    // by `finish` with `--keep-going`
+    pub fn record_failure(&self, path: &Path, error: String) {
+        if !self.settings.quiet_errors {
+            println!("{}", error);
+        }
+        self.failures.lock().unwrap().push((path.to_path_buf(), error));
+    }
+
+    // Writes the entries describing the whole run, after every file has been added.
+    // Fails without writing them when the disk ran out of space, as the archive can't be finished.
+    pub fn finish(self) -> io::Result<RunSummary> {
+        check_cancelled(&self.settings.cancel)?;
+        if self.out_of_space.load(Ordering::Relaxed) {
+            return Err(io::Error::from(io::ErrorKind::StorageFull));
+        }
+        // Embedded files are written past the `--time-limit` deadline too, a run cut short still has its LICENSE
+        for (path, entry_name) in &self.settings.embeds {
+            println!("Embedding {:?} as {}", path, entry_name);
+            if let Err(e) = self.write_entry(entry_name, path, path) {
+                self.record_failure(path, format!("Error embedding file: {:?}, {:?}", path, e));
+            }
+        }
+        if self.settings.verbose {
+            self.stats.print(self.start.elapsed());
+        }
+        let failures = self.failures.into_inner().unwrap();
+        if let Some(summary) = failure_summary(&failures, self.settings) {
+            println!("{}", summary);
+        }
+        let skipped_files = self.skipped_files.into_inner();
+        if skipped_files > 0 {
+            println!("Time limit reached, skipped {} files", skipped_files);
+        }
+        let files_over_limit = self.files_over_limit.into_inner();
+        if files_over_limit > 0 {
+            let max_files = self.settings.max_files.unwrap_or_default();
+            if self.settings.max_files_action == MaxFilesAction::Error {
+                return Err(io::Error::other(format!("More than {} files to compress, {} over the --max-files limit", max_files, files_over_limit)));
+            }
+            println!("File limit of {} reached, skipped {} files", max_files, files_over_limit);
+        }
+        let chunked_files = self.chunked_files.into_inner().unwrap();
+        if !chunked_files.is_empty() {
+            let mut zip_guard = self.zip.lock().unwrap();
+            zip_guard.start_file(CHUNK_MANIFEST_NAME, FileOptions::<()>::default())?;
+            zip_guard.write_all(manifest_json(&chunked_files).as_bytes())?;
+        }
+        let brotli_files = self.brotli_files.into_inner().unwrap();
+        if !brotli_files.is_empty() {
+            let mut zip_guard = self.zip.lock().unwrap();
+            zip_guard.start_file(BROTLI_MANIFEST_NAME, FileOptions::<()>::default())?;
+            zip_guard.write_all(brotli_manifest_json(&brotli_files).as_bytes())?;
+        }
+        let sparse_files = self.sparse_files.into_inner().unwrap();
+        if !sparse_files.is_empty() {
+            let mut zip_guard = self.zip.lock().unwrap();
+            zip_guard.start_file(SPARSE_MANIFEST_NAME, FileOptions::<()>::default())?;
+            zip_guard.write_all(sparse_manifest_json(&sparse_files).as_bytes())?;
+        }
+        if let Some(deduplicator) = self.deduplicator {
+            let duplicates = deduplicator.into_duplicates();
+            if !duplicates.is_empty() {
+                let mut zip_guard = self.zip.lock().unwrap();
+                zip_guard.start_file(DEDUP_MANIFEST_NAME, FileOptions::<()>::default())?;
+                zip_guard.write_all(dedup_manifest_json(&duplicates).as_bytes())?;
+            }
+        }
+        if let Some(manifest) = &self.settings.manifest {
+            write_manifest(manifest, &self.manifest_entries.into_inner().unwrap())?;
+        }
+        let xattrs = self.xattrs.into_inner().unwrap();
+        if !xattrs.is_empty() {
+            let mut zip_guard = self.zip.lock().unwrap();
+            zip_guard.start_file(XATTRS_NAME, FileOptions::<()>::default())?;
+            zip_guard.write_all(xattrs_json(&xattrs).as_bytes())?;
+        }
+        let owners = self.owners.into_inner().unwrap();
+        if !owners.is_empty() {
+            let mut zip_guard = self.zip.lock().unwrap();
+            zip_guard.start_file(OWNERSHIP_NAME, FileOptions::<()>::default())?;
+            zip_guard.write_all(ownership_json(&owners).as_bytes())?;
+        }
+        let creation_times = self.creation_times.into_inner().unwrap();
+        if !creation_times.is_empty() {
+            let mut zip_guard = self.zip.lock().unwrap();
+            zip_guard.start_file(CREATION_TIMES_NAME, FileOptions::<()>::default())?;
+            zip_guard.write_all(creation_times_json(&creation_times).as_bytes())?;
+        }
+        self.progress.finish();
+        Ok(RunSummary { failures, source_paths: self.source_paths.into_inner().unwrap(), report_rows: self.report_rows.into_inner().unwrap() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zip::ZipArchive;
+    use super::*;
+    use crate::test_support::{compress, compress_converted, entry_names, random_bytes, settings, write_files, TempDir};
+
+    // Fails its first read, the way a compressor fails part way through an entry
+    struct FailingOnce<R> {
+        inner: R,
+        failed: bool,
+    }
+
+    impl<R: Read> Read for FailingOnce<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(io::Error::other("injected failure"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl<R: Seek> Seek for FailingOnce<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    // Writes the first file through a reader failing once, then the others as usual
+    fn write_failing_first(settings: &CompressionSettings, input: &Path, names: &[&str]) -> io::Result<()> {
+        let zip = Mutex::new(ZipWriter::new(File::create(&settings.output_zip)?));
+        let writer = EntryWriter::new(&zip, settings, names.len());
+        let path = input.join(names[0]);
+        let mut reader = FailingOnce { inner: File::open(&path)?, failed: false };
+        let size = reader.inner.metadata()?.len();
+        let options = settings.file_options(&path, &path, size)?;
+        writer.write_with_fallback(names[0], &mut reader, &mut writer.lock_zip(), options, None, size)?;
+        for name in &names[1..] {
+            writer.write(name, &input.join(name))?;
+        }
+        writer.finish()?;
+        zip.into_inner().unwrap().finish()?;
+        Ok(())
+    }
+
+    // Writes the files one at a time through an entry writer, calling `between` after each one
+    fn write_one_by_one(settings: &CompressionSettings, input: &Path, names: &[&str], between: impl Fn(&EntryWriter)) -> io::Result<RunSummary> {
+        let zip = Mutex::new(ZipWriter::new(File::create(&settings.output_zip)?));
+        let writer = EntryWriter::new(&zip, settings, names.len());
+        for name in names {
+            writer.write(name, &input.join(name))?;
+            between(&writer);
+        }
+        let summary = writer.finish()?;
+        zip.into_inner().unwrap().finish()?;
+        Ok(summary)
+    }
+
+    // The extra field of the archive's first local file header
+    fn first_local_extra(zip_path: &Path) -> Vec<u8> {
+        let bytes = std::fs::read(zip_path).unwrap();
+        let name_len = u16::from_le_bytes([bytes[26], bytes[27]]) as usize;
+        let extra_len = u16::from_le_bytes([bytes[28], bytes[29]]) as usize;
+        bytes[30 + name_len..30 + name_len + extra_len].to_vec()
+    }
+
+    fn has_zip64_extra(extra: &[u8]) -> bool {
+        let mut rest = extra;
+        while rest.len() >= 4 {
+            let (id, len) = (u16::from_le_bytes([rest[0], rest[1]]), u16::from_le_bytes([rest[2], rest[3]]) as usize);
+            if id == 0x0001 {
+                return true;
+            }
+            rest = &rest[(4 + len).min(rest.len())..];
+        }
+        false
+    }
+
+    #[test]
+    fn forced_zip64_archive_opens() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"small file")]);
+        let zip_path = dir.join("zip64.zip");
+        compress(&dir.join("input"), &CompressionSettings { zip64: Zip64Mode::Always, ..settings(&zip_path) });
+        assert!(has_zip64_extra(&first_local_extra(&zip_path)));
+
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("a.txt").unwrap()).unwrap(), "small file");
+
+        compress(&dir.join("input"), &CompressionSettings { zip64: Zip64Mode::Auto, ..settings(&zip_path) });
+        assert!(!has_zip64_extra(&first_local_extra(&zip_path)));
+    }
+
+    #[test]
+    fn auto_stores_random_files_and_compresses_text() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("random.bin", &random_bytes(64 * 1024, 4)), ("text.txt", &b"compressible\n".repeat(1000))]);
+        let zip_path = dir.join("auto.zip");
+        compress(&dir.join("input"), &CompressionSettings { compression_algorithm: "auto".to_owned(), ..settings(&zip_path) });
+
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.by_name("random.bin").unwrap().compression(), CompressionMethod::Stored);
+        assert_eq!(archive.by_name("text.txt").unwrap().compression(), CompressionMethod::Zstd);
+    }
+
+    #[test]
+    fn files_after_the_time_limit_are_skipped() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        let zip_path = dir.join("limited.zip");
+        let settings = CompressionSettings { time_limit: Some(Duration::from_millis(200)), ..settings(&zip_path) };
+        // The first file is slow enough to use up the time limit
+        write_one_by_one(&settings, &dir.join("input"), &["a.txt", "b.txt", "c.txt"], |_| std::thread::sleep(Duration::from_millis(300))).unwrap();
+
+        assert_eq!(entry_names(&zip_path), ["a.txt"]);
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("a.txt").unwrap()).unwrap(), "a");
+    }
+
+    #[test]
+    fn method_rule_applies_to_converted_files() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("notes.txt", &b"notes\n".repeat(100)), ("data.json", b"{}")]);
+        let zip_path = dir.join("converted.zip");
+        let rule = MethodRule { algorithm: "Bzip2".to_owned(), level: Some(9) };
+        compress_converted(&dir.join("input"), &CompressionSettings { method_rules: HashMap::from([("txt".to_owned(), rule)]), ..settings(&zip_path) });
+
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.by_name("notes.txt.bin").unwrap().compression(), CompressionMethod::Bzip2);
+        assert_eq!(archive.by_name("data.json").unwrap().compression(), CompressionMethod::Zstd);
+    }
+
+    // Writes `a.txt` and `unreadable.txt`, a directory that opens but can't be read as a file whoever
+    // runs the test, recording the failure like the compression loops do
+    fn write_with_unreadable_file(settings: &CompressionSettings, input: &Path) -> RunSummary {
+        let zip = Mutex::new(ZipWriter::new(File::create(&settings.output_zip).unwrap()));
+        let writer = EntryWriter::new(&zip, settings, 2);
+        for name in ["a.txt", "unreadable.txt"] {
+            let path = input.join(name);
+            if let Err(e) = writer.write(name, &path) {
+                writer.record_failure(&path, format!("Error adding file to zip: {}, {:?}", name, e));
+            }
+        }
+        let summary = writer.finish().unwrap();
+        zip.into_inner().unwrap().finish().unwrap();
+        summary
+    }
+
+    // The entry names listed in one of the JSON metadata entries, such as the extended attributes
+    fn metadata_entry_names(zip_path: &Path, metadata_name: &str) -> Vec<String> {
+        let mut archive = ZipArchive::new(File::open(zip_path).unwrap()).unwrap();
+        let stored: serde_json::Value = serde_json::from_reader(archive.by_name(metadata_name).unwrap()).unwrap();
+        stored["files"].as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn unreadable_file_is_listed_in_the_summary() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"a")]);
+        std::fs::create_dir(input.join("unreadable.txt")).unwrap();
+        let settings = CompressionSettings { keep_going: true, ..settings(&dir.join("out.zip")) };
+        let summary = write_with_unreadable_file(&settings, &input);
+
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].0, input.join("unreadable.txt"));
+        assert!(summary.failures[0].1.contains("unreadable.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_file_leaves_no_metadata() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        let zip_path = dir.join("out.zip");
+        let settings = CompressionSettings { keep_going: true, xattrs: true, preserve_owner: true, preserve_ctime: true, record_source_path: true, ..settings(&zip_path) };
+        write_files(&input, &[("a.txt", b"a")]);
+        std::fs::create_dir(input.join("unreadable.txt")).unwrap();
+        let tagged = ["a.txt", "unreadable.txt"].iter().all(|name| xattr::set(input.join(name), "user.rust_zip.test", b"tag").is_ok());
+        let summary = write_with_unreadable_file(&settings, &input);
+
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.source_paths, [("a.txt".to_owned(), input.join("a.txt"))]);
+        assert_eq!(metadata_entry_names(&zip_path, OWNERSHIP_NAME), ["a.txt"]);
+        // Temp directories without extended attributes or creation times have nothing of them to leave out
+        if tagged {
+            assert_eq!(metadata_entry_names(&zip_path, XATTRS_NAME), ["a.txt"]);
+        }
+        if read_creation_time(&input.join("a.txt")).is_some() {
+            assert_eq!(metadata_entry_names(&zip_path, CREATION_TIMES_NAME), ["a.txt"]);
+        }
+    }
+
+    #[test]
+    fn timing_breakdown_counts_every_file_within_the_elapsed_time() {
+        let dir = TempDir::new();
+        let files: Vec<(String, Vec<u8>)> = (0..4u64).map(|i| (format!("file_{}.bin", i), random_bytes(1 << 20, i))).collect();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(name, bytes)| (name.as_str(), bytes.as_slice())).collect();
+        write_files(&dir.join("input"), &files);
+        let settings = CompressionSettings { compression_level: 10, ..settings(&dir.join("out.zip")) };
+
+        let zip = Mutex::new(ZipWriter::new(File::create(&settings.output_zip).unwrap()));
+        let writer = EntryWriter::new(&zip, &settings, files.len());
+        let start = Instant::now();
+        // One file at a time, so the times summed over the threads can't add up to more than the elapsed time
+        for (name, _) in &files {
+            writer.write(name, &dir.join("input").join(name)).unwrap();
+        }
+        let elapsed = start.elapsed();
+        // Every file is counted once in each step, the timed steps don't overlap so they stay within the elapsed time.
+        // Compressing 4 MiB at level 10 is most of the run, so the steps cover at least half of it.
+        assert_eq!(writer.stats.counts(), (4, 4));
+        let steps = writer.stats.total();
+        assert!(steps <= elapsed, "{:?} of {:?} in the steps", steps, elapsed);
+        assert!(steps * 2 >= elapsed, "only {:?} of {:?} in the steps", steps, elapsed);
+    }
+
+    #[test]
+    fn embedded_files_are_added_after_the_folder() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("nested/b.txt", b"b")]);
+        write_files(&dir.join("dist"), &[("LICENSE-MIT", b"license text")]);
+        let settings = CompressionSettings {
+            embeds: vec![(dir.join("dist/LICENSE-MIT"), "LICENSE".to_owned())],
+            ..settings(&dir.join("out.zip"))
+        };
+        compress(&dir.join("input"), &settings);
+
+        let names = entry_names(&dir.join("out.zip"));
+        assert_eq!(names.len(), 3);
+        assert_eq!(names.last().unwrap(), "LICENSE");
+        let mut archive = ZipArchive::new(File::open(dir.join("out.zip")).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("LICENSE").unwrap()).unwrap(), "license text");
+    }
+
+    #[test]
+    fn embedded_files_are_added_after_the_time_limit() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("b.txt", b"b")]);
+        write_files(&dir.join("dist"), &[("LICENSE-MIT", b"license text")]);
+        let zip_path = dir.join("limited.zip");
+        let settings = CompressionSettings {
+            embeds: vec![(dir.join("dist/LICENSE-MIT"), "LICENSE".to_owned())],
+            time_limit: Some(Duration::from_millis(200)),
+            ..settings(&zip_path)
+        };
+        write_one_by_one(&settings, &dir.join("input"), &["a.txt", "b.txt"], |_| std::thread::sleep(Duration::from_millis(300))).unwrap();
+
+        assert_eq!(entry_names(&zip_path), ["a.txt", "LICENSE"]);
+    }
+
+    #[test]
+    fn quiet_errors_only_report_the_count() {
+        let failures = vec![
+            (PathBuf::from("input/a.txt"), "Error adding file to zip: a.txt, denied".to_owned()),
+            (PathBuf::from("input/b.txt"), "Error adding file to zip: b.txt, denied".to_owned()),
+        ];
+        let quiet = CompressionSettings { quiet_errors: true, ..CompressionSettings::default() };
+        assert_eq!(failure_summary(&failures, &quiet).unwrap(), "2 files failed");
+        assert_eq!(failure_summary(&[], &quiet), None);
+        assert_eq!(failure_summary(&failures, &CompressionSettings::default()), None);
+
+        let keep_going = CompressionSettings { keep_going: true, ..quiet };
+        let summary = failure_summary(&failures, &keep_going).unwrap();
+        assert_eq!(summary.lines().collect::<Vec<_>>(), [
+            "Failures:",
+            "  \"input/a.txt\": Error adding file to zip: a.txt, denied",
+            "  \"input/b.txt\": Error adding file to zip: b.txt, denied",
+        ]);
+    }
+
+    #[test]
+    fn stored_entries_are_aligned() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[
+            ("a.bin", &random_bytes(65_537, 1)),
+            ("longer-name.bin", &random_bytes(70_001, 2)),
+            ("nested/c.bin", &random_bytes(66_077, 3)),
+            ("text.txt", &b"compressible\n".repeat(100)),
+        ]);
+        let zip_path = dir.join("aligned.zip");
+        compress(&dir.join("input"), &CompressionSettings { compression_algorithm: "auto".to_owned(), align: Some(4096), ..settings(&zip_path) });
+
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        for name in ["a.bin", "longer-name.bin", "nested/c.bin"] {
+            let entry = archive.by_name(name).unwrap();
+            assert_eq!(entry.compression(), CompressionMethod::Stored);
+            assert_eq!(entry.data_start() % 4096, 0, "{}", name);
+        }
+        assert_eq!(archive.by_name("text.txt").unwrap().compression(), CompressionMethod::Zstd);
+    }
+
+    #[test]
+    fn max_files_stops_or_fails_after_the_limit() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("1.txt", b"1"), ("2.txt", b"2"), ("3.txt", b"3"), ("4.txt", b"4"), ("5.txt", b"5")]);
+        let zip_path = dir.join("out.zip");
+        compress(&dir.join("input"), &CompressionSettings { max_files: Some(3), ..settings(&zip_path) });
+        assert_eq!(entry_names(&zip_path).len(), 3);
+
+        let settings = CompressionSettings { max_files: Some(3), max_files_action: MaxFilesAction::Error, ..settings(&zip_path) };
+        let Err(e) = crate::archive_output::compress_to_output(&dir.join("input"), &zip_path, None, &settings, Default::default()) else {
+            panic!("more files than --max-files were archived with the error action");
+        };
+        assert_eq!(e.to_string(), "More than 3 files to compress, 2 over the --max-files limit");
+    }
+
+    #[test]
+    fn failed_entry_is_stored_with_the_fallback_method() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        let text = "text ".repeat(100);
+        write_files(&input, &[("a.txt", text.as_bytes()), ("b.txt", text.as_bytes())]);
+        let settings = CompressionSettings { fallback_method: Some("Stored".to_owned()), ..settings(&dir.join("out.zip")) };
+
+        // Only the first try of a.txt fails, so b.txt is compressed as usual
+        write_failing_first(&settings, &input, &["a.txt", "b.txt"]).unwrap();
+        let mut archive = ZipArchive::new(File::open(&settings.output_zip).unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+        let a = archive.by_name("a.txt").unwrap();
+        assert_eq!(a.compression(), CompressionMethod::Stored);
+        assert_eq!(io::read_to_string(a).unwrap(), text);
+        assert_eq!(archive.by_name("b.txt").unwrap().compression(), CompressionMethod::Zstd);
+
+        // Without a fallback the failure is the entry's error
+        let settings = CompressionSettings { fallback_method: None, ..settings };
+        let Err(e) = write_failing_first(&settings, &input, &["a.txt"]) else {
+            panic!("the injected failure was not reported");
+        };
+        assert_eq!(e.to_string(), "injected failure");
+    }
+
+    #[test]
+    fn failed_start_keeps_the_entry_before() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("a.txt", b"first"), ("b.txt", b"second"), ("c.txt", b"third")]);
+        let settings = CompressionSettings { fallback_method: Some("Stored".to_owned()), ..settings(&dir.join("out.zip")) };
+
+        let zip = Mutex::new(ZipWriter::new(File::create(&settings.output_zip).unwrap()));
+        let writer = EntryWriter::new(&zip, &settings, 3);
+        writer.write("a.txt", &input.join("a.txt")).unwrap();
+        // A duplicate name fails in `start_file`, both with the method and with the fallback
+        let Err(e) = writer.write("a.txt", &input.join("b.txt")) else {
+            panic!("a duplicate entry name was written");
+        };
+        assert!(e.to_string().contains("Duplicate filename"), "{}", e);
+        writer.write("c.txt", &input.join("c.txt")).unwrap();
+        writer.finish().unwrap();
+        zip.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(entry_names(&settings.output_zip), ["a.txt", "c.txt"]);
+        let mut archive = ZipArchive::new(File::open(&settings.output_zip).unwrap()).unwrap();
+        assert_eq!(io::read_to_string(archive.by_name("a.txt").unwrap()).unwrap(), "first");
+        assert_eq!(io::read_to_string(archive.by_name("c.txt").unwrap()).unwrap(), "third");
+    }
+
+    #[test]
+    fn large_entries_are_intact_with_a_small_flush_interval() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        // One size ends part way through an interval, the other on its last byte
+        let (uneven, even) = (random_bytes(1_000_003, 3), b"flushed ".repeat(64 * 4096 / 8));
+        write_files(&input, &[("uneven.bin", &uneven), ("even.txt", &even)]);
+        for algorithm in ["Deflated", "Zstd"] {
+            let settings = CompressionSettings { compression_algorithm: algorithm.to_owned(), flush_interval: Some(4096), ..settings(&dir.join(format!("{}.zip", algorithm))) };
+            let summary = compress(&input, &settings);
+            assert!(summary.failures.is_empty());
+
+            let mut archive = ZipArchive::new(File::open(&settings.output_zip).unwrap()).unwrap();
+            for (name, bytes) in [("uneven.bin", &uneven), ("even.txt", &even)] {
+                let mut contents = Vec::new();
+                archive.by_name(name).unwrap().read_to_end(&mut contents).unwrap();
+                assert!(contents == *bytes, "{} differs with {}", name, algorithm);
+            }
+        }
+    }
+}
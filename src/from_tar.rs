@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use tar::{Archive, EntryType};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::collector::normalize_separators;
+use crate::utils::get_zip_compression_method;
+
+/// Repackages a tar archive as a zip, keeping entry names and unix modes.
+/// Directories and symlinks become zip directory and symlink entries, other special entries are skipped.
+/// With `no_dir_entries` directories are left out, their files' paths still recreate them on extraction.
+pub fn tar_to_zip(tar_path: &Path, output_zip_path: &Path, compression_algorithm: &str, compression_level: i64, no_dir_entries: bool) -> io::Result<()> {
+    let (compression_method, valid_level) = get_zip_compression_method(compression_algorithm, compression_level)?;
+    let mut archive = Archive::new(File::open(tar_path)?);
+    let mut zip = ZipWriter::new(File::create(output_zip_path)?);
+    let mut count = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Some(name) = path.to_str().map(normalize_separators) else {
+            println!("Skipping tar entry whose name isn't UTF-8: {}", path.to_string_lossy());
+            continue;
+        };
+        let header = entry.header();
+        let options: FileOptions<()> = FileOptions::default()
+            .compression_method(compression_method)
+            .compression_level(valid_level)
+            .unix_permissions(header.mode()?)
+            .large_file(header.size()? >= u32::MAX as u64);
+
+        match header.entry_type() {
+            EntryType::Directory if no_dir_entries => continue,
+            EntryType::Directory => zip.add_directory(name.as_str(), options)?,
+            EntryType::Symlink => {
+                let target = match entry.link_name()? {
+                    Some(target) => match target.to_str() {
+                        Some(target) => normalize_separators(target),
+                        None => {
+                            println!("Skipping symlink {} whose target isn't UTF-8: {}", name, target.to_string_lossy());
+                            continue;
+                        }
+                    },
+                    None => {
+                        println!("Skipping symlink without target: {}", name);
+                        continue;
+                    }
+                };
+                zip.add_symlink(name.as_str(), target, options)?;
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                zip.start_file(name.as_str(), options)?;
+                io::copy(&mut entry, &mut zip)?;
+            }
+            other => {
+                println!("Skipping unsupported tar entry {} of type {:?}", name, other);
+                continue;
+            }
+        }
+        count += 1;
+    }
+
+    zip.finish()?;
+    println!("Converted {} tar entries into {:?}", count, output_zip_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use zip::ZipArchive;
+    use super::*;
+    use crate::test_support::{entry_names, TempDir};
+
+    fn header(entry_type: EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(size);
+        header
+    }
+
+    // A tar with a directory, two files and a symlink to one of them
+    fn write_tar(tar_path: &Path) {
+        let mut builder = tar::Builder::new(File::create(tar_path).unwrap());
+        builder.append_data(&mut header(EntryType::Directory, 0o755, 0), "docs/", io::empty()).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o640, 5), "docs/a.txt", &b"alpha"[..]).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o600, 4), "b.txt", &b"beta"[..]).unwrap();
+        builder.append_link(&mut header(EntryType::Symlink, 0o777, 0), "link", "docs/a.txt").unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn tar_entries_become_the_same_zip_entries() {
+        let dir = TempDir::new();
+        write_tar(&dir.join("input.tar"));
+        let zip_path = dir.join("output.zip");
+        tar_to_zip(&dir.join("input.tar"), &zip_path, "Zstd", 3, false).unwrap();
+
+        assert_eq!(entry_names(&zip_path), ["docs/", "docs/a.txt", "b.txt", "link"]);
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        for (name, contents, mode) in [("docs/a.txt", "alpha", 0o640), ("b.txt", "beta", 0o600)] {
+            let mut file = archive.by_name(name).unwrap();
+            assert_eq!(file.unix_mode().unwrap() & 0o777, mode, "{}", name);
+            assert_eq!(io::read_to_string(&mut file).unwrap(), contents);
+        }
+        assert!(archive.by_name("docs/").unwrap().is_dir());
+        let mut link = archive.by_name("link").unwrap();
+        assert_eq!(link.unix_mode().unwrap() & 0o170000, 0o120000);
+        assert_eq!(io::read_to_string(&mut link).unwrap(), "docs/a.txt");
+    }
+
+    #[test]
+    fn directories_are_left_out_without_dir_entries() {
+        let dir = TempDir::new();
+        write_tar(&dir.join("input.tar"));
+        tar_to_zip(&dir.join("input.tar"), &dir.join("output.zip"), "Deflated", 6, true).unwrap();
+        assert_eq!(entry_names(&dir.join("output.zip")), ["docs/a.txt", "b.txt", "link"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_are_skipped() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new();
+        let tar_path = dir.join("input.tar");
+        let mut builder = tar::Builder::new(File::create(&tar_path).unwrap());
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 4), Path::new(std::ffi::OsStr::from_bytes(b"caf\xe9.txt")), &b"cafe"[..]).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 2), "ok.txt", &b"ok"[..]).unwrap();
+        builder.append_link(&mut header(EntryType::Symlink, 0o777, 0), "link", Path::new(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"))).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        tar_to_zip(&tar_path, &dir.join("output.zip"), "Deflated", 6, false).unwrap();
+        assert_eq!(entry_names(&dir.join("output.zip")), ["ok.txt"]);
+    }
+}
@@ -0,0 +1,48 @@
+//! Compression and extraction of zip archives with optional binary conversion, used by the `compressimagesvideosrust`
+//! command line and by programs embedding it, such as GUIs passing a cancel flag and progress callback.
+
+pub mod cli;
+pub mod compression;
+pub mod decompression;
+pub mod image_processing;
+pub mod utils;
+pub mod text_to_binary;
+pub mod compression_wo_conversion;
+pub mod decompression_wo_conversion;
+pub mod archive_edit;
+pub mod archive_info;
+pub mod archive_output;
+pub mod archive_diff;
+pub mod binary_naming;
+pub mod binary_converter;
+pub mod brotli_entries;
+pub mod cancel;
+pub mod entry_writer;
+pub mod central_directory;
+pub mod checksum;
+pub mod chunking;
+pub mod collector;
+pub mod color;
+pub mod config;
+pub mod copy_tree;
+pub mod dedup;
+#[cfg(feature = "dev")]
+pub mod corpus;
+#[cfg(feature = "tar")]
+pub mod from_tar;
+#[cfg(feature = "tar-zst")]
+pub mod tar_zst;
+pub mod target_ratio;
+pub mod manifest;
+pub mod ownership;
+pub mod creation_time;
+pub mod progress;
+pub mod report;
+pub mod self_test;
+pub mod sparse;
+pub mod stats;
+pub mod streaming;
+pub mod xattrs;
+
+#[cfg(test)]
+mod test_support;
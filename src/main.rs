@@ -1,86 +1,415 @@
-mod compression;
-mod decompression;
-mod image_processing;
-mod utils;
-mod text_to_binary;
-mod compression_wo_conversion;
-mod decompression_wo_conversion;
-
-use std::fs::File;
 use std::io::{self};
-use std::path::{Path};
-use std::sync::Mutex;
-use std::time::Instant;
-use zip::{ZipWriter};
+use std::path::Path;
+use clap::Parser;
 
-use crate::compression::add_files_to_zip;
-use crate::compression::FileType;
-use crate::decompression::decompress_and_convert_to_files;
-use crate::compression_wo_conversion::add_files_directly_to_zip;
+use compressimagesvideosrust::{archive_diff, archive_info, checksum, color, copy_tree, decompression_wo_conversion, self_test};
+#[cfg(feature = "dev")]
+use compressimagesvideosrust::corpus;
+#[cfg(feature = "tar")]
+use compressimagesvideosrust::from_tar;
+#[cfg(feature = "tar-zst")]
+use compressimagesvideosrust::tar_zst;
+use compressimagesvideosrust::archive_edit::{add_file_to_zip, recompress_zip, remove_entry_from_zip};
+use compressimagesvideosrust::archive_info::list_entries;
+use compressimagesvideosrust::cancel::cancel_on_ctrl_c;
+use compressimagesvideosrust::cli::{Args, Commands};
+use compressimagesvideosrust::decompression::decompress_and_convert_to_files;
+use compressimagesvideosrust::decompression_wo_conversion::ExtractOptions;
+use compressimagesvideosrust::entry_writer::CompressionSettings;
+use compressimagesvideosrust::archive_output::{compress_per_subfolder, compress_to_output, ensure_output_dir, OutputOptions};
+use compressimagesvideosrust::collector::{canonical_output_path, collect_files};
+use compressimagesvideosrust::config::{load_config, Config};
+use compressimagesvideosrust::utils::{print_algorithms, resolve_password, resolve_temp_dir};
+use compressimagesvideosrust::target_ratio::{estimate_output_size, level_for_target_ratio, tune};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let convert_to_binary = args.iter().any(|arg| arg == "--convert_to_binary");
-    let decompress_without_conversion = args.iter().any(|arg| arg == "--decompress_without_conversion");
+    let args = Args::parse();
+    color::init(args.color);
+
+    match args.command {
+        Commands::Compression {
+            input_folder,
+            output_zip,
+            compression_algorithm,
+            compression_level,
+            config,
+            threads,
+            no_lpt,
+            prefix,
+            strip_prefix,
+            strip_prefix_lenient,
+            method_for,
+            fallback_method,
+            embed,
+            convert_to_binary,
+            convert_types,
+            normalize_eol,
+            binary_naming,
+            on_unsupported,
+            target_ratio,
+            mut recursive,
+            max_depth,
+            follow_symlinks,
+            dereference_junctions,
+            exclude_from,
+            respect_gitignore,
+            stdin,
+            entry_name,
+            null,
+            include_special,
+            case_fold_entries,
+            include_root,
+            xattrs,
+            preserve_owner,
+            preserve_ctime,
+            dedup,
+            manifest,
+            report,
+            #[cfg(feature = "tar-zst")]
+            also_tar_zst,
+            record_source_path,
+            host_os,
+            zip64,
+            align,
+            chunk_large_files,
+            flush_interval,
+            sparse,
+            progress_interval,
+            time_limit,
+            max_files,
+            max_files_action,
+            mkdir,
+            temp_dir,
+            streaming,
+            no_atomic,
+            per_subfolder,
+            estimate,
+            dry_run,
+            keep_going,
+            quiet_errors,
+            password,
+            password_env,
+            password_stdin,
+            verbose,
+            checksum_algorithm,
+        } => {
+            let folder_path = input_folder.as_path();
+            let output_zip_path = output_zip.as_path();
+            let from_stdin = folder_path == Path::new("-");
 
-    match args.get(1).map(String::as_str) {
-        Some("compression") => {
-            if args.len() < 6 || args.len() > 7 {
-                println!("Usage for compression: cargo run -- compression <input_folder> <output_zip> <compression_algorithm> <compression_level> [--convert_to_binary]");
+            if from_stdin {
+                if stdin || password_stdin || target_ratio.is_some() || estimate {
+                    println!("{}: --stdin, --password-stdin, --target-ratio and --estimate can't be used when compressing stdin.", color::error());
+                    return Ok(());
+                }
+            } else if !folder_path.exists() || !folder_path.is_dir() {
+                println!("{}: Folder does not exist or is not a directory.", color::error());
                 return Ok(());
             }
-            let folder_path = Path::new(&args[2]);
-            let output_zip_path = &args[3];
-            let compression_algorithm = &args[4];
-            let compression_level = args[5].parse::<i64>().unwrap_or(3); // Default level to 3 if parsing fails
-
-            if !folder_path.exists() || !folder_path.is_dir() {
-                println!("Error: Folder does not exist or is not a directory.");
+            #[cfg(feature = "tar-zst")]
+            if also_tar_zst.is_some() && (from_stdin || stdin) {
+                println!("{}: --also-tar-zst can't read the files from stdin a second time.", color::error());
+                return Ok(());
+            }
+            if per_subfolder && from_stdin {
+                println!("{}: --per-subfolder needs an input folder, not stdin.", color::error());
+                return Ok(());
+            }
+            #[cfg(feature = "tar-zst")]
+            if per_subfolder && also_tar_zst.is_some() {
+                println!("{}: --also-tar-zst can't be used with --per-subfolder.", color::error());
                 return Ok(());
             }
 
-            let file = File::create(output_zip_path)?;
-            let zip = ZipWriter::new(file);
-            let zip_mutex = Mutex::new(zip);
-            let file_type = FileType::Other; // Adjust based on your needs
+            // Command line flags take precedence over the config file, which takes precedence over the defaults
+            let flags = Config { compression_algorithm, compression_level, recursive: recursive.then_some(true), threads, prefix, ..Config::default() };
+            let config = match &config {
+                Some(config_path) => load_config(config_path)?.overridden_by(flags),
+                None => flags,
+            };
+            let compression_algorithm = config.compression_algorithm.unwrap_or_else(|| "Zstd".to_owned());
+            let mut compression_level = config.compression_level.unwrap_or(3);
+            recursive = config.recursive.unwrap_or(false);
+            let prefix = config.prefix;
+            if let Some(threads) = config.threads {
+                rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+            }
+
+            if let Some(target_ratio) = target_ratio {
+                if compression_algorithm == "Zstd" {
+                    compression_level = level_for_target_ratio(folder_path, compression_level, target_ratio)?;
+                } else {
+                    println!("{} --target-ratio only applies to Zstd, ignoring it", color::warning());
+                }
+            }
 
-            println!("Creating zip file at {}", output_zip_path);
+            // With --per-subfolder the output zip path is the directory the archives are written to
+            let output_dir = if per_subfolder { Some(output_zip_path) } else { output_zip_path.parent() };
+            if let Some(output_dir) = output_dir {
+                match ensure_output_dir(output_dir, mkdir) {
+                    Err(e) if !mkdir => {
+                        println!("{}: {}", color::error(), e);
+                        return Ok(());
+                    }
+                    result => result?,
+                }
+            }
+
+            if max_depth.is_some() && !recursive {
+                println!("{} --max-depth only applies with --recursive, ignoring it", color::warning());
+            }
+            if normalize_eol.is_some() && !convert_to_binary && convert_types.is_empty() {
+                println!("{} --normalize-eol only applies with --convert-to-binary, ignoring it", color::warning());
+            }
             println!("Using compression algorithm: {}, level: {}", compression_algorithm, compression_level);
+            let mut settings = CompressionSettings {
+                output_zip: canonical_output_path(output_zip_path),
+                compression_algorithm,
+                compression_level,
+                method_rules: method_for.into_iter().collect(),
+                fallback_method,
+                recursive,
+                max_depth,
+                exclude_from,
+                respect_gitignore,
+                follow_symlinks,
+                dereference_junctions,
+                include_patterns: config.include,
+                exclude_patterns: config.exclude,
+                prefix,
+                strip_prefix,
+                strip_prefix_lenient,
+                paths_from_stdin: stdin,
+                null_separated: null,
+                include_special,
+                include_root,
+                xattrs,
+                preserve_owner,
+                preserve_ctime,
+                dedup,
+                manifest,
+                report,
+                #[cfg(feature = "tar-zst")]
+                also_tar_zst: also_tar_zst.as_deref().map(canonical_output_path),
+                zip64,
+                align,
+                binary_naming,
+                case_fold_entries,
+                convert_types: (!convert_types.is_empty()).then(|| convert_types.iter().copied().collect()),
+                normalize_eol,
+                on_unsupported,
+                chunk_large_files,
+                flush_interval,
+                sparse,
+                progress_interval,
+                keep_going,
+                quiet_errors,
+                time_limit,
+                max_files,
+                max_files_action,
+                verbose,
+                embeds: embed,
+                largest_first: !no_lpt,
+                temp_dir: resolve_temp_dir(temp_dir.as_deref())?,
+                password: resolve_password(password, password_env.as_deref(), password_stdin)?,
+                cancel: Some(cancel_on_ctrl_c()),
+                progress: None,
+                checksum_algorithm,
+                record_source_path,
+            };
 
-            if convert_to_binary {
-                println!("Converting files to binary and adding to zip...");
-                add_files_to_zip(&zip_mutex, folder_path, compression_algorithm, compression_level, file_type);
-            } else {
-                println!("Adding files directly to zip...");
-                // Standard compression without binary conversion
-                add_files_directly_to_zip(&zip_mutex, folder_path, compression_algorithm, compression_level);
+            if estimate {
+                if settings.paths_from_stdin {
+                    println!("{} --estimate can't read the paths from stdin twice, skipping the estimate", color::warning());
+                } else {
+                    estimate_output_size(&collect_files(folder_path, &settings)?, &settings)?;
+                }
+            }
+            if dry_run {
+                println!("Dry run, no archive written.");
+                return Ok(());
             }
 
-            let zip = zip_mutex.into_inner().unwrap();
-            zip.finish()?;
+            let output_options = OutputOptions { convert_to_binary, host_os, streaming, no_atomic };
+            let failed_files = if per_subfolder {
+                compress_per_subfolder(folder_path, output_zip_path, &mut settings, output_options)?
+            } else {
+                compress_to_output(folder_path, output_zip_path, from_stdin.then_some(entry_name.as_str()), &settings, output_options)?.failures.len()
+            };
+            #[cfg(feature = "tar-zst")]
+            if let Some(tar_zst_path) = &also_tar_zst {
+                tar_zst::write_tar_zst(folder_path, tar_zst_path, &settings)?;
+            }
+            if settings.keep_going && failed_files > 0 {
+                return Err(io::Error::other(format!("{} files failed to compress", failed_files)));
+            }
 
             println!("Compression completed successfully.");
         },
-        Some("decompression") => {
-            if args.len() < 4 || args.len() > 5 {
-                println!("Usage for decompression: cargo run -- decompression <zip_path> <output_folder> [--decompress_without_conversion]");
-                return Ok(());
+        Commands::Decompression {
+            zip_path,
+            output_folder,
+            into_subdir,
+            verify_checksum,
+            checksum_file,
+            test,
+            decompress_without_conversion,
+            safe,
+            on_duplicate,
+            resume,
+            update,
+            progress_interval,
+            xattrs,
+            preserve_owner,
+            preserve_ctime,
+            validate_json,
+            convert_only,
+            buffer_to_memory_threshold,
+            min_size,
+            max_size,
+            renames,
+            parallel,
+            case_fold_entries,
+            password,
+            password_env,
+            password_stdin,
+        } => {
+            let zip_path = zip_path.as_path();
+            let password = resolve_password(password, password_env.as_deref(), password_stdin)?;
+            let cancel = Some(cancel_on_ctrl_c());
+            if verify_checksum || checksum_file.is_some() {
+                checksum::verify_checksum_file(zip_path, checksum_file.as_deref())?;
+            }
+            if test {
+                return decompression_wo_conversion::test_archive(zip_path, password.as_deref(), &cancel, parallel);
+            }
+            let mut output_folder = output_folder.expect("clap requires the output folder without --test");
+            if into_subdir {
+                output_folder = decompression_wo_conversion::archive_subdir(&output_folder, zip_path);
             }
-            let zip_path = Path::new(&args[2]);
-            let output_folder = Path::new(&args[3]);
+            let output_folder = output_folder.as_path();
 
+            let options = ExtractOptions {
+                safe,
+                on_duplicate,
+                resume,
+                update,
+                progress_interval,
+                xattrs,
+                preserve_owner,
+                preserve_ctime,
+                validate_json,
+                convert_only: (!convert_only.is_empty()).then(|| convert_only.into_iter().collect()),
+                buffer_to_memory_threshold,
+                min_size,
+                max_size,
+                renames,
+                parallel,
+                case_fold_entries,
+                password,
+                cancel,
+                progress: None,
+            };
             if decompress_without_conversion {
                 println!("Decompressing without conversion...");
-                decompression_wo_conversion::decompress_files(zip_path, output_folder).await?;
+                decompression_wo_conversion::decompress_files(zip_path, output_folder, &options).await?;
                 println!("Decompressed file: {:?}", zip_path.file_name().unwrap());
             } else {
                 println!("Decompressing and converting files...");
-                decompress_and_convert_to_files(zip_path, output_folder).await?;
+                decompress_and_convert_to_files(zip_path, output_folder, &options).await?;
                 println!("Decompressed and converted file: {:?}", zip_path.file_name().unwrap());
             }
         },
-        _ => println!("Invalid mode. Please specify 'compression' or 'decompression'."),
+        Commands::Add { zip_path, file, compression_algorithm, compression_level, entry_name } => {
+            if !zip_path.is_file() {
+                println!("{}: Zip file does not exist.", color::error());
+                return Ok(());
+            }
+            add_file_to_zip(&zip_path, &file, &compression_algorithm, compression_level, entry_name.as_deref())?;
+        },
+        Commands::Remove { zip_path, entry_name } => {
+            if !zip_path.is_file() {
+                println!("{}: Zip file does not exist.", color::error());
+                return Ok(());
+            }
+            remove_entry_from_zip(&zip_path, &entry_name)?;
+        },
+        Commands::Recompress { input_zip, output_zip, compression_algorithm, compression_level, only } => {
+            if !input_zip.is_file() {
+                println!("{}: Zip file does not exist.", color::error());
+                return Ok(());
+            }
+            recompress_zip(&input_zip, &output_zip, &compression_algorithm, compression_level, &only)?;
+        },
+        Commands::Copy { input_folder, output_folder, config, recursive, max_depth, follow_symlinks, exclude_from, respect_gitignore, include_root, prefix } => {
+            if !input_folder.is_dir() {
+                println!("{}: Folder does not exist or is not a directory.", color::error());
+                return Ok(());
+            }
+            let config = match &config {
+                Some(config_path) => load_config(config_path)?,
+                None => Config::default(),
+            };
+            let settings = CompressionSettings {
+                recursive: recursive || config.recursive.unwrap_or(false),
+                max_depth,
+                follow_symlinks,
+                exclude_from,
+                respect_gitignore,
+                include_patterns: config.include,
+                exclude_patterns: config.exclude,
+                prefix: prefix.or(config.prefix),
+                include_root,
+                ..CompressionSettings::default()
+            };
+            let failed_files = copy_tree::copy_files(&input_folder, &output_folder, &settings)?;
+            if failed_files > 0 {
+                return Err(io::Error::other(format!("{} files failed to copy", failed_files)));
+            }
+        },
+        Commands::Diff { zip_path, folder, recursive, max_depth, exclude_from, respect_gitignore, include_root, prefix, strip_prefix, strip_prefix_lenient } => {
+            if !zip_path.is_file() {
+                println!("{}: Zip file does not exist.", color::error());
+                return Ok(());
+            }
+            if !folder.is_dir() {
+                println!("{}: Folder does not exist or is not a directory.", color::error());
+                return Ok(());
+            }
+            let settings = CompressionSettings { recursive, max_depth, exclude_from, respect_gitignore, include_root, prefix, strip_prefix, strip_prefix_lenient, ..CompressionSettings::default() };
+            archive_diff::print_diff(&archive_diff::diff_archive(&zip_path, &folder, &settings)?);
+        },
+        Commands::Tune { input_folder, compression_algorithm } => {
+            if !input_folder.is_dir() {
+                println!("{}: Folder does not exist or is not a directory.", color::error());
+                return Ok(());
+            }
+            tune(&input_folder, &compression_algorithm)?;
+        },
+        Commands::Probe { path } => archive_info::probe(&path)?,
+        Commands::Algorithms => print_algorithms(),
+        Commands::SelfTest => self_test::self_test().await?,
+        Commands::List { zip_path } => {
+            if !zip_path.is_file() {
+                println!("{}: Zip file does not exist.", color::error());
+                return Ok(());
+            }
+            list_entries(&zip_path)?;
+        },
+        #[cfg(feature = "tar")]
+        Commands::FromTar { input_tar, output_zip, compression_algorithm, compression_level, no_dir_entries } => {
+            if !input_tar.is_file() {
+                println!("{}: Tar file does not exist.", color::error());
+                return Ok(());
+            }
+            from_tar::tar_to_zip(&input_tar, &output_zip, &compression_algorithm, compression_level, no_dir_entries)?;
+        },
+        #[cfg(feature = "dev")]
+        Commands::GenCorpus { dir, files, avg_size, entropy, seed } => {
+            corpus::generate_corpus(&dir, files, avg_size, entropy, seed)?;
+        },
     }
     Ok(())
 }
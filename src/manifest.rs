@@ -0,0 +1,68 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+
+/// One archived file in the external `--manifest`
+pub struct ManifestEntry {
+    pub name: String,
+    pub original_path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub crc32: u32,
+    /// Digest of the bytes with the `--checksum-algorithm`, keyed on its name in the manifest
+    pub digest: Option<(String, String)>,
+}
+
+// Modification times are stored as seconds since the Unix epoch, null where the filesystem has none
+pub fn write_manifest(path: &Path, entries: &[ManifestEntry]) -> std::io::Result<()> {
+    let entries: Vec<Value> = entries.iter()
+        .map(|entry| {
+            let mut value = json!({
+                "name": entry.name,
+                "path": entry.original_path.to_string_lossy(),
+                "size": entry.size,
+                "mtime": entry.modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).map(|mtime| mtime.as_secs()),
+                "crc32": entry.crc32,
+            });
+            if let Some((algorithm, digest)) = &entry.digest {
+                value[algorithm.as_str()] = Value::String(digest.clone());
+            }
+            value
+        })
+        .collect();
+    std::fs::write(path, serde_json::to_string_pretty(&json!({ "entries": entries }))?)?;
+    println!("Wrote manifest of {} entries to {:?}", entries.len(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, settings, write_files, TempDir};
+
+    #[test]
+    fn manifest_entries_match_the_archive() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"first file"), ("nested/b.txt", b"second"), ("empty.txt", b"")]);
+        let output = dir.join("out.zip");
+        let settings = CompressionSettings { manifest: Some(dir.join("manifest.json")), ..settings(&output) };
+        compress(&dir.join("input"), &settings);
+
+        let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+        let mut listed: Vec<(String, u64, u32)> = manifest["entries"].as_array().unwrap().iter()
+            .map(|entry| (entry["name"].as_str().unwrap().to_owned(), entry["size"].as_u64().unwrap(), entry["crc32"].as_u64().unwrap() as u32))
+            .collect();
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut archived: Vec<(String, u64, u32)> = (0..archive.len())
+            .map(|i| {
+                let file = archive.by_index(i).unwrap();
+                (file.name().to_owned(), file.size(), file.crc32())
+            })
+            .collect();
+        listed.sort();
+        archived.sort();
+        assert_eq!(listed, archived);
+        assert!(manifest["entries"][0]["mtime"].is_u64());
+    }
+}
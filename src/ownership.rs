@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use serde_json::{json, Map, Value};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::color;
+
+/// Entry holding the uid and gid of the archived files, written with `--preserve-owner`
+pub const OWNERSHIP_NAME: &str = ".rust-zip-owners.json";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Only Unix files have a uid and gid, other systems archive their files without them
+pub fn read_owner(path: &Path) -> Option<Owner> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match std::fs::metadata(path) {
+            Ok(metadata) => Some(Owner { uid: metadata.uid(), gid: metadata.gid() }),
+            Err(e) => {
+                eprintln!("{} could not read the owner of {:?}: {}", color::warning(), path, e);
+                None
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+pub fn ownership_json(files: &[(String, Owner)]) -> String {
+    let mut entries = Map::new();
+    for (name, owner) in files {
+        entries.insert(name.clone(), json!({ "uid": owner.uid, "gid": owner.gid }));
+    }
+    json!({ "files": entries }).to_string()
+}
+
+/// Gives the extracted files the owners stored in the archive. Changing the owner usually needs root,
+/// so the first refused change prints a warning and the remaining files keep the extracting user as owner.
+/// Does nothing for archives written without `--preserve-owner`.
+pub fn restore_ownership(archive: &mut ZipArchive<File>, output_folder: &Path) -> io::Result<()> {
+    let mut entry = match archive.by_name(OWNERSHIP_NAME) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    let stored: Value = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid ownership entry: {}", e)))?;
+
+    let files = stored["files"].as_object().cloned().unwrap_or_default();
+    for (name, owner) in files {
+        if !Path::new(&name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in ownership entry: {}", name)));
+        }
+        let (Some(uid), Some(gid)) = (owner["uid"].as_u64(), owner["gid"].as_u64()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid owner of {}", name)));
+        };
+        let path = output_folder.join(&name);
+        if !path.is_file() {
+            continue;
+        }
+        #[cfg(unix)]
+        if let Err(e) = std::os::unix::fs::chown(&path, Some(uid as u32), Some(gid as u32)) {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                println!("{} not permitted to change file owners, run as root to restore them", color::warning());
+                return Ok(());
+            }
+            eprintln!("{} could not set the owner of {:?}: {}", color::warning(), path, e);
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (uid, gid);
+            println!("{} file owners can only be restored on Unix, skipping them", color::warning());
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use super::*;
+    use crate::decompression_wo_conversion::{decompress_files, ExtractOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, extract_options, settings, write_files, TempDir};
+
+    #[tokio::test]
+    async fn owners_round_trip_through_the_archive() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("nested/b.txt", b"b")]);
+        let zip_path = dir.join("out.zip");
+        compress(&dir.join("input"), &CompressionSettings { preserve_owner: true, ..settings(&zip_path) });
+
+        let source = std::fs::metadata(dir.join("input/nested/b.txt")).unwrap();
+        let mut archive = ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let stored: Value = serde_json::from_reader(archive.by_name(OWNERSHIP_NAME).unwrap()).unwrap();
+        assert_eq!(stored["files"]["nested/b.txt"], json!({ "uid": source.uid(), "gid": source.gid() }));
+        assert_eq!(stored["files"].as_object().unwrap().len(), 2);
+
+        // The files are the extracting user's own, so giving them their owner back is allowed without root
+        let options = ExtractOptions { preserve_owner: true, ..extract_options() };
+        decompress_files(&zip_path, &dir.join("output"), &options).await.unwrap();
+        let extracted = std::fs::metadata(dir.join("output/nested/b.txt")).unwrap();
+        assert_eq!((extracted.uid(), extracted.gid()), (source.uid(), source.gid()));
+        assert!(!dir.join("output").join(OWNERSHIP_NAME).exists());
+    }
+}
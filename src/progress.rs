@@ -0,0 +1,141 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What a progress callback is told about, for embedders that render their own progress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Started { total: usize },
+    FileDone { name: String, bytes: u64 },
+    Finished,
+}
+
+pub type ProgressCallback = Box<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Periodic "processed X/Y files" lines for runs without a terminal, such as CI logs.
+/// Lines are printed from the processing loops, at most once per interval.
+/// The optional callback gets every event as it happens, regardless of the interval.
+pub struct Progress<'a> {
+    total_files: usize,
+    processed_files: AtomicUsize,
+    processed_bytes: AtomicU64,
+    interval: Option<Duration>,
+    last_report: Mutex<Instant>,
+    callback: Option<&'a ProgressCallback>,
+}
+
+impl<'a> Progress<'a> {
+    // An interval of 0 disables the progress lines, as does running in a terminal
+    pub fn new(total_files: usize, interval_seconds: u64, callback: Option<&'a ProgressCallback>) -> Self {
+        let enabled = interval_seconds > 0 && !std::io::stdout().is_terminal();
+        Self::with_interval(total_files, enabled.then(|| Duration::from_secs(interval_seconds)), callback)
+    }
+
+    fn with_interval(total_files: usize, interval: Option<Duration>, callback: Option<&'a ProgressCallback>) -> Self {
+        if let Some(callback) = callback {
+            callback(ProgressEvent::Started { total: total_files });
+        }
+        Progress {
+            total_files,
+            processed_files: AtomicUsize::new(0),
+            processed_bytes: AtomicU64::new(0),
+            interval,
+            last_report: Mutex::new(Instant::now()),
+            callback,
+        }
+    }
+
+    pub fn record(&self, name: &str, bytes: u64) {
+        if let Some(line) = self.advance(name, bytes) {
+            println!("{}", line);
+        }
+    }
+
+    // Counts the file and returns the progress line when one is due
+    fn advance(&self, name: &str, bytes: u64) -> Option<String> {
+        if let Some(callback) = self.callback {
+            callback(ProgressEvent::FileDone { name: name.to_owned(), bytes });
+        }
+        let files = self.processed_files.fetch_add(1, Ordering::SeqCst) + 1;
+        let bytes = self.processed_bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+
+        let interval = self.interval?;
+        let mut last_report = self.last_report.lock().unwrap();
+        if last_report.elapsed() < interval {
+            return None;
+        }
+        *last_report = Instant::now();
+        Some(format!("Progress: processed {}/{} files, {:.1} MB", files, self.total_files, bytes as f64 / (1024.0 * 1024.0)))
+    }
+
+    pub fn finish(&self) {
+        if let Some(callback) = self.callback {
+            callback(ProgressEvent::Finished);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::decompression_wo_conversion::{decompress_files, ExtractOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, extract_options, settings, write_files, TempDir};
+
+    fn collect_events() -> (ProgressCallback, Arc<Mutex<Vec<ProgressEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collected = Arc::clone(&events);
+        (Box::new(move |event| collected.lock().unwrap().push(event)), events)
+    }
+
+    // Sorted by name, the files of a run are reported in the order the threads finish them
+    fn sorted(mut events: Vec<ProgressEvent>) -> Vec<ProgressEvent> {
+        events[1..3].sort_by_key(|event| match event {
+            ProgressEvent::FileDone { name, .. } => name.clone(),
+            _ => String::new(),
+        });
+        events
+    }
+
+    #[test]
+    fn progress_lines_are_printed_at_the_interval() {
+        let progress = Progress::with_interval(3, Some(Duration::ZERO), None);
+        let lines: Vec<Option<String>> = ["a.txt", "b.txt", "c.txt"].iter().map(|name| progress.advance(name, 512 * 1024)).collect();
+        assert_eq!(lines.last().unwrap().as_deref(), Some("Progress: processed 3/3 files, 1.5 MB"));
+        assert!(lines.iter().all(Option::is_some));
+
+        // Nothing is due before the interval has passed since the last line
+        let progress = Progress::with_interval(3, Some(Duration::from_secs(3600)), None);
+        assert!(["a.txt", "b.txt", "c.txt"].iter().all(|name| progress.advance(name, 1).is_none()));
+    }
+
+    #[test]
+    fn interval_of_zero_prints_no_progress_lines() {
+        let progress = Progress::new(2, 0, None);
+        assert!(progress.interval.is_none());
+        assert!(progress.advance("a.txt", 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn events_of_a_two_file_archive() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"first"), ("nested/b.txt", b"second file")]);
+        let expected = vec![
+            ProgressEvent::Started { total: 2 },
+            ProgressEvent::FileDone { name: "a.txt".to_owned(), bytes: 5 },
+            ProgressEvent::FileDone { name: "nested/b.txt".to_owned(), bytes: 11 },
+            ProgressEvent::Finished,
+        ];
+
+        let (progress, events) = collect_events();
+        let zip_path = dir.join("out.zip");
+        compress(&dir.join("input"), &CompressionSettings { progress: Some(progress), ..settings(&zip_path) });
+        assert_eq!(sorted(events.lock().unwrap().clone()), expected);
+
+        let (progress, events) = collect_events();
+        decompress_files(&zip_path, &dir.join("output"), &ExtractOptions { progress: Some(progress), ..extract_options() }).await.unwrap();
+        assert_eq!(sorted(events.lock().unwrap().clone()), expected);
+    }
+}
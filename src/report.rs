@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::archive_info::{open_archive, EntryInfo};
+use crate::chunking::part_name;
+
+/// One archived file in the `--report`, the sizes, method and CRC32 are read back from the finished archive
+pub struct ReportRow {
+    pub name: String,
+    pub original_path: PathBuf,
+    pub duration: Duration,
+}
+
+/// Writes the CSV report of the files added to the finished archive, one row per file in the order they were written.
+/// Files split by `--chunk-large-files` are reported with the sizes of their parts added up and no CRC32,
+/// since no entry holds the whole file.
+pub fn write_report(path: &Path, archive_path: &Path, rows: &[ReportRow]) -> io::Result<()> {
+    let entries = open_archive(archive_path)?;
+    let entries: HashMap<&str, &EntryInfo> = entries.iter().map(|entry| (entry.name.as_str(), entry)).collect();
+
+    let mut csv = String::from("name,original_path,uncompressed_size,compressed_size,method,crc32,duration_ms\n");
+    for row in rows {
+        let (uncompressed_size, compressed_size, method, crc32) = match entries.get(row.name.as_str()) {
+            Some(entry) => (entry.uncompressed_size, entry.compressed_size, entry.method.to_string(), format!("{:08x}", entry.crc32)),
+            None => {
+                let parts: Vec<&EntryInfo> = (0..).map_while(|part| entries.get(part_name(&row.name, part).as_str()).copied()).collect();
+                let method = parts.first().map(|part| part.method.to_string()).unwrap_or_default();
+                (parts.iter().map(|part| part.uncompressed_size).sum(), parts.iter().map(|part| part.compressed_size).sum(), method, String::new())
+            }
+        };
+        let fields = [
+            csv_field(&row.name),
+            csv_field(&row.original_path.to_string_lossy()),
+            uncompressed_size.to_string(),
+            compressed_size.to_string(),
+            method,
+            crc32,
+            row.duration.as_millis().to_string(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    std::fs::write(path, csv)?;
+    println!("Wrote report of {} files to {:?}", rows.len(), path);
+    Ok(())
+}
+
+// Fields with a comma, quote or line break are quoted, with their quotes doubled
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, random_bytes, settings, write_files, TempDir};
+
+    #[test]
+    fn report_has_one_row_per_archived_file() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        let large = random_bytes(100_000, 7);
+        write_files(&input, &[("a.txt", b"aaaa"), ("nested/b.txt", &[b'b'; 1000]), ("c.bin", &large)]);
+        let output = dir.join("out.zip");
+        let report = dir.join("report.csv");
+        compress(&input, &CompressionSettings { report: Some(report.clone()), ..settings(&output) });
+
+        let csv = std::fs::read_to_string(&report).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("name,original_path,uncompressed_size,compressed_size,method,crc32,duration_ms"));
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&output).unwrap()).unwrap();
+        let mut names = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').collect();
+            assert_eq!(fields.len(), 7, "{}", line);
+            let entry = archive.by_name(fields[0]).unwrap();
+            assert!(Path::new(fields[1]).ends_with(fields[0]));
+            assert_eq!(fields[2].parse::<u64>().unwrap(), std::fs::metadata(input.join(fields[0])).unwrap().len());
+            assert_eq!(fields[3].parse::<u64>().unwrap(), entry.compressed_size());
+            assert!(entry.compressed_size() > 0);
+            assert_eq!(fields[4], entry.compression().to_string());
+            assert_eq!(u32::from_str_radix(fields[5], 16).unwrap(), entry.crc32());
+            assert!(fields[6].parse::<u128>().is_ok());
+            names.push(fields[0].to_owned());
+        }
+        names.sort();
+        assert_eq!(names, ["a.txt", "c.bin", "nested/b.txt"]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zip::ZipWriter;
+
+use crate::compression_wo_conversion::add_files_directly_to_zip;
+use crate::decompression_wo_conversion::{decompress_files, DuplicatePolicy, ExtractOptions};
+use crate::entry_writer::CompressionSettings;
+use crate::utils::supported_algorithms;
+
+// Files covering the cases the round trip has to get right: empty, text, incompressible and nested
+fn write_dataset(input: &Path) -> io::Result<Vec<(&'static str, Vec<u8>)>> {
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let noise: Vec<u8> = (0..256 * 1024).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect();
+    let files = vec![
+        ("empty.txt", Vec::new()),
+        ("text.txt", b"the quick brown fox jumps over the lazy dog\n".repeat(2000)),
+        ("noise.bin", noise),
+        ("nested/dir/data.json", br#"{"self_test": true}"#.to_vec()),
+    ];
+    for (name, bytes) in &files {
+        let path = input.join(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, bytes)?;
+    }
+    Ok(files)
+}
+
+async fn round_trip(work_dir: &Path, algorithm: &str, files: &[(&str, Vec<u8>)]) -> io::Result<()> {
+    let input = work_dir.join("input");
+    let zip_path = work_dir.join(format!("{}.zip", algorithm));
+    let output = work_dir.join(format!("{}-output", algorithm));
+
+    let settings = CompressionSettings {
+        output_zip: zip_path.clone(),
+        compression_algorithm: algorithm.to_owned(),
+        compression_level: 3,
+        recursive: true,
+        ..CompressionSettings::default()
+    };
+    let zip = Mutex::new(ZipWriter::new(File::create(&zip_path)?));
+    let summary = add_files_directly_to_zip(&zip, &input, &settings)?;
+    if !summary.failures.is_empty() {
+        return Err(io::Error::other(format!("{} files failed to compress", summary.failures.len())));
+    }
+    zip.into_inner().unwrap().finish()?;
+
+    let options = ExtractOptions {
+        safe: true,
+        on_duplicate: DuplicatePolicy::Error,
+        resume: false,
+        update: false,
+        progress_interval: 0,
+        xattrs: false,
+        preserve_owner: false,
+        preserve_ctime: false,
+        validate_json: false,
+        convert_only: None,
+        buffer_to_memory_threshold: u64::MAX,
+        min_size: None,
+        max_size: None,
+        renames: Vec::new(),
+        parallel: false,
+        case_fold_entries: false,
+        password: None,
+        cancel: None,
+        progress: None,
+    };
+    decompress_files(&zip_path, &output, &options).await?;
+
+    for (name, bytes) in files {
+        if std::fs::read(output.join(name))? != *bytes {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} differs after the round trip", name)));
+        }
+    }
+    Ok(())
+}
+
+fn work_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("rust-zip-{}-self-test", std::process::id()))
+}
+
+async fn run_all(work_dir: &Path) -> io::Result<Vec<(&'static str, io::Result<()>)>> {
+    let files = write_dataset(&work_dir.join("input"))?;
+    let mut results = Vec::new();
+    for algorithm in supported_algorithms() {
+        results.push((algorithm, round_trip(work_dir, algorithm, &files).await));
+    }
+    Ok(results)
+}
+
+fn print_results(results: &[(&str, io::Result<()>)]) -> io::Result<()> {
+    let mut failures = 0;
+    for (algorithm, result) in results {
+        match result {
+            Ok(()) => println!("PASS {}", algorithm),
+            Err(e) => {
+                println!("FAIL {}: {}", algorithm, e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(io::Error::other(format!("{} of {} algorithms failed the self-test", failures, results.len())));
+    }
+    Ok(())
+}
+
+/// Compresses and extracts a small generated dataset with every supported algorithm and compares the bytes,
+/// printing PASS or FAIL for each algorithm. Fails if any algorithm did.
+/// The work directory is removed afterwards on every path, a failure to remove it is only printed.
+pub async fn self_test() -> io::Result<()> {
+    let work_dir = work_dir();
+    let outcome = run_all(&work_dir).await.and_then(|results| print_results(&results));
+    match std::fs::remove_dir_all(&work_dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => println!("Could not remove the self-test directory {:?}: {}", work_dir, e),
+        _ => {}
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn every_supported_algorithm_passes() {
+        self_test().await.unwrap();
+        assert!(!work_dir().exists());
+    }
+}
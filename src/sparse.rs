@@ -0,0 +1,139 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+use serde_json::{json, Value};
+
+/// Entry listing the files stored with `--sparse` as their data segments only, so extraction can put the holes back
+pub const SPARSE_MANIFEST_NAME: &str = ".rust-zip-sparse.json";
+
+pub struct SparseFile {
+    pub name: String,
+    pub size: u64,
+    /// Offset and length of every data segment, in file order
+    pub segments: Vec<(u64, u64)>,
+}
+
+/// Finds the data segments of a file with `SEEK_DATA` and `SEEK_HOLE`. Returns None when the file has no holes,
+/// or when the filesystem doesn't report them.
+#[cfg(target_os = "linux")]
+pub fn data_segments(file: &File, size: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while (offset as u64) < size {
+        let start = match lseek(file, offset, Whence::SeekData) {
+            Ok(start) => start,
+            // No data after the offset, the rest of the file is a hole
+            Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let end = lseek(file, start, Whence::SeekHole)?;
+        segments.push((start as u64, (end - start) as u64));
+        offset = end;
+    }
+    lseek(file, 0, Whence::SeekSet)?;
+    let data_size: u64 = segments.iter().map(|(_, length)| length).sum();
+    Ok((data_size < size).then_some(segments))
+}
+
+/// Holes are only detected on Linux, elsewhere sparse files are stored whole
+#[cfg(not(target_os = "linux"))]
+pub fn data_segments(_file: &File, _size: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+pub fn sparse_manifest_json(sparse_files: &[SparseFile]) -> String {
+    let files: Vec<Value> = sparse_files.iter()
+        .map(|file| json!({ "name": file.name, "size": file.size, "segments": file.segments }))
+        .collect();
+    json!({ "sparse_files": files }).to_string()
+}
+
+/// Spreads the data segments of the extracted files listed in the sparse manifest back to their offsets,
+/// leaving the gaps unwritten so they become holes again, then removes the manifest.
+/// Does nothing for archives written without `--sparse`.
+pub fn restore_sparse_files(output_folder: &Path) -> io::Result<()> {
+    let manifest_path = output_folder.join(SPARSE_MANIFEST_NAME);
+    if !manifest_path.is_file() {
+        return Ok(());
+    }
+
+    let manifest: Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid sparse manifest: {}", e)))?;
+    let files = manifest["sparse_files"].as_array().cloned().unwrap_or_default();
+
+    for file in files {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid entry in sparse manifest");
+        let (Some(name), Some(size), Some(segments)) = (file["name"].as_str(), file["size"].as_u64(), file["segments"].as_array()) else {
+            return Err(invalid());
+        };
+        if !Path::new(name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in sparse manifest: {}", name)));
+        }
+
+        let packed_path = output_folder.join(name);
+        let mut sparse_name = packed_path.file_name().unwrap_or_default().to_owned();
+        sparse_name.push(".sparse");
+        let sparse_path = packed_path.with_file_name(sparse_name);
+
+        let mut packed = File::open(&packed_path)?;
+        let mut output = File::create(&sparse_path)?;
+        for segment in segments {
+            let (Some(offset), Some(length)) = (segment[0].as_u64(), segment[1].as_u64()) else {
+                return Err(invalid());
+            };
+            output.seek(SeekFrom::Start(offset))?;
+            if io::copy(&mut (&mut packed).take(length), &mut output)? != length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Sparse file {} is missing data", name)));
+            }
+        }
+        output.set_len(size)?;
+        std::fs::rename(&sparse_path, &packed_path)?;
+        println!("Restored the holes of sparse file {}", name);
+    }
+
+    std::fs::remove_file(&manifest_path)?;
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use super::*;
+    use crate::decompression_wo_conversion::decompress_files;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, entry_names, extract_options, settings, TempDir};
+
+    const SIZE: u64 = 16 << 20;
+
+    fn allocated(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[tokio::test]
+    async fn sparse_file_is_extracted_sparse() {
+        let dir = TempDir::new();
+        std::fs::create_dir(dir.join("input")).unwrap();
+        let mut file = File::create(dir.join("input/disk.img")).unwrap();
+        file.write_all(&[1u8; 4096]).unwrap();
+        file.seek(SeekFrom::Start(8 << 20)).unwrap();
+        file.write_all(&[2u8; 4096]).unwrap();
+        file.set_len(SIZE).unwrap();
+        drop(file);
+        assert!(allocated(&dir.join("input/disk.img")) < SIZE, "the temp directory's filesystem has no holes");
+
+        let settings = CompressionSettings { sparse: true, ..settings(&dir.join("out.zip")) };
+        compress(&dir.join("input"), &settings);
+        assert!(entry_names(&dir.join("out.zip")).contains(&SPARSE_MANIFEST_NAME.to_owned()));
+        decompress_files(&dir.join("out.zip"), &dir.join("output"), &extract_options()).await.unwrap();
+
+        let extracted = dir.join("output/disk.img");
+        assert_eq!(std::fs::read(&extracted).unwrap(), std::fs::read(dir.join("input/disk.img")).unwrap());
+        assert!(allocated(&extracted) < SIZE / 2, "{} bytes allocated", allocated(&extracted));
+        assert!(!dir.join("output").join(SPARSE_MANIFEST_NAME).exists());
+    }
+}
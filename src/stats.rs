@@ -0,0 +1,76 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Time spent in each step of adding the files, summed over all worker threads, printed with `--verbose`
+#[derive(Default)]
+pub struct CompressionStats {
+    opening: AtomicU64,
+    lock_waiting: AtomicU64,
+    reading: AtomicU64,
+    compressing: AtomicU64,
+    files_opened: AtomicU64,
+    entries_copied: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn add_opening(&self, elapsed: Duration) {
+        self.opening.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.files_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_lock_waiting(&self, elapsed: Duration) {
+        self.lock_waiting.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // `io::copy` into the zip both reads the source and compresses, the reads are timed by `TimedReader`
+    // so the rest of the copy is the compression and the write into the archive
+    pub fn add_copy(&self, elapsed: Duration, read_time: Duration) {
+        self.reading.fetch_add(read_time.as_nanos() as u64, Ordering::Relaxed);
+        self.compressing.fetch_add(elapsed.saturating_sub(read_time).as_nanos() as u64, Ordering::Relaxed);
+        self.entries_copied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The time of all the steps together
+    pub fn total(&self) -> Duration {
+        [&self.opening, &self.lock_waiting, &self.reading, &self.compressing].iter()
+            .map(|nanos| Duration::from_nanos(nanos.load(Ordering::Relaxed)))
+            .sum()
+    }
+
+    /// How many files were opened and how many entries were copied into the archive
+    pub fn counts(&self) -> (u64, u64) {
+        (self.files_opened.load(Ordering::Relaxed), self.entries_copied.load(Ordering::Relaxed))
+    }
+
+    pub fn print(&self, total: Duration) {
+        let step = |nanos: &AtomicU64| Duration::from_nanos(nanos.load(Ordering::Relaxed));
+        let (files_opened, entries_copied) = self.counts();
+        println!("Timing breakdown over all threads, {:?} elapsed, {:?} in the steps below:", total, self.total());
+        println!("  opening files:           {:?} over {} files", step(&self.opening), files_opened);
+        println!("  waiting for the zip:     {:?}", step(&self.lock_waiting));
+        println!("  reading:                 {:?}", step(&self.reading));
+        println!("  compressing and writing: {:?} over {} entries", step(&self.compressing), entries_copied);
+    }
+}
+
+/// Reader measuring the time spent in the reads of the wrapped reader
+pub struct TimedReader<R> {
+    inner: R,
+    pub elapsed: Duration,
+}
+
+impl<R: Read> TimedReader<R> {
+    pub fn new(inner: R) -> Self {
+        TimedReader { inner, elapsed: Duration::ZERO }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let read = self.inner.read(buf);
+        self.elapsed += start.elapsed();
+        read
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The zip writer needs to seek back to patch headers, which pipes can't do, so with `--streaming`
+// the archive is built in a temporary file first
+pub fn temp_archive_path(temp_dir: &Path) -> PathBuf {
+    temp_dir.join(format!("rust-zip-{}.zip.tmp", std::process::id()))
+}
+
+/// Where the archive is written before being renamed to the output, next to it so the rename stays on one filesystem
+pub fn atomic_temp_path(output_path: &Path) -> PathBuf {
+    let mut file_name = output_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    output_path.with_file_name(file_name)
+}
+
+/// The error for an archive that ran out of disk space, naming the filesystem that is full
+pub fn out_of_space_error(archive_path: &Path) -> io::Error {
+    let directory = archive_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let directory = directory.canonicalize().unwrap_or_else(|_| directory.to_path_buf());
+    io::Error::new(io::ErrorKind::StorageFull, format!("Not enough disk space to write {:?}, the filesystem mounted at {:?} is full", archive_path, mount_point(&directory)))
+}
+
+// The highest ancestor still on the same device, other systems only get the directory itself
+fn mount_point(directory: &Path) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Ok(device) = std::fs::metadata(directory).map(|metadata| metadata.dev()) {
+            return directory.ancestors()
+                .take_while(|ancestor| std::fs::metadata(ancestor).is_ok_and(|metadata| metadata.dev() == device))
+                .last()
+                .unwrap_or(directory)
+                .to_path_buf();
+        }
+    }
+    directory.to_path_buf()
+}
+
+/// Copies the finished archive to the output, which may be a FIFO, then removes the temporary file.
+/// The output is only opened here, so a reader on the FIFO doesn't have to wait for the compression.
+pub fn stream_to_output(temp_path: &Path, output_path: &Path) -> io::Result<()> {
+    let mut archive = File::open(temp_path)?;
+    let mut output = OpenOptions::new().write(true).create(true).truncate(true).open(output_path)?;
+    let copied = io::copy(&mut archive, &mut output);
+    drop(archive);
+    std::fs::remove_file(temp_path)?;
+    let written = match copied {
+        // A FIFO output is left to its reader, a regular file can't be anything but a truncated archive
+        Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+            if std::fs::metadata(output_path).is_ok_and(|metadata| metadata.is_file()) {
+                let _ = std::fs::remove_file(output_path);
+            }
+            return Err(out_of_space_error(output_path));
+        }
+        copied => copied?,
+    };
+    println!("Streamed {} bytes to {:?}", written, output_path);
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use tar::Builder;
+
+use crate::collector::{archive_entry_name, collect_files, entry_prefix};
+use crate::entry_writer::CompressionSettings;
+
+/// Writes the files the compression archives into a `.tar.zst` as well, under the same names as their zip entries.
+/// The files are collected with the same settings, so both outputs hold the same files. The level is the
+/// compression level for Zstd and Zstd's default for the other algorithms.
+pub fn write_tar_zst(folder_path: &Path, output_path: &Path, settings: &CompressionSettings) -> io::Result<usize> {
+    let files = collect_files(folder_path, settings)?;
+    let root = entry_prefix(folder_path, settings)?;
+    let level = if settings.compression_algorithm == "Zstd" {
+        (settings.compression_level as i32).clamp(1, 22)
+    } else {
+        zstd::DEFAULT_COMPRESSION_LEVEL
+    };
+
+    let encoder = zstd::Encoder::new(File::create(output_path)?, level)?;
+    let mut builder = Builder::new(encoder);
+    for path in &files {
+        builder.append_path_with_name(path, archive_entry_name(folder_path, path, &root, settings)?)?;
+    }
+    builder.into_inner()?.finish()?;
+    println!("Wrote {} files to {:?}", files.len(), output_path);
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_diff::is_metadata_entry;
+    use crate::archive_info::open_archive;
+    use crate::test_support::{compress, settings, write_files, TempDir};
+    use crate::utils::supported_algorithms;
+
+    #[test]
+    fn tar_zst_holds_the_files_of_the_zip() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("a.txt", b"a"), ("nested/b.txt", &b"b".repeat(1000))]);
+        for algorithm in supported_algorithms() {
+            let settings = CompressionSettings { compression_algorithm: algorithm.to_owned(), ..settings(&dir.join(format!("{}.zip", algorithm))) };
+            compress(&dir.join("input"), &settings);
+            let tar_zst_path = dir.join(format!("{}.tar.zst", algorithm));
+            write_tar_zst(&dir.join("input"), &tar_zst_path, &settings).unwrap();
+
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(&tar_zst_path).unwrap()).unwrap());
+            let mut tar_names: Vec<String> = archive.entries().unwrap().map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned()).collect();
+            // The zip's hidden manifests, such as the Brotli one, describe the zip entries and aren't files
+            let mut zip_names: Vec<String> = open_archive(&settings.output_zip).unwrap().into_iter()
+                .map(|entry| entry.name)
+                .filter(|name| !is_metadata_entry(name))
+                .collect();
+            tar_names.sort();
+            zip_names.sort();
+            assert_eq!(tar_names, zip_names, "{}", algorithm);
+            assert_eq!(tar_names, ["a.txt", "nested/b.txt"]);
+        }
+    }
+}
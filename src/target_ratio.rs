@@ -0,0 +1,221 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::entry_writer::CompressionSettings;
+use crate::utils::{compressed_size_in_memory, supported_algorithms};
+
+const SAMPLE_FILES: usize = 16;
+const SAMPLE_BYTES_PER_FILE: u64 = 1024 * 1024;
+const LEVEL_STEP: i64 = 3;
+const MAX_ZSTD_LEVEL: i64 = 22;
+const TIME_BUDGET: Duration = Duration::from_secs(10);
+
+/// Compresses a sample of the folder's files with Zstd and raises the level until the
+/// achieved ratio (uncompressed / compressed) reaches the target, the maximum level is hit,
+/// or the time budget runs out. Returns the level to use for the real run.
+pub fn level_for_target_ratio(folder_path: &Path, compression_level: i64, target_ratio: f64) -> io::Result<i64> {
+    let start = Instant::now();
+    let samples = read_samples(folder_path)?;
+    let uncompressed: u64 = samples.iter().map(|sample| sample.len() as u64).sum();
+    if uncompressed == 0 {
+        println!("No data to sample for --target-ratio, keeping level {}", compression_level);
+        return Ok(compression_level);
+    }
+
+    let mut level = compression_level;
+    loop {
+        let mut compressed = 0;
+        for sample in &samples {
+            compressed += compressed_size_in_memory(sample, "Zstd", level)?;
+        }
+        let ratio = uncompressed as f64 / compressed.max(1) as f64;
+        println!("Sampled ratio at Zstd level {}: {:.2} (target {:.2})", level, ratio, target_ratio);
+
+        if ratio >= target_ratio {
+            break;
+        }
+        if level >= MAX_ZSTD_LEVEL {
+            println!("Target ratio not reached at the maximum level");
+            break;
+        }
+        if start.elapsed() > TIME_BUDGET {
+            println!("Time budget for --target-ratio exhausted");
+            break;
+        }
+        level = (level.max(0) + LEVEL_STEP).min(MAX_ZSTD_LEVEL);
+    }
+
+    println!("Using Zstd level {} after {:?} of sampling", level, start.elapsed());
+    Ok(level)
+}
+
+// Levels tried by `tune`, from fast to strong
+fn tune_levels(algorithm: &str) -> io::Result<&'static [i64]> {
+    match algorithm {
+        "Zstd" => Ok(&[1, 3, 6, 9, 12, 15, 19, 22]),
+        "Bzip2" | "Deflated" => Ok(&[1, 3, 6, 9]),
+        #[cfg(feature = "brotli")]
+        "Brotli" => Ok(&[1, 3, 5, 7, 9, 11]),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported compression algorithm, supported algorithms are: {}", supported_algorithms().join(", ")))),
+    }
+}
+
+/// Compresses a sample of the folder's files at several levels and prints the ratio and speed of each,
+/// then recommends the lowest level that saves at least 98% of what the strongest level saves.
+/// Returns the recommended level, None when there is no data to sample.
+pub fn tune(folder_path: &Path, algorithm: &str) -> io::Result<Option<i64>> {
+    let levels = tune_levels(algorithm)?;
+    let samples = read_samples(folder_path)?;
+    let uncompressed: u64 = samples.iter().map(|sample| sample.len() as u64).sum();
+    if uncompressed == 0 {
+        println!("No data to sample in {:?}", folder_path);
+        return Ok(None);
+    }
+    println!("Sampled {} files, {:.1} MB", samples.len(), uncompressed as f64 / 1_000_000.0);
+
+    let mut results = Vec::new();
+    for &level in levels {
+        let start = Instant::now();
+        let mut compressed = 0;
+        for sample in &samples {
+            compressed += compressed_size_in_memory(sample, algorithm, level)?;
+        }
+        let elapsed = start.elapsed();
+        let ratio = uncompressed as f64 / compressed.max(1) as f64;
+        let speed = uncompressed as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("{} level {:>2}: ratio {:.2}, {:.1} MB/s", algorithm, level, ratio, speed);
+        results.push((level, compressed));
+    }
+
+    let strongest = results.iter().map(|&(_, compressed)| compressed).min().unwrap();
+    let best_saving = uncompressed.saturating_sub(strongest) as f64;
+    let (recommended, _) = results.iter()
+        .find(|&&(_, compressed)| uncompressed.saturating_sub(compressed) as f64 >= best_saving * 0.98)
+        .unwrap();
+    println!("Recommended level: {}", recommended);
+    Ok(Some(*recommended))
+}
+
+/// Predicts the archive size by compressing a sample of the files in memory and extrapolating
+/// the sampled ratio to the total size of the files. This is a rough estimate, files that
+/// compress very differently from the sample make it off. Returns the estimated size in bytes.
+pub fn estimate_output_size(files: &[PathBuf], settings: &CompressionSettings) -> io::Result<u64> {
+    let mut total_bytes = 0;
+    for path in files {
+        total_bytes += std::fs::metadata(path)?.len();
+    }
+    let samples = sample_files(files)?;
+    let sampled: u64 = samples.iter().map(|sample| sample.len() as u64).sum();
+    if sampled == 0 {
+        println!("Estimate: no data to sample, the archive will hold {} empty files", files.len());
+        return Ok(0);
+    }
+
+    // `auto` mostly compresses with Zstd, so that is what the sample uses
+    let algorithm = if settings.compression_algorithm == "auto" { "Zstd" } else { &settings.compression_algorithm };
+    let mut compressed = 0;
+    for sample in &samples {
+        compressed += compressed_size_in_memory(sample, algorithm, settings.compression_level)?;
+    }
+    let ratio = sampled as f64 / compressed.max(1) as f64;
+    let estimate = (total_bytes as f64 / ratio) as u64;
+    println!("Estimate: {} files, {:.1} MB in, roughly {:.1} MB out (ratio {:.2}, sampled {} files)",
+        files.len(), total_bytes as f64 / 1_000_000.0, estimate as f64 / 1_000_000.0, ratio, samples.len());
+    Ok(estimate)
+}
+
+// Files are picked evenly spread over the folder listing, so the sample isn't just its first files
+fn read_samples(folder_path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let files: Vec<_> = std::fs::read_dir(folder_path)?
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    sample_files(&files)
+}
+
+fn sample_files(files: &[PathBuf]) -> io::Result<Vec<Vec<u8>>> {
+    let stride = files.len().div_ceil(SAMPLE_FILES).max(1);
+
+    let mut samples = Vec::new();
+    for path in files.iter().step_by(stride) {
+        let mut sample = Vec::new();
+        File::open(path)?.take(SAMPLE_BYTES_PER_FILE).read_to_end(&mut sample)?;
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::collect_files;
+    use crate::test_support::{compress, pseudo_random, random_bytes, settings, write_files, TempDir};
+
+    fn sampled_ratio(folder: &Path, level: i64) -> f64 {
+        let samples = read_samples(folder).unwrap();
+        let uncompressed: usize = samples.iter().map(Vec::len).sum();
+        let compressed: u64 = samples.iter().map(|sample| compressed_size_in_memory(sample, "Zstd", level).unwrap()).sum();
+        uncompressed as f64 / compressed as f64
+    }
+
+    #[test]
+    fn level_escalates_until_the_target_ratio_is_reached() {
+        let dir = TempDir::new();
+        // An incompressible file next to text made of random words, which higher levels compress better
+        let noise = random_bytes(64 * 1024, 1);
+        let words = ["archive", "entry", "level", "ratio", "zstd", "sample", "folder", "bytes"];
+        let text: Vec<u8> = pseudo_random(40 * 1024, 2).flat_map(|n| format!("{} ", words[n as usize % words.len()]).into_bytes()).collect();
+        write_files(&dir.join("input"), &[("noise.bin", &noise), ("text.txt", &text)]);
+
+        let target = sampled_ratio(&dir.join("input"), 19);
+        assert!(sampled_ratio(&dir.join("input"), 1) < target);
+        let level = level_for_target_ratio(&dir.join("input"), 1, target).unwrap();
+        assert!(level > 1);
+        assert!(sampled_ratio(&dir.join("input"), level) >= target || level == MAX_ZSTD_LEVEL);
+    }
+
+    #[test]
+    fn level_is_kept_when_the_target_ratio_is_already_reached() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("text.txt", &b"the same line again\n".repeat(1000))]);
+        assert_eq!(level_for_target_ratio(&dir.join("input"), 3, 2.0).unwrap(), 3);
+    }
+
+    #[test]
+    fn tune_recommends_one_of_the_tried_levels() {
+        let dir = TempDir::new();
+        let text: Vec<u8> = pseudo_random(8 * 1024, 5).flat_map(|n| format!("word{} ", n % 64).into_bytes()).collect();
+        write_files(&dir.join("input"), &[("text.txt", &text), ("noise.bin", &random_bytes(8 * 1024, 6))]);
+        for algorithm in ["Zstd", "Deflated"] {
+            let recommended = tune(&dir.join("input"), algorithm).unwrap().unwrap();
+            assert!(tune_levels(algorithm).unwrap().contains(&recommended), "{} level {}", algorithm, recommended);
+        }
+        assert_eq!(tune(&dir.join("input"), "Lzma").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn tune_of_an_empty_folder_recommends_nothing() {
+        let dir = TempDir::new();
+        assert_eq!(tune(dir.path(), "Zstd").unwrap(), None);
+    }
+
+    #[test]
+    fn estimate_is_close_to_the_archive_size() {
+        let dir = TempDir::new();
+        let files: Vec<(String, Vec<u8>)> = (0..40u64)
+            .map(|i| (format!("file_{}.txt", i), pseudo_random(4000, i).flat_map(|n| format!("line {} of the log\n", n % 500).into_bytes()).collect()))
+            .collect();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(name, bytes)| (name.as_str(), bytes.as_slice())).collect();
+        write_files(&dir.join("input"), &files);
+        let settings = settings(&dir.join("out.zip"));
+
+        let estimate = estimate_output_size(&collect_files(&dir.join("input"), &settings).unwrap(), &settings).unwrap();
+        compress(&dir.join("input"), &settings);
+        let actual = std::fs::metadata(dir.join("out.zip")).unwrap().len();
+        // The estimate leaves out the headers, which are small next to compressible data
+        assert!(estimate as f64 > actual as f64 / 2.0 && (estimate as f64) < actual as f64 * 2.0, "estimate {} for {} bytes", estimate, actual);
+    }
+}
@@ -0,0 +1,135 @@
+//! Helpers shared by the unit tests
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+use crate::archive_output::{write_archive, OutputOptions};
+use crate::decompression_wo_conversion::{DuplicatePolicy, ExtractOptions};
+use crate::entry_writer::{CompressionSettings, RunSummary};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system temp directory, removed with everything in it when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("rust-zip-test-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes the files under `dir`, creating their parent directories
+pub fn write_files(dir: &Path, files: &[(&str, &[u8])]) {
+    for (name, bytes) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, bytes).unwrap();
+    }
+}
+
+/// Numbers from a linear congruential generator, the same on every run for a seed
+pub fn pseudo_random(len: usize, mut state: u64) -> impl Iterator<Item = u64> {
+    (0..len).map(move |_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        state >> 33
+    })
+}
+
+/// Incompressible bytes, the same on every run for a seed
+pub fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    pseudo_random(len, seed).map(|n| n as u8).collect()
+}
+
+/// Writes a zip with the entries in order, deflated
+pub fn write_zip(zip_path: &Path, entries: &[(&str, &[u8])]) {
+    let mut zip = ZipWriter::new(File::create(zip_path).unwrap());
+    for (name, bytes) in entries {
+        zip.start_file(*name, FileOptions::<()>::default()).unwrap();
+        zip.write_all(bytes).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+/// Names of the entries of the archive, in the order of its central directory
+pub fn entry_names(zip_path: &Path) -> Vec<String> {
+    let archive = ZipArchive::new(File::open(zip_path).unwrap()).unwrap();
+    (0..archive.len()).map(|i| archive.name_for_index(i).unwrap().to_owned()).collect()
+}
+
+/// Creates a named pipe with the `mkfifo` command every Unix has
+#[cfg(unix)]
+pub fn make_fifo(path: &Path) {
+    let status = std::process::Command::new("mkfifo").arg(path).status().unwrap();
+    assert!(status.success(), "mkfifo {:?} failed", path);
+}
+
+/// The settings of a plain recursive Zstd run writing `output_zip`, with intermediates next to it
+pub fn settings(output_zip: &Path) -> CompressionSettings {
+    CompressionSettings {
+        output_zip: output_zip.to_path_buf(),
+        compression_algorithm: "Zstd".to_owned(),
+        compression_level: 3,
+        recursive: true,
+        temp_dir: output_zip.parent().unwrap().to_path_buf(),
+        ..CompressionSettings::default()
+    }
+}
+
+/// Archives the folder into `settings.output_zip` like the compression command
+pub fn compress(folder: &Path, settings: &CompressionSettings) -> RunSummary {
+    write_archive(&settings.output_zip, &settings.output_zip, folder, None, settings, OutputOptions::default()).unwrap()
+}
+
+/// Archives the folder like the compression command with `--convert-to-binary`
+pub fn compress_converted(folder: &Path, settings: &CompressionSettings) -> RunSummary {
+    let options = OutputOptions { convert_to_binary: true, ..OutputOptions::default() };
+    write_archive(&settings.output_zip, &settings.output_zip, folder, None, settings, options).unwrap()
+}
+
+/// An archive checked in under `tests/fixtures`
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// The options of a plain safe extraction, without any of the optional restores
+pub fn extract_options() -> ExtractOptions {
+    ExtractOptions {
+        safe: true,
+        on_duplicate: DuplicatePolicy::Error,
+        resume: false,
+        update: false,
+        progress_interval: 0,
+        xattrs: false,
+        preserve_owner: false,
+        preserve_ctime: false,
+        validate_json: false,
+        convert_only: None,
+        buffer_to_memory_threshold: u64::MAX,
+        min_size: None,
+        max_size: None,
+        renames: Vec::new(),
+        parallel: false,
+        case_fold_entries: false,
+        password: None,
+        cancel: None,
+        progress: None,
+    }
+}
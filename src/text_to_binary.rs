@@ -1,78 +1,102 @@
-use std::fs::File;
 use std::io;
 use std::path::{Path, PathBuf};
-use memmap::MmapOptions;
-use std::sync::atomic::{AtomicUsize, Ordering};
-// Implement the function text_to_binary_file that reads a text file and writes its contents to a binary file.
-
-static FILE_COUNT: AtomicUsize = AtomicUsize::new(1);
-
-pub fn text_to_binary_file(text_path: &Path, output_folder: &Path) -> io::Result<PathBuf> {
-    let file = File::open(text_path)?;
-    let mmap = unsafe { MmapOptions::new().map(&file)? };
-
-    let binary_file_name = text_path.file_name().unwrap().to_str().unwrap().to_owned() + ".bin";
-    let binary_file_path = output_folder.join(binary_file_name);
-
-    std::fs::write(&binary_file_path, &mmap[..])?;
-
-    let count = FILE_COUNT.fetch_add(1, Ordering::SeqCst);
-    println!("{}: Text file: {:?} converted to Binary file: {:?}", count, text_path.file_name().unwrap(), binary_file_path.file_name().unwrap());
+use clap::ValueEnum;
+
+use crate::binary_converter::{copy_to_binary, BinaryConverter};
+use crate::color;
+
+/// Line endings text files are rewritten to with `--normalize-eol`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    /// `\n`, as on Linux and macOS
+    Lf,
+    /// `\r\n`, as on Windows
+    Crlf,
+}
 
-    Ok(binary_file_path)
+pub struct TextConverter {
+    pub line_ending: Option<LineEnding>,
 }
 
-pub async fn determine_text_format(binary_path: &Path) -> io::Result<String> {
-    let mut extension = binary_path.extension().and_then(std::ffi::OsStr::to_str);
+impl BinaryConverter for TextConverter {
+    fn kind(&self) -> &'static str {
+        "Text"
+    }
 
-    if extension == Some("bin") {
-        if let Some(stem) = binary_path.file_stem().and_then(|s| s.to_str()) {
-            if let Some(pos) = stem.rfind('.') {
-                extension = Some(&stem[(pos + 1)..]);
-            }
+    fn to_binary(&self, source_path: &Path, output_folder: &Path) -> io::Result<PathBuf> {
+        let binary_path = copy_to_binary(self.kind(), source_path, output_folder)?;
+        if let Some(line_ending) = self.line_ending {
+            normalize_line_endings(&binary_path, line_ending)?;
         }
+        Ok(binary_path)
     }
+}
 
-    match extension {
-        Some("json") | Some("Json") => Ok("json".to_string()),
-        Some("txt") | Some("Txt") => Ok("txt".to_string()),
-        _ => Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Unsupported or unknown text format",
-        )),
+// A `.txt` name doesn't make a file text, so files with NUL bytes are left untouched.
+// Lone `\r` line endings aren't taken for line breaks.
+fn normalize_line_endings(path: &Path, line_ending: LineEnding) -> io::Result<()> {
+    let bytes = std::fs::read(path)?;
+    if bytes.contains(&0) {
+        println!("Not normalizing the line endings of {:?}, it looks binary", path.file_name().unwrap());
+        return Ok(());
+    }
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut lines = bytes.split(|&byte| byte == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        normalized.extend_from_slice(line);
+        if lines.peek().is_some() {
+            normalized.extend_from_slice(if line_ending == LineEnding::Crlf { b"\r\n" } else { b"\n" });
+        }
+    }
+    if normalized != bytes {
+        std::fs::write(path, normalized)?;
     }
+    Ok(())
 }
 
-// Implement the function binary_to_text_file that reads a binary file and writes its contents to a text file.
-pub async fn convert_binary_to_text(binary_path: &Path, decompression_folder: &Path) -> io::Result<()> {
-    let file = File::open(binary_path)?;
-    let mmap = unsafe { MmapOptions::new().map(&file)? };
-    let txt = String::from_utf8_lossy(&mmap[..]);
-
-    // Determine the correct image format and extension
-    let format = determine_text_format(binary_path).await?;
-    let extension = match format.as_str() {
-        //expect String
-        "json" => "json".to_string(),
-        "txt" => "txt".to_string(),
-        _=> "txt".to_string(),
-    };
-
-    // Extract the file stem, removing the .bin extension if present
-    let mut output_file_name = binary_path.file_stem().unwrap().to_str().unwrap().to_owned();
-    if output_file_name.ends_with(&extension) {
-        // If the stem already ends with the correct extension, do not append again
-        output_file_name.truncate(output_file_name.len() - extension.len());
+/// Prints a warning naming the file when the bytes aren't valid JSON, returns whether they are
+pub fn warn_if_invalid_json(path: &Path, bytes: &[u8]) -> bool {
+    match serde_json::from_slice::<serde_json::Value>(bytes) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("{} {:?} is not valid JSON: {}", color::warning(), path, e);
+            false
+        }
     }
-    let output_file_name = format!("{}.{}", output_file_name, extension);
-
-    let output_path = decompression_folder.join(&output_file_name);
-
-    //change this to another function supporting txt
-    //img.save(&output_path).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-    std::fs::write(&output_path, &txt[..])?;
+}
 
-    println!("Converted binary file to txt: {:?}", output_path);
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use super::*;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress_converted, settings, write_files, TempDir};
+
+    #[test]
+    fn json_is_validated() {
+        assert!(warn_if_invalid_json(Path::new("valid.json"), br#"{"files": [1, 2], "name": null}"#));
+        assert!(!warn_if_invalid_json(Path::new("invalid.json"), br#"{"files": [1, 2"#));
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn mixed_line_endings_are_normalized_in_the_archive() {
+        let dir = TempDir::new();
+        let input = dir.join("input");
+        write_files(&input, &[("mixed.txt", b"one\r\ntwo\nthree\rstill three\r\n"), ("nul.txt", b"a\r\n\0b\n")]);
+        for (line_ending, expected) in [(LineEnding::Lf, &b"one\ntwo\nthree\rstill three\n"[..]), (LineEnding::Crlf, b"one\r\ntwo\r\nthree\rstill three\r\n")] {
+            let output = dir.join(format!("{:?}.zip", line_ending));
+            compress_converted(&input, &CompressionSettings { normalize_eol: Some(line_ending), ..settings(&output) });
+
+            let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+            let mut entry = |name| {
+                let mut bytes = Vec::new();
+                io::Read::read_to_end(&mut archive.by_name(name).unwrap(), &mut bytes).unwrap();
+                bytes
+            };
+            assert_eq!(entry("mixed.txt.bin"), expected);
+            // A file with a NUL byte isn't taken for text, whatever its name
+            assert_eq!(entry("nul.txt.bin"), b"a\r\n\0b\n");
+        }
+    }
+}
@@ -1,21 +1,441 @@
-use std::io;
-use zip::CompressionMethod;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use memmap::{Mmap, MmapOptions};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
-pub fn get_compression_method(algorithm: &str, level: i64) -> io::Result<(CompressionMethod, Option<i64>)> {
+use crate::entry_writer::MethodRule;
+use crate::color;
+
+/// Names accepted for the compression algorithm. The zip methods are compiled in through the default features
+/// of the zip crate, Brotli with the `brotli` feature.
+pub fn supported_algorithms() -> Vec<&'static str> {
+    let mut algorithms = vec!["Zstd", "Bzip2", "Deflated"];
+    if cfg!(feature = "brotli") {
+        algorithms.push("Brotli");
+    }
+    algorithms
+}
+
+/// Levels accepted by each algorithm, other levels fall back to the default level
+pub fn level_range(algorithm: &str) -> Option<RangeInclusive<i64>> {
     match algorithm {
-        "Zstd" => {
-            let valid_level = if level >= -7 && level <= 22 { Some(level) } else { Some(3) };
-            Ok((CompressionMethod::Zstd, valid_level))
-        },
-        "Bzip2" => {
-            let valid_level = if level >= 0 && level <= 9 { Some(level) } else { Some(6) };
-            Ok((CompressionMethod::Bzip2, valid_level))
-        },
-        "Deflated" => {
-            let valid_level = if level >= 0 && level <= 9 { Some(level) }
-            else { Some(6) };
-            Ok((CompressionMethod::Deflated, valid_level))
+        "Zstd" => Some(-7..=22),
+        "Bzip2" | "Deflated" => Some(0..=9),
+        #[cfg(feature = "brotli")]
+        "Brotli" => Some(0..=11),
+        _ => None,
+    }
+}
+
+pub fn print_algorithms() {
+    for algorithm in supported_algorithms() {
+        let levels = level_range(algorithm).expect("every supported algorithm has a level range");
+        println!("{:<10} levels {} to {}", algorithm, levels.start(), levels.end());
+    }
+}
+
+// The levels are checked against `level_range`, out of range levels get the default level of the algorithm
+pub fn get_compression_method(algorithm: &str, level: i64) -> io::Result<(CompressionMethod, Option<i64>)> {
+    let (compression_method, env_var, builtin_default) = match algorithm {
+        "Zstd" => (CompressionMethod::Zstd, "RUSTZIP_ZSTD_DEFAULT", 3),
+        "Bzip2" => (CompressionMethod::Bzip2, "RUSTZIP_BZIP2_DEFAULT", 6),
+        "Deflated" => (CompressionMethod::Deflated, "RUSTZIP_DEFLATED_DEFAULT", 6),
+        // Brotli isn't a zip method: the entry writer compresses the bytes with the returned level as the quality
+        // and stores them, see `brotli_entries`
+        #[cfg(feature = "brotli")]
+        "Brotli" => (CompressionMethod::Stored, "RUSTZIP_BROTLI_DEFAULT", 9),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported compression algorithm, supported algorithms are: {}", supported_algorithms().join(", ")))),
+    };
+    let range = level_range(algorithm).expect("every supported algorithm has a level range");
+    let valid_level = if range.contains(&level) { level } else { default_level(env_var, range, builtin_default) };
+    Ok((compression_method, Some(valid_level)))
+}
+
+// For the commands writing entries straight through the zip crate, which can't apply Brotli
+pub fn get_zip_compression_method(algorithm: &str, level: i64) -> io::Result<(CompressionMethod, Option<i64>)> {
+    if algorithm == "Brotli" {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Brotli is only supported by the compression command"));
+    }
+    get_compression_method(algorithm, level)
+}
+
+// Reads the fallback level for an algorithm from the environment, so teams can standardize it
+fn default_level(env_var: &str, range: RangeInclusive<i64>, builtin_default: i64) -> i64 {
+    match std::env::var(env_var) {
+        Ok(value) => match value.trim().parse::<i64>() {
+            Ok(level) if range.contains(&level) => level,
+            _ => {
+                eprintln!("{} ignoring {}={}, expected a level between {} and {}", color::warning(), env_var, value, range.start(), range.end());
+                builtin_default
+            }
         },
-        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported compression algorithm, supported algorithms are: Zstd, Bzip2, Deflated")),
+        Err(_) => builtin_default,
+    }
+}
+
+/// Returns true for entry names that point outside the output folder on their own,
+/// either through a leading root (`/etc/x`, `\\server\x`) or a Windows drive letter (`C:\x`).
+pub fn is_absolute_entry_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let has_root = name.starts_with('/') || name.starts_with('\\');
+    let has_drive_letter = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    has_root || has_drive_letter
+}
+
+pub fn check_entry_name(name: &str, safe: bool) -> io::Result<()> {
+    if is_absolute_entry_name(name) {
+        if safe {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Refusing to extract entry with absolute path: {}", name)));
+        }
+        eprintln!("{} entry {} has an absolute path or drive letter", color::warning(), name);
+    }
+    Ok(())
+}
+
+/// The password from `--password`, else from the environment variable named by `--password-env`,
+/// else read as the first line of stdin with `--password-stdin`
+pub fn resolve_password(password: Option<String>, password_env: Option<&str>, password_stdin: bool) -> io::Result<Option<String>> {
+    if password.is_some() {
+        return Ok(password);
+    }
+    if let Some(variable) = password_env {
+        return std::env::var(variable).map(Some).map_err(|e| {
+            io::Error::new(io::ErrorKind::NotFound, format!("Can't read the password from environment variable {}: {}", variable, e))
+        });
+    }
+    if password_stdin {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        return Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()));
+    }
+    Ok(None)
+}
+
+/// Directory for intermediate files, `--temp-dir` when given, otherwise the system temp directory.
+/// It must exist and be writable, which is checked with a probe file.
+pub fn resolve_temp_dir(temp_dir: Option<&Path>) -> io::Result<PathBuf> {
+    let temp_dir = temp_dir.map(Path::to_path_buf).unwrap_or_else(std::env::temp_dir);
+    if !temp_dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Temp directory {:?} does not exist", temp_dir)));
+    }
+    let probe = temp_dir.join(format!(".rust-zip-probe-{}", std::process::id()));
+    File::create(&probe)
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| io::Error::new(e.kind(), format!("Temp directory {:?} is not writable: {}", temp_dir, e)))?;
+    Ok(temp_dir)
+}
+
+/// Creates the directory of a directory entry (a name ending in `/`), so empty directories are restored too.
+/// Markers for the archive root such as `/` or `./` have nothing to create, other names that would
+/// leave the output folder are skipped with a warning.
+pub fn create_directory_entry(output_folder: &Path, name: &str, enclosed_name: Option<PathBuf>) -> io::Result<()> {
+    match enclosed_name {
+        Some(path) => {
+            std::fs::create_dir_all(output_folder.join(&path))?;
+            if path.components().any(|component| component != Component::CurDir) {
+                println!("Created directory: {:?}", path);
+            }
+        }
+        None if name.chars().all(|c| c == '/' || c == '\\' || c == '.') && !name.contains("..") => {}
+        None => eprintln!("{} skipping directory entry with an invalid name: {}", color::warning(), name),
+    }
+    Ok(())
+}
+
+// Compresses the data into a throwaway in-memory archive and returns the compressed size of the entry
+pub fn compressed_size_in_memory(data: &[u8], algorithm: &str, level: i64) -> io::Result<u64> {
+    #[cfg(feature = "brotli")]
+    if algorithm == "Brotli" {
+        let (_, quality) = get_compression_method(algorithm, level)?;
+        let mut compressed = Vec::new();
+        crate::brotli_entries::compress_into(&mut &data[..], &mut compressed, quality.unwrap_or(9) as u32)?;
+        return Ok(compressed.len() as u64);
+    }
+    let (compression_method, valid_level) = get_compression_method(algorithm, level)?;
+    let options: FileOptions<()> = FileOptions::default()
+        .compression_method(compression_method).compression_level(valid_level);
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("sample", options)?;
+    zip.write_all(data)?;
+    let cursor = zip.finish()?;
+
+    let mut archive = ZipArchive::new(cursor)?;
+    let compressed_size = archive.by_index(0)?.compressed_size();
+    Ok(compressed_size)
+}
+
+const ENTROPY_SAMPLE_BYTES: u64 = 64 * 1024;
+// Bits per byte above which data is considered already compressed (8.0 is fully random)
+const HIGH_ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Picks the method for `auto` mode from the Shannon entropy of the start of the file:
+/// already compressed or random data is stored as is, everything else uses Zstd.
+pub fn choose_method_for(path: &Path) -> io::Result<CompressionMethod> {
+    let mut sample = Vec::new();
+    File::open(path)?.take(ENTROPY_SAMPLE_BYTES).read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(CompressionMethod::Zstd);
+    }
+
+    let mut counts = [0u64; 256];
+    for &byte in &sample {
+        counts[byte as usize] += 1;
+    }
+    let total = sample.len() as f64;
+    let entropy: f64 = counts.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let probability = count as f64 / total;
+            -probability * probability.log2()
+        })
+        .sum();
+
+    if entropy > HIGH_ENTROPY_THRESHOLD {
+        Ok(CompressionMethod::Stored)
+    } else {
+        Ok(CompressionMethod::Zstd)
+    }
+}
+
+pub fn file_crc32(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+// Copies a file through a memory map, falling back to a plain copy where mapping fails, as it does
+// for empty files and on some network or virtual filesystems
+pub fn copy_via_mmap(source_path: &Path, destination_path: &Path) -> io::Result<()> {
+    copy_with_map(source_path, destination_path, |file| unsafe { MmapOptions::new().map(file) })
+}
+
+// The mapping is passed in so the tests can make it fail
+fn copy_with_map(source_path: &Path, destination_path: &Path, map: impl FnOnce(&File) -> io::Result<Mmap>) -> io::Result<()> {
+    let mut file = File::open(source_path)?;
+    match map(&file) {
+        Ok(mmap) => std::fs::write(destination_path, &mmap[..]),
+        Err(e) => {
+            println!("Memory mapping {:?} failed ({}), copying it without mmap", source_path, e);
+            io::copy(&mut file, &mut File::create(destination_path)?)?;
+            Ok(())
+        }
+    }
+}
+
+// Parses sizes like `512`, `4K` or `1M`
+pub fn parse_size(size: &str) -> io::Result<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1024),
+        Some('M') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('G') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    number.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid size: {}", size)))
+}
+
+// Parses `--method-for` rules like `png=auto` or `txt=Zstd:19`, a missing level keeps the global one
+pub fn parse_method_rule(rule: &str) -> Result<(String, MethodRule), String> {
+    let (extension, method) = rule.split_once('=').ok_or_else(|| format!("Invalid rule {}, expected <ext>=<algorithm>[:<level>]", rule))?;
+    let (algorithm, level) = match method.split_once(':') {
+        Some((algorithm, level)) => (algorithm, Some(level.parse::<i64>().map_err(|_| format!("Invalid level in rule: {}", rule))?)),
+        None => (method, None),
+    };
+    if algorithm != "auto" && !supported_algorithms().contains(&algorithm) {
+        return Err(format!("Unsupported algorithm in rule {}, supported algorithms are: {}, auto", rule, supported_algorithms().join(", ")));
+    }
+    let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+    Ok((extension, MethodRule { algorithm: algorithm.to_owned(), level }))
+}
+
+// Parses `--rename` mappings like `old/=new/` or `a.txt=b.txt`, the other name may be empty to strip a prefix
+pub fn parse_rename(mapping: &str) -> Result<(String, String), String> {
+    match mapping.split_once('=') {
+        Some((from, to)) if !from.is_empty() => Ok((from.to_owned(), to.to_owned())),
+        _ => Err(format!("Invalid mapping {}, expected <from>=<to>", mapping)),
+    }
+}
+
+// Parses `--fallback-method`, which also accepts `Stored` but not Brotli, since the retry goes straight through the zip crate
+pub fn parse_fallback_method(algorithm: &str) -> Result<String, String> {
+    if algorithm != "Stored" && get_zip_compression_method(algorithm, 0).is_err() {
+        let mut algorithms: Vec<&str> = supported_algorithms().into_iter().filter(|algorithm| *algorithm != "Brotli").collect();
+        algorithms.push("Stored");
+        return Err(format!("Unsupported fallback algorithm {}, supported algorithms are: {}", algorithm, algorithms.join(", ")));
+    }
+    Ok(algorithm.to_owned())
+}
+
+// Parses `--embed` values like `dist/LICENSE=LICENSE`, split at the last `=` so paths may contain one
+pub fn parse_embed(embed: &str) -> Result<(PathBuf, String), String> {
+    let (path, entry_name) = embed.rsplit_once('=').ok_or_else(|| format!("Invalid embed {}, expected <path>=<entry_name>", embed))?;
+    let entry_name = entry_name.replace('\\', "/").trim_start_matches('/').to_owned();
+    if path.is_empty() || entry_name.is_empty() {
+        return Err(format!("Invalid embed {}, expected <path>=<entry_name>", embed));
+    }
+    Ok((PathBuf::from(path), entry_name))
+}
+
+// Parses durations like `90`, `30s`, `5m` or `1h`, plain numbers are seconds
+pub fn parse_duration(duration: &str) -> io::Result<Duration> {
+    let duration = duration.trim();
+    let (number, multiplier) = match duration.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        _ => (duration, 1),
+    };
+    number.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid duration: {}", duration)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, random_bytes, settings, write_files, TempDir};
+
+    #[test]
+    fn random_bytes_are_stored_and_text_compressed() {
+        let dir = TempDir::new();
+        write_files(dir.path(), &[("random.bin", &random_bytes(64 * 1024, 3)), ("text.txt", &b"plain text, compresses well\n".repeat(2000))]);
+        assert_eq!(choose_method_for(&dir.join("random.bin")).unwrap(), CompressionMethod::Stored);
+        assert_eq!(choose_method_for(&dir.join("text.txt")).unwrap(), CompressionMethod::Zstd);
+    }
+
+    #[test]
+    fn failed_memory_map_falls_back_to_a_plain_copy() {
+        let dir = TempDir::new();
+        let contents = random_bytes(100 * 1024, 7);
+        write_files(dir.path(), &[("source.bin", &contents), ("empty.txt", b"")]);
+        let simulated_failure = |_: &File| Err(io::Error::other("mmap not supported by this filesystem"));
+        copy_with_map(&dir.join("source.bin"), &dir.join("copy.bin"), simulated_failure).unwrap();
+        assert_eq!(std::fs::read(dir.join("copy.bin")).unwrap(), contents);
+
+        // Empty files can't be mapped at all
+        copy_via_mmap(&dir.join("empty.txt"), &dir.join("empty.bin")).unwrap();
+        assert_eq!(std::fs::read(dir.join("empty.bin")).unwrap(), b"");
+        copy_via_mmap(&dir.join("source.bin"), &dir.join("mapped.bin")).unwrap();
+        assert_eq!(std::fs::read(dir.join("mapped.bin")).unwrap(), contents);
+    }
+
+    // The only test reading RUSTZIP_BZIP2_DEFAULT, as the environment is shared by the test threads
+    #[test]
+    fn out_of_range_level_falls_back_to_the_environment_default() {
+        std::env::set_var("RUSTZIP_BZIP2_DEFAULT", "2");
+        assert_eq!(get_compression_method("Bzip2", 42).unwrap(), (CompressionMethod::Bzip2, Some(2)));
+        assert_eq!(get_compression_method("Bzip2", 9).unwrap(), (CompressionMethod::Bzip2, Some(9)));
+        std::env::set_var("RUSTZIP_BZIP2_DEFAULT", "20");
+        assert_eq!(get_compression_method("Bzip2", 42).unwrap(), (CompressionMethod::Bzip2, Some(6)));
+        std::env::remove_var("RUSTZIP_BZIP2_DEFAULT");
+        assert_eq!(get_compression_method("Bzip2", 42).unwrap(), (CompressionMethod::Bzip2, Some(6)));
+    }
+
+    #[test]
+    fn roots_and_drive_letters_are_absolute_entry_names() {
+        for name in ["/etc/passwd", "\\\\server\\share", "C:\\x.txt", "c:/x.txt"] {
+            assert!(is_absolute_entry_name(name), "{}", name);
+        }
+        for name in ["etc/passwd", "docs/C:x"] {
+            assert!(!is_absolute_entry_name(name), "{}", name);
+        }
+        assert_eq!(check_entry_name("/etc/passwd", true).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(check_entry_name("/etc/passwd", false).is_ok());
+    }
+
+    #[test]
+    fn sizes_and_durations_are_parsed_with_their_suffix() {
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("2m").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(2 * 60 * 60));
+    }
+
+    #[test]
+    fn overflowing_sizes_and_durations_are_invalid() {
+        assert_eq!(parse_size("18446744073709551615G").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(parse_duration("18446744073709551615h").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(parse_size("lots").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn temp_dir_must_exist() {
+        let dir = TempDir::new();
+        assert_eq!(resolve_temp_dir(Some(dir.path())).unwrap(), dir.path());
+        let e = resolve_temp_dir(Some(&dir.join("missing"))).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn embeds_are_split_at_the_last_equals_sign() {
+        assert_eq!(parse_embed("dist/a=b/LICENSE=docs\\LICENSE").unwrap(), (PathBuf::from("dist/a=b/LICENSE"), "docs/LICENSE".to_owned()));
+        assert_eq!(parse_embed("README.md=/README.md").unwrap().1, "README.md");
+        assert!(parse_embed("README.md").is_err());
+        assert!(parse_embed("README.md=").is_err());
+    }
+
+    #[test]
+    fn password_from_the_environment_encrypts_the_archive() {
+        std::env::set_var("RUSTZIP_TEST_PASSWORD", "from the environment");
+        let password = resolve_password(None, Some("RUSTZIP_TEST_PASSWORD"), false).unwrap();
+        assert_eq!(password.as_deref(), Some("from the environment"));
+        // A password on the command line wins over the environment
+        assert_eq!(resolve_password(Some("flag".to_owned()), Some("RUSTZIP_TEST_PASSWORD"), false).unwrap().as_deref(), Some("flag"));
+        let e = resolve_password(None, Some("RUSTZIP_TEST_PASSWORD_UNSET"), false).unwrap_err();
+        assert!(e.to_string().contains("RUSTZIP_TEST_PASSWORD_UNSET"));
+
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("secret.txt", b"secret")]);
+        let settings = CompressionSettings { password, ..settings(&dir.join("out.zip")) };
+        compress(&dir.join("input"), &settings);
+        let mut archive = zip::ZipArchive::new(File::open(dir.join("out.zip")).unwrap()).unwrap();
+        assert!(archive.by_name("secret.txt").is_err());
+        let file = archive.by_name_decrypt("secret.txt", b"from the environment").unwrap();
+        assert_eq!(io::read_to_string(file).unwrap(), "secret");
+    }
+
+    #[test]
+    fn supported_algorithms_match_the_enabled_features() {
+        let algorithms = supported_algorithms();
+        assert_eq!(algorithms.contains(&"Brotli"), cfg!(feature = "brotli"));
+        assert_eq!(&algorithms[..3], ["Zstd", "Bzip2", "Deflated"]);
+        for algorithm in algorithms {
+            assert!(level_range(algorithm).is_some(), "{}", algorithm);
+            assert!(get_compression_method(algorithm, 3).is_ok(), "{}", algorithm);
+        }
+        assert!(level_range("Lzma").is_none());
+    }
+
+    // The default levels themselves can come from the environment, which another test changes for Bzip2
+    #[test]
+    fn levels_are_checked_against_the_level_range() {
+        for algorithm in supported_algorithms() {
+            let range = level_range(algorithm).unwrap();
+            for level in [*range.start(), *range.end()] {
+                assert_eq!(get_compression_method(algorithm, level).unwrap().1, Some(level), "{}", algorithm);
+            }
+            for level in [range.start() - 1, range.end() + 1] {
+                let default = get_compression_method(algorithm, level).unwrap().1.unwrap();
+                assert!(range.contains(&default), "{} {} gave {}", algorithm, level, default);
+            }
+        }
     }
-}
\ No newline at end of file
+}
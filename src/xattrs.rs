@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use serde_json::{json, Map, Value};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::color;
+
+/// Entry holding the extended attributes of the archived files, written with `--xattrs`
+pub const XATTRS_NAME: &str = ".rust-zip-xattrs.json";
+
+pub type Attributes = BTreeMap<String, Vec<u8>>;
+
+// Filesystems without extended attributes are skipped silently, other failures only warn
+pub fn read_xattrs(path: &Path) -> Attributes {
+    let mut attributes = Attributes::new();
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::Unsupported {
+                eprintln!("{} could not read extended attributes of {:?}: {}", color::warning(), path, e);
+            }
+            return attributes;
+        }
+    };
+    for name in names {
+        let Some(name_str) = name.to_str() else { continue };
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => {
+                attributes.insert(name_str.to_owned(), value);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{} could not read extended attribute {} of {:?}: {}", color::warning(), name_str, path, e),
+        }
+    }
+    attributes
+}
+
+// Values are arbitrary bytes, so they are stored hex encoded
+pub fn xattrs_json(files: &[(String, Attributes)]) -> String {
+    let mut entries = Map::new();
+    for (name, attributes) in files {
+        let attributes: Map<String, Value> = attributes.iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.iter().map(|byte| format!("{:02x}", byte)).collect())))
+            .collect();
+        entries.insert(name.clone(), Value::Object(attributes));
+    }
+    json!({ "files": entries }).to_string()
+}
+
+/// Sets the extended attributes stored in the archive on the extracted files.
+/// Does nothing for archives written without `--xattrs`.
+pub fn restore_xattrs(archive: &mut ZipArchive<File>, output_folder: &Path) -> io::Result<()> {
+    let mut entry = match archive.by_name(XATTRS_NAME) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    let stored: Value = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid extended attributes entry: {}", e)))?;
+
+    let files = stored["files"].as_object().cloned().unwrap_or_default();
+    for (name, attributes) in files {
+        if !Path::new(&name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid file name in extended attributes entry: {}", name)));
+        }
+        let path = output_folder.join(&name);
+        if !path.is_file() {
+            continue;
+        }
+        for (key, value) in attributes.as_object().cloned().unwrap_or_default() {
+            let Some(value) = value.as_str().and_then(decode_hex) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid value of extended attribute {} of {}", key, name)));
+            };
+            if let Err(e) = xattr::set(&path, &key, &value) {
+                if e.kind() == io::ErrorKind::Unsupported {
+                    println!("Extended attributes are not supported in {:?}, skipping them", output_folder);
+                    return Ok(());
+                }
+                eprintln!("{} could not set extended attribute {} on {:?}: {}", color::warning(), key, path, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::decompression_wo_conversion::{decompress_files, ExtractOptions};
+    use crate::entry_writer::CompressionSettings;
+    use crate::test_support::{compress, extract_options, settings, write_files, TempDir};
+
+    #[tokio::test]
+    async fn extended_attributes_round_trip() {
+        let dir = TempDir::new();
+        write_files(&dir.join("input"), &[("tagged.txt", b"tagged"), ("plain.txt", b"plain")]);
+        let value = b"\x00binary \xffvalue";
+        if let Err(e) = xattr::set(dir.join("input/tagged.txt"), "user.rust_zip.test", value) {
+            println!("Skipping, the temp directory has no extended attributes: {}", e);
+            return;
+        }
+        let zip_path = dir.join("xattrs.zip");
+        compress(&dir.join("input"), &CompressionSettings { xattrs: true, ..settings(&zip_path) });
+
+        decompress_files(&zip_path, &dir.join("output"), &ExtractOptions { xattrs: true, ..extract_options() }).await.unwrap();
+        assert_eq!(xattr::get(dir.join("output/tagged.txt"), "user.rust_zip.test").unwrap().unwrap(), value);
+        assert!(!dir.join("output").join(XATTRS_NAME).exists());
+
+        // Without the flag the attributes stay in the archive only
+        decompress_files(&zip_path, &dir.join("without"), &extract_options()).await.unwrap();
+        assert_eq!(xattr::get(dir.join("without/tagged.txt"), "user.rust_zip.test").unwrap(), None);
+    }
+
+    #[test]
+    fn hex_values_are_decoded() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}